@@ -0,0 +1,84 @@
+// ----------------------------------------------------------------------------
+/// CRC-32 (IEEE 802.3, the one used by zlib), computed incrementally
+pub struct Crc32 {
+    table: [u32; 256],
+    value: u32
+}
+
+impl Crc32 {
+    pub fn new() -> Crc32 {
+        let mut table = [0u32; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut c = i as u32;
+            for _ in 0..8 {
+                c = if c & 1 != 0 { 0xEDB88320 ^ (c >> 1) } else { c >> 1 };
+            }
+            *entry = c;
+        }
+
+        Crc32 { table: table, value: 0xFFFFFFFF }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &b in bytes.iter() {
+            self.value = self.table[((self.value ^ b as u32) & 0xFF) as usize] ^ (self.value >> 8);
+        }
+    }
+
+    pub fn finish(&self) -> u32 { !self.value }
+}
+
+// ----------------------------------------------------------------------------
+/// 64-bit FNV-1a. Not a checksum, but unlike the hashers in std its values are specified,
+/// so they can be stored and compared across versions of the crate and of Rust.
+pub struct Fnv1a64 {
+    value: u64
+}
+
+impl Fnv1a64 {
+    pub fn new() -> Fnv1a64 {
+        Fnv1a64 { value: 0xCBF29CE484222325 }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &b in bytes.iter() {
+            self.value = (self.value ^ b as u64).wrapping_mul(0x100000001B3);
+        }
+    }
+
+    pub fn finish(&self) -> u64 { self.value }
+}
+
+// ----------------------------------------------------------------------------
+#[cfg(test)]
+mod test {
+    use ::checksum::{Crc32, Fnv1a64};
+
+    #[test]
+    fn crc32_known_values() {
+        assert_eq!(Crc32::new().finish(), 0);
+
+        let mut crc = Crc32::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.finish(), 0xCBF43926);
+
+        // Incremental updates give the same result
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xCBF43926);
+    }
+
+    #[test]
+    fn fnv1a64_known_values() {
+        assert_eq!(Fnv1a64::new().finish(), 0xCBF29CE484222325);
+
+        let mut hash = Fnv1a64::new();
+        hash.update(b"a");
+        assert_eq!(hash.finish(), 0xAF63DC4C8601EC8C);
+
+        let mut hash = Fnv1a64::new();
+        hash.update(b"foo");
+        assert_eq!(hash.finish(), 0xDCB27518FED9D577);
+    }
+}
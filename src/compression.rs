@@ -1,5 +1,156 @@
-#[derive(Copy, Clone)]
+use std::borrow::Cow;
+
+use ::error::{StorageError, StorageResult};
+
+/// How a chunk is compressed, as recorded in its header. The Compressor that wrote it is
+/// not recorded, e.g. the gzip level.
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Compression {
     None,
-    Snappy
+    Snappy,
+    Gzip
+}
+
+// ----------------------------------------------------------------------------
+/// The compression applied by the inserters to the chunks of new stripes, including its
+/// settings. Each compressor writes chunks with one Compression:
+///
+/// ```
+/// use table::{Compression, Compressor, Encoding};
+///
+/// assert_eq!(Compressor::None.compression(), Compression::None);
+/// let _ = Encoding::DeltaOfDelta;
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Compressor {
+    None,
+    /// Gzip with the given level, from 0 (no compression) to 9 (best compression)
+    #[cfg(feature = "gzip")]
+    Gzip(u32)
+}
+
+impl Compressor {
+    pub fn compression(&self) -> Compression {
+        match *self {
+            Compressor::None => Compression::None,
+            #[cfg(feature = "gzip")]
+            Compressor::Gzip(_) => Compression::Gzip
+        }
+    }
+
+    /// The level of the compressor, 0 for the ones without levels
+    pub fn level(&self) -> u32 {
+        match *self {
+            Compressor::None => 0,
+            #[cfg(feature = "gzip")]
+            Compressor::Gzip(level) => level
+        }
+    }
+
+    /// The compressor with the given compression and level, None if this build cannot
+    /// write that compression
+    #[cfg_attr(not(feature = "gzip"), allow(unused_variables))]
+    pub fn with_compression(compression: Compression, level: u32) -> Option<Compressor> {
+        match compression {
+            Compression::None => Some(Compressor::None),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Some(Compressor::Gzip(level)),
+            _ => None
+        }
+    }
+
+    pub fn compress<'a>(&self, bytes: &'a [u8]) -> Cow<'a, [u8]> {
+        match *self {
+            Compressor::None => Cow::Borrowed(bytes),
+            #[cfg(feature = "gzip")]
+            Compressor::Gzip(level) => {
+                use std::io::Write;
+                use flate2::write::GzEncoder;
+
+                let mut encoder = GzEncoder::new(Vec::new(), ::flate2::Compression::new(level));
+                // Writing into a vector cannot fail
+                encoder.write_all(bytes).unwrap();
+                Cow::Owned(encoder.finish().unwrap())
+            }
+        }
+    }
+}
+
+/// Undoes the compression of a chunk, checking that it has the expected size
+pub fn decompress(compression: Compression, bytes: Vec<u8>, uncompressed_size: usize) -> StorageResult<Vec<u8>> {
+    if compression == Compression::None {
+        try!(check_decompressed_size(bytes.len(), uncompressed_size));
+        return Ok(bytes);
+    }
+
+    let mut decompressed = Vec::with_capacity(uncompressed_size);
+    try!(decompress_into(compression, &bytes, uncompressed_size, &mut decompressed));
+    Ok(decompressed)
+}
+
+/// Like decompress, but writes into out (replacing its contents), so that its buffer can be
+/// reused across chunks
+pub fn decompress_into(compression: Compression, bytes: &[u8], uncompressed_size: usize, out: &mut Vec<u8>) -> StorageResult<()> {
+    out.clear();
+    out.reserve(uncompressed_size);
+    match compression {
+        Compression::None => out.extend_from_slice(bytes),
+        #[cfg(feature = "gzip")]
+        Compression::Gzip => {
+            use std::io::Read;
+            use flate2::read::GzDecoder;
+
+            try!(GzDecoder::new(bytes).read_to_end(out));
+        },
+        _ => return Err(StorageError::InvalidFormat(format!("Unsupported chunk compression {:?}", compression)))
+    }
+
+    check_decompressed_size(out.len(), uncompressed_size)
+}
+
+fn check_decompressed_size(size: usize, uncompressed_size: usize) -> StorageResult<()> {
+    if size != uncompressed_size {
+        return Err(StorageError::InvalidFormat(format!("Chunk decompressed to {} bytes, expected {}", size, uncompressed_size)));
+    }
+    Ok(())
+}
+
+// ----------------------------------------------------------------------------
+#[cfg(test)]
+mod test {
+    use ::compression::*;
+
+    #[test]
+    fn uncompressed_chunks_are_checked() {
+        let data = vec!(1, 2, 3);
+        assert_eq!(Compressor::None.compress(&data).into_owned(), data);
+        assert_eq!(decompress(Compression::None, data.clone(), 3).unwrap(), data);
+        assert!(decompress(Compression::None, data.clone(), 4).is_err());
+        assert!(decompress(Compression::Snappy, data.clone(), 3).is_err());
+
+        let mut out = vec!(9; 10);
+        decompress_into(Compression::None, &data, 3, &mut out).unwrap();
+        assert_eq!(out, data);
+        assert!(decompress_into(Compression::None, &data, 2, &mut out).is_err());
+        assert!(decompress_into(Compression::Snappy, &data, 3, &mut out).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn gzip_round_trip() {
+        let data: Vec<u8> = (0..10000).map(|i| (i % 7) as u8).collect();
+
+        let fast = Compressor::Gzip(1).compress(&data).into_owned();
+        let best = Compressor::Gzip(9).compress(&data).into_owned();
+        assert!(best.len() <= fast.len());
+        assert!(fast.len() < data.len());
+
+        assert_eq!(decompress(Compression::Gzip, fast, data.len()).unwrap(), data);
+        assert_eq!(decompress(Compression::Gzip, best.clone(), data.len()).unwrap(), data);
+        assert!(decompress(Compression::Gzip, best.clone(), data.len() + 1).is_err());
+
+        let mut out = Vec::new();
+        decompress_into(Compression::Gzip, &best, data.len(), &mut out).unwrap();
+        assert_eq!(out, data);
+    }
 }
@@ -0,0 +1,285 @@
+use std::path::Path;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::str;
+
+use ::storage::{Storage, StorageBuilder, ColumnDatatype, ColumnValue};
+use ::storage_reader::{StorageReader, StorageStripeIterator};
+use ::error::{StorageError, StorageResult};
+use ::export::ExportOptions;
+
+// ----------------------------------------------------------------------------
+/// Splits a CSV line into its fields. Fields can be quoted (with "" as an escaped quote),
+/// but they cannot span several lines.
+fn split_line(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes => {
+                if chars.peek() == Some(&'"') {
+                    field.push('"');
+                    chars.next();
+                } else {
+                    in_quotes = false;
+                }
+            },
+            '"' if field.is_empty() => in_quotes = true,
+            ',' if !in_quotes => fields.push(field.split_off(0)),
+            _ => field.push(c)
+        }
+    }
+    fields.push(field);
+
+    fields
+}
+
+/// Converts a CSV field to a value of the given type. Empty fields are NULL.
+fn parse_field(field: &str, datatype: &ColumnDatatype, line_number: usize) -> StorageResult<ColumnValue> {
+    if field.is_empty() {
+        return Ok(ColumnValue::Null);
+    }
+
+    let invalid = || StorageError::InvalidFormat(format!("Line {}: invalid {} value '{}'", line_number, datatype, field));
+
+    let value = match *datatype {
+        ColumnDatatype::Byte => ColumnValue::Byte(try!(field.parse().map_err(|_| invalid()))),
+        ColumnDatatype::Int16 => ColumnValue::Int16(try!(field.parse().map_err(|_| invalid()))),
+        ColumnDatatype::Int32 => ColumnValue::Int32(try!(field.parse().map_err(|_| invalid()))),
+        ColumnDatatype::Int64 => ColumnValue::Int64(try!(field.parse().map_err(|_| invalid()))),
+        ColumnDatatype::Float => ColumnValue::Float(try!(field.parse().map_err(|_| invalid()))),
+        ColumnDatatype::Timestamp => ColumnValue::Timestamp(try!(field.parse().map_err(|_| invalid()))),
+        ColumnDatatype::FixedLength(_) => ColumnValue::FixedLength(field.as_bytes().to_vec()),
+        ColumnDatatype::VariableLength => ColumnValue::VariableLength(field.as_bytes().to_vec()),
+    };
+
+    Ok(value)
+}
+
+// ----------------------------------------------------------------------------
+/// Creates a storage at dest with the given schema, and fills it with the rows of a CSV
+/// file. The first line of the file is a header with the column names.
+pub fn import_csv<P: AsRef<Path>>(csv_path: P, schema: &[(&str, ColumnDatatype)], dest: &Path) -> StorageResult<Storage> {
+    let mut lines = BufReader::new(try!(File::open(csv_path))).lines();
+
+    // Check the header
+    let header = match lines.next() {
+        Some(line) => split_line(&try!(line)),
+        None => return Err(StorageError::InvalidFormat("Missing CSV header".to_string()))
+    };
+    let expected: Vec<&str> = schema.iter().map(|&(name, _)| name).collect();
+    if header != expected {
+        return Err(StorageError::InvalidFormat(format!("CSV header {:?} does not match the schema {:?}", header, expected)));
+    }
+
+    let mut builder = StorageBuilder::new();
+    for &(name, datatype) in schema.iter() {
+        builder.column(name, datatype);
+    }
+    let storage = try!(builder.at(dest));
+
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        for (i, line) in lines.enumerate() {
+            // Line numbers start at 1, and the header is line 1
+            let line_number = i + 2;
+            let fields = split_line(&try!(line));
+            if fields.len() != schema.len() {
+                return Err(StorageError::InvalidFormat(format!("Line {}: expected {} fields, got {}", line_number, schema.len(), fields.len())));
+            }
+
+            let mut row = Vec::with_capacity(fields.len());
+            for (field, &(_, ref datatype)) in fields.iter().zip(schema.iter()) {
+                row.push(try!(parse_field(field, datatype, line_number)));
+            }
+            try!(inserter.enqueue_row(&row));
+        }
+        try!(inserter.finish());
+    }
+
+    insertion_manager.finish_inserting()
+}
+
+/// Quotes a field if it contains a separator, a quote or a line break
+fn escape_field(field: &str) -> String {
+    if field.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace("\"", "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Renders a value as a CSV field. NULL values are rendered as in the options (empty fields
+/// by default), and binary values as UTF-8 if possible, or as hexadecimal bytes otherwise.
+fn render_field(value: &ColumnValue, options: &ExportOptions) -> String {
+    match *value {
+        ColumnValue::Null => escape_field(options.null.text().unwrap_or("")),
+        ColumnValue::Byte(v) => v.to_string(),
+        ColumnValue::Int16(v) => v.to_string(),
+        ColumnValue::Int32(v) => v.to_string(),
+        ColumnValue::Int64(v) => v.to_string(),
+        ColumnValue::Float(v) => v.to_string(),
+        ColumnValue::Timestamp(v) => v.to_string(),
+        ColumnValue::FixedLength(ref v) | ColumnValue::VariableLength(ref v) => match str::from_utf8(v) {
+            Ok(s) => escape_field(s),
+            Err(_) => v.iter().map(|b| format!("{:02X}", b)).collect()
+        }
+    }
+}
+
+/// Writes all the rows of a storage as CSV, preceded by a header with the column names
+pub fn export_csv<W: Write>(reader: &mut StorageReader, out: &mut W, options: &ExportOptions) -> StorageResult<()> {
+    let storage = reader.storage();
+
+    let header: Vec<String> = storage.columns().iter().map(|c| escape_field(&c.name)).collect();
+    try!(writeln!(out, "{}", header.join(",")));
+
+    for stripe in StorageStripeIterator::new(storage) {
+        let columns = try!(stripe.read_columns());
+        let num_rows = columns.first().map_or(0, |c| c.len());
+        for num_row in 0..num_rows {
+            let fields: Vec<String> = storage.columns().iter().zip(columns.iter())
+                .map(|(column, values)| {
+                    let value = &values[num_row];
+                    match value.as_fixed_string() {
+                        Some(s) if column.is_fixed_string() => escape_field(s),
+                        _ => render_field(value, options)
+                    }
+                })
+                .collect();
+            try!(writeln!(out, "{}", fields.join(",")));
+        }
+    }
+
+    Ok(())
+}
+
+// ----------------------------------------------------------------------------
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::Write;
+
+    use ::csv::*;
+    use ::export::{ExportOptions, NullFormat};
+    use ::storage::{StorageBuilder, ColumnDatatype, ColumnValue};
+    use ::storage_reader::StorageReader;
+    use ::test::TestPath;
+
+    #[test]
+    fn split_csv_lines() {
+        assert_eq!(split_line("a,b,,c"), vec!("a", "b", "", "c"));
+        assert_eq!(split_line("\"a,b\",\"say \"\"hi\"\"\""), vec!("a,b", "say \"hi\""));
+    }
+
+    #[test]
+    fn import_a_small_csv() {
+        let test_path = TestPath::new();
+        let csv_file = test_path.file_name("test.csv");
+        {
+            let mut file = File::create(&csv_file).unwrap();
+            write!(file, "id,value,name\n1,10,one\n2,,two\n3,30,\n").unwrap();
+        }
+
+        let schema = [("id", ColumnDatatype::Int32), ("value", ColumnDatatype::Int64), ("name", ColumnDatatype::VariableLength)];
+        let storage = import_csv(&csv_file, &schema, &test_path.file_name("test.storage")).unwrap();
+        assert_eq!(storage.num_rows(), 3);
+
+        let rows = storage.read_rows(&[1, 2]).unwrap();
+        assert_eq!(rows[0], vec!(ColumnValue::Int32(2), ColumnValue::Null, ColumnValue::VariableLength("two".into())));
+        assert_eq!(rows[1], vec!(ColumnValue::Int32(3), ColumnValue::Int64(30), ColumnValue::Null));
+    }
+
+    #[test]
+    #[should_panic(expected="Line 3")]
+    fn malformed_numbers_report_the_line() {
+        let test_path = TestPath::new();
+        let csv_file = test_path.file_name("test.csv");
+        {
+            let mut file = File::create(&csv_file).unwrap();
+            write!(file, "id\n1\nfoo\n").unwrap();
+        }
+
+        import_csv(&csv_file, &[("id", ColumnDatatype::Int32)], &test_path.file_name("test.storage")).unwrap();
+    }
+
+    #[test]
+    fn fixed_strings_are_exported_without_padding() {
+        let test_path = TestPath::new();
+        let file_name = test_path.file_name("test.storage");
+        let storage = StorageBuilder::new()
+            .column_fixed_string("code", 6)
+            .column("raw", ColumnDatatype::FixedLength(3))
+            .at(&file_name).unwrap();
+        assert!(storage.column(0).is_fixed_string());
+        assert!(!storage.column(1).is_fixed_string());
+
+        let mut insertion_manager = storage.begin_inserting();
+        {
+            let mut inserter = insertion_manager.create_inserter();
+            inserter.enqueue_row(&vec!(ColumnValue::FixedLength(b"ab".to_vec()), ColumnValue::FixedLength(vec!(b'x', 0, 0)))).unwrap();
+            inserter.enqueue_row(&vec!(ColumnValue::fixed_string("a,b", 6).unwrap(), ColumnValue::Null)).unwrap();
+            assert!(inserter.enqueue_row(&vec!(ColumnValue::FixedLength(b"toolong".to_vec()), ColumnValue::Null)).is_err());
+        }
+        insertion_manager.finish_inserting().unwrap();
+
+        let mut reader = StorageReader::open(&file_name).unwrap();
+        assert!(reader.storage().column(0).is_fixed_string());
+        assert_eq!(reader.get_row(0).unwrap()[0], ColumnValue::FixedLength(b"ab\0\0\0\0".to_vec()));
+        assert_eq!(reader.get_row(0).unwrap()[0].as_fixed_string(), Some("ab"));
+
+        let mut exported = Vec::new();
+        export_csv(&mut reader, &mut exported, &ExportOptions::default()).unwrap();
+        assert_eq!(String::from_utf8(exported).unwrap(), "code,raw\nab,x\0\0\n\"a,b\",\n");
+
+        assert!(StorageBuilder::new().column_fixed_string("code", 0).in_memory().is_err());
+    }
+
+    #[test]
+    fn nulls_are_exported_as_in_the_options() {
+        let storage = StorageBuilder::new()
+            .column("id", ColumnDatatype::Int32)
+            .column("name", ColumnDatatype::VariableLength)
+            .in_memory().unwrap();
+
+        let mut insertion_manager = storage.begin_inserting();
+        {
+            let mut inserter = insertion_manager.create_inserter();
+            inserter.enqueue_row(&vec!(ColumnValue::Int32(1), ColumnValue::Null)).unwrap();
+        }
+        let bytes = insertion_manager.finish_to_bytes().unwrap();
+
+        let expected = [(NullFormat::Native, "id,name\n1,\n"), (NullFormat::Empty, "id,name\n1,\n"),
+                        (NullFormat::Literal, "id,name\n1,NULL\n"), (NullFormat::Backslash, "id,name\n1,\\N\n")];
+        for &(null, csv) in expected.iter() {
+            let mut reader = StorageReader::from_bytes(bytes.clone()).unwrap();
+            let mut exported = Vec::new();
+            export_csv(&mut reader, &mut exported, &ExportOptions { null: null }).unwrap();
+            assert_eq!(String::from_utf8(exported).unwrap(), csv);
+        }
+    }
+
+    #[test]
+    fn exported_csv_matches_the_imported_one() {
+        let test_path = TestPath::new();
+        let csv_file = test_path.file_name("test.csv");
+        let contents = "id,flag,code,name\n1,-3,abc,\"Doe, John\"\n2,,,\n3,7,xyz,\"say \"\"hi\"\"\"\n";
+        {
+            let mut file = File::create(&csv_file).unwrap();
+            file.write_all(contents.as_bytes()).unwrap();
+        }
+
+        let schema = [("id", ColumnDatatype::Int64), ("flag", ColumnDatatype::Byte),
+            ("code", ColumnDatatype::FixedLength(3)), ("name", ColumnDatatype::VariableLength)];
+        import_csv(&csv_file, &schema, &test_path.file_name("test.storage")).unwrap();
+
+        let mut reader = StorageReader::open(test_path.file_name("test.storage")).unwrap();
+        let mut exported = Vec::new();
+        export_csv(&mut reader, &mut exported, &ExportOptions::default()).unwrap();
+        assert_eq!(String::from_utf8(exported).unwrap(), contents);
+    }
+}
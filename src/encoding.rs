@@ -1,6 +1,471 @@
-#[derive(Copy, Clone)]
+use std::{u32, u64};
+use std::collections::HashMap;
+
+use ::error::{StorageError, StorageResult};
+
+/// How the values of a chunk are encoded, as recorded in its header
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Encoding {
     Raw,
     Delta,
-    RLE
+    RLE,
+    DeltaOfDelta,
+    /// The values minus a base (the minimum of the chunk), stored in the chunk header
+    FrameOfReference(i64),
+    /// Codes into the dictionary of the column (see GlobalDictionary), -1 for NULL. Only for
+    /// variable length chunks.
+    Dictionary
+}
+
+/// How the inserters choose the encoding of numeric chunks. Timestamps are always encoded
+/// with delta-of-delta.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Encoder {
+    /// The values as they are
+    Raw,
+    /// Each chunk is encoded with all the encodings that apply to its type (raw, RLE, and
+    /// for integers delta and frame of reference), keeping the smallest result
+    Auto
+}
+
+/// Whether the chunks of a variable length column store codes into a dictionary shared by
+/// the whole storage, which is written once in the footer instead of in every chunk
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DictionaryMode {
+    None,
+    /// Each chunk uses the dictionary if the values it would add to it take less than the
+    /// values of the chunk, e.g. for columns with few distinct values
+    Auto,
+    /// Every chunk uses the dictionary, whatever the values
+    Always
+}
+
+// ----------------------------------------------------------------------------
+/// The values of a column in code order. Values are only ever added, so the codes in the
+/// chunks already written never change.
+#[derive(Debug, Clone, Default)]
+pub struct GlobalDictionary {
+    values: Vec<Vec<u8>>,
+    codes: HashMap<Vec<u8>, i32>
+}
+
+impl GlobalDictionary {
+    pub fn new() -> GlobalDictionary {
+        GlobalDictionary { values: Vec::new(), codes: HashMap::new() }
+    }
+
+    /// The dictionary with the given values, e.g. read from the footer
+    pub fn from_values(values: Vec<Vec<u8>>) -> GlobalDictionary {
+        let codes = values.iter().enumerate().map(|(code, v)| (v.clone(), code as i32)).collect();
+        GlobalDictionary { values: values, codes: codes }
+    }
+
+    pub fn len(&self) -> usize { self.values.len() }
+    pub fn is_empty(&self) -> bool { self.values.is_empty() }
+    pub fn values(&self) -> &[Vec<u8>] { &self.values }
+
+    pub fn value(&self, code: i32) -> Option<&[u8]> {
+        if code < 0 {
+            return None;
+        }
+        self.values.get(code as usize).map(|v| &v[..])
+    }
+
+    pub fn code(&self, value: &[u8]) -> Option<i32> {
+        self.codes.get(value).cloned()
+    }
+
+    /// The code of the value, adding it to the dictionary if it is not there yet
+    pub fn insert(&mut self, value: &[u8]) -> i32 {
+        if let Some(code) = self.code(value) {
+            return code;
+        }
+
+        let code = self.values.len() as i32;
+        self.values.push(value.to_vec());
+        self.codes.insert(value.to_vec(), code);
+        code
+    }
+}
+
+// ----------------------------------------------------------------------------
+/// Maps signed integers to unsigned ones so that small magnitudes (positive or negative)
+/// become small values: 0, -1, 1, -2, 2... become 0, 1, 2, 3, 4...
+pub fn zigzag(v: i64) -> u64 { ((v << 1) ^ (v >> 63)) as u64 }
+pub fn unzigzag(v: u64) -> i64 { ((v >> 1) as i64) ^ -((v & 1) as i64) }
+
+pub fn write_varint(mut v: u64, out: &mut Vec<u8>) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+pub fn read_varint(bytes: &mut &[u8]) -> StorageResult<u64> {
+    let mut result: u64 = 0;
+    let mut shift = 0;
+    loop {
+        let b = match bytes.split_first() {
+            Some((&b, rest)) => { *bytes = rest; b },
+            None => return Err(StorageError::InvalidFormat("Truncated varint".to_string()))
+        };
+        if shift >= 64 {
+            return Err(StorageError::InvalidFormat("Varint is too long".to_string()));
+        }
+
+        result |= ((b & 0x7F) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Ok(result);
+        }
+        shift += 7;
+    }
+}
+
+// ----------------------------------------------------------------------------
+/// Delta encoding: the first value and then the differences between consecutive values.
+/// The differences of signed values are often negative, so they are zigzagged before being
+/// written as varints. The differences must fit in an i64, see delta_encodable.
+pub fn encode_delta(values: &[i64], out: &mut Vec<u8>) {
+    let mut previous: i64 = 0;
+    for &v in values.iter() {
+        write_varint(zigzag(v.wrapping_sub(previous)), out);
+        previous = v;
+    }
+}
+
+/// Whether the differences between consecutive values fit in an i64, so that the values
+/// can be delta-encoded (swings larger than the range of i64 can't).
+pub fn delta_encodable(values: &[i64]) -> bool {
+    values.windows(2).all(|pair| pair[1].checked_sub(pair[0]).is_some())
+}
+
+/// The sum is accumulated in an i128, so a corrupt stream that reconstructs a value out of
+/// the range of i64 is an error instead of silently wrapping around.
+pub fn decode_delta(mut bytes: &[u8]) -> StorageResult<Vec<i64>> {
+    let mut values = Vec::new();
+    let mut previous: i128 = 0;
+    while !bytes.is_empty() {
+        let v = previous + unzigzag(try!(read_varint(&mut bytes))) as i128;
+        if v < i64::MIN as i128 || v > i64::MAX as i128 {
+            return Err(StorageError::InvalidFormat(
+                format!("Delta stream reconstructs {}, out of the range of i64", v)));
+        }
+
+        values.push(v as i64);
+        previous = v;
+    }
+
+    Ok(values)
+}
+
+// ----------------------------------------------------------------------------
+/// Delta-of-delta encoding: the first value, the first delta and then the differences
+/// between consecutive deltas, all of them as zigzag varints. Near-regular series (like
+/// timestamps) end up using about one byte per value.
+pub fn encode_delta_of_delta(values: &[i64], out: &mut Vec<u8>) {
+    let mut previous_value: i64 = 0;
+    let mut previous_delta: i64 = 0;
+    for (i, &v) in values.iter().enumerate() {
+        let delta = v.wrapping_sub(previous_value);
+        let encoded = if i == 0 { v } else { delta.wrapping_sub(previous_delta) };
+        write_varint(zigzag(encoded), out);
+
+        previous_value = v;
+        previous_delta = if i == 0 { 0 } else { delta };
+    }
+}
+
+pub fn decode_delta_of_delta(mut bytes: &[u8]) -> StorageResult<Vec<i64>> {
+    let mut values = Vec::new();
+    let mut previous_value: i64 = 0;
+    let mut previous_delta: i64 = 0;
+    while !bytes.is_empty() {
+        let encoded = unzigzag(try!(read_varint(&mut bytes)));
+        let v = if values.is_empty() {
+            encoded
+        } else {
+            let delta = previous_delta.wrapping_add(encoded);
+            previous_delta = delta;
+            previous_value.wrapping_add(delta)
+        };
+
+        values.push(v);
+        previous_value = v;
+    }
+
+    Ok(values)
+}
+
+// ----------------------------------------------------------------------------
+/// Run-length encoding of fixed-size values: each run is its length as a little-endian u32
+/// followed by the bytes of the repeated value. Runs longer than u32::MAX are split.
+pub fn encode_rle(values: &[u8], value_size: usize, out: &mut Vec<u8>) {
+    encode_rle_with_max_run(values, value_size, u32::MAX, out)
+}
+
+fn encode_rle_with_max_run(values: &[u8], value_size: usize, max_run: u32, out: &mut Vec<u8>) {
+    let mut runs = values.chunks(value_size).peekable();
+    while let Some(value) = runs.next() {
+        let mut run_length: u32 = 1;
+        while run_length < max_run && runs.peek() == Some(&value) {
+            runs.next();
+            run_length += 1;
+        }
+
+        out.extend_from_slice(&run_length.to_le_bytes());
+        out.extend_from_slice(value);
+    }
+}
+
+/// Expands the runs back into exactly num_values values of value_size bytes each
+pub fn decode_rle(mut bytes: &[u8], value_size: usize, num_values: usize) -> StorageResult<Vec<u8>> {
+    let mut values = Vec::with_capacity(num_values * value_size);
+    let mut decoded = 0;
+    while !bytes.is_empty() {
+        if bytes.len() < 4 + value_size {
+            return Err(StorageError::InvalidFormat("Truncated RLE run".to_string()));
+        }
+
+        let mut length_buf = [0u8; 4];
+        length_buf.copy_from_slice(&bytes[..4]);
+        let run_length = u32::from_le_bytes(length_buf) as usize;
+        if run_length > num_values - decoded {
+            return Err(StorageError::InvalidFormat("RLE runs exceed the number of values".to_string()));
+        }
+
+        let value = &bytes[4..4 + value_size];
+        for _ in 0..run_length {
+            values.extend_from_slice(value);
+        }
+
+        decoded += run_length;
+        bytes = &bytes[4 + value_size..];
+    }
+
+    if decoded != num_values {
+        return Err(StorageError::InvalidFormat(format!("RLE runs have {} values, expected {}", decoded, num_values)));
+    }
+
+    Ok(values)
+}
+
+// ----------------------------------------------------------------------------
+/// Frame-of-reference encoding: the values minus the minimum of all of them (the base, which
+/// is returned), bit-packed with the width of the largest residual. The width is written as
+/// a first byte. Values that cluster around some base end up using only a few bits.
+pub fn encode_frame_of_reference(values: &[i64], out: &mut Vec<u8>) -> i64 {
+    let base = values.iter().cloned().min().unwrap_or(0);
+    let max_residual = values.iter().map(|&v| v.wrapping_sub(base) as u64).max().unwrap_or(0);
+    let width = 64 - max_residual.leading_zeros();
+    out.push(width as u8);
+
+    let mut buffer: u128 = 0;
+    let mut buffered_bits = 0;
+    for &v in values.iter() {
+        buffer |= (v.wrapping_sub(base) as u64 as u128) << buffered_bits;
+        buffered_bits += width;
+        while buffered_bits >= 8 {
+            out.push(buffer as u8);
+            buffer >>= 8;
+            buffered_bits -= 8;
+        }
+    }
+    if buffered_bits > 0 {
+        out.push(buffer as u8);
+    }
+
+    base
+}
+
+pub fn decode_frame_of_reference(bytes: &[u8], base: i64, num_values: usize) -> StorageResult<Vec<i64>> {
+    let (width, packed) = match bytes.split_first() {
+        Some((&width, packed)) if width <= 64 => (width as usize, packed),
+        _ => return Err(StorageError::InvalidFormat("Invalid frame of reference chunk".to_string()))
+    };
+    if packed.len() != (num_values * width).div_ceil(8) {
+        return Err(StorageError::InvalidFormat("Frame of reference chunk does not match its number of values".to_string()));
+    }
+
+    let mask = if width == 64 { u64::MAX } else { (1u64 << width) - 1 };
+    let mut values = Vec::with_capacity(num_values);
+    let mut bytes = packed.iter();
+    let mut buffer: u128 = 0;
+    let mut buffered_bits = 0;
+    for _ in 0..num_values {
+        while buffered_bits < width {
+            buffer |= (*bytes.next().unwrap() as u128) << buffered_bits;
+            buffered_bits += 8;
+        }
+
+        values.push(base.wrapping_add((buffer as u64 & mask) as i64));
+        buffer >>= width;
+        buffered_bits -= width;
+    }
+
+    Ok(values)
+}
+
+// ----------------------------------------------------------------------------
+#[cfg(test)]
+mod test {
+    use std::i64;
+    use ::error::StorageError;
+    use ::encoding::*;
+
+    #[test]
+    fn delta_of_delta_round_trip() {
+        let series = vec!(1000, 2000, 3000, 4001, 4999, 6000, i64::MIN, i64::MAX, 0, -5);
+        let mut encoded = Vec::new();
+        encode_delta_of_delta(&series, &mut encoded);
+        assert_eq!(decode_delta_of_delta(&encoded).unwrap(), series);
+
+        let mut encoded = Vec::new();
+        encode_delta_of_delta(&[], &mut encoded);
+        assert!(encoded.is_empty());
+        assert!(decode_delta_of_delta(&encoded).unwrap().is_empty());
+
+        assert!(decode_delta_of_delta(&[0x80]).is_err());
+    }
+
+    #[test]
+    fn zigzag_values() {
+        assert_eq!((-2..3).map(zigzag).collect::<Vec<u64>>(), vec!(3, 1, 0, 2, 4));
+        for &v in [0, 1, -1, i64::MIN, i64::MAX].iter() {
+            assert_eq!(unzigzag(zigzag(v)), v);
+        }
+    }
+
+    #[test]
+    fn delta_of_a_noisy_series() {
+        // A noisy signed series: the deltas are small, but many of them are negative
+        let series: Vec<i64> = (0..1000i64).map(|i| (i * 5 % 13) - 6 - i / 10).collect();
+
+        let mut encoded = Vec::new();
+        encode_delta(&series, &mut encoded);
+        assert_eq!(decode_delta(&encoded).unwrap(), series);
+
+        // The same deltas written without zigzag take the full 10 bytes when negative
+        let mut without_zigzag = Vec::new();
+        let mut previous = 0;
+        for &v in series.iter() {
+            write_varint((v - previous) as u64, &mut without_zigzag);
+            previous = v;
+        }
+
+        assert!(encoded.len() * 2 < without_zigzag.len());
+    }
+
+    #[test]
+    fn delta_of_large_swings() {
+        // Every delta fits in an i64, even though the sum of their magnitudes doesn't
+        let series = vec!(i64::MAX, 0, i64::MIN, -1, i64::MAX - 1, 0, i64::MIN + 1);
+        assert!(delta_encodable(&series));
+        let mut encoded = Vec::new();
+        encode_delta(&series, &mut encoded);
+        assert_eq!(decode_delta(&encoded).unwrap(), series);
+
+        assert!(!delta_encodable(&[i64::MIN, i64::MAX]));
+        assert!(!delta_encodable(&[0, -1, i64::MAX]));
+    }
+
+    #[test]
+    fn corrupt_deltas_are_rejected() {
+        // i64::MAX followed by +1, which would wrap around to i64::MIN
+        let mut encoded = Vec::new();
+        write_varint(zigzag(i64::MAX), &mut encoded);
+        write_varint(zigzag(1), &mut encoded);
+        match decode_delta(&encoded) {
+            Err(StorageError::InvalidFormat(_)) => (),
+            other => panic!("Unexpected result {:?}", other)
+        }
+
+        let mut encoded = Vec::new();
+        write_varint(zigzag(i64::MIN), &mut encoded);
+        write_varint(zigzag(-1), &mut encoded);
+        assert!(decode_delta(&encoded).is_err());
+    }
+
+    #[test]
+    fn rle_round_trip() {
+        let values: Vec<u8> = vec!(1, 1, 1, 2, 3, 3, 1);
+        let mut encoded = Vec::new();
+        encode_rle(&values, 1, &mut encoded);
+        assert_eq!(encoded, vec!(3, 0, 0, 0, 1, 1, 0, 0, 0, 2, 2, 0, 0, 0, 3, 1, 0, 0, 0, 1));
+        assert_eq!(decode_rle(&encoded, 1, values.len()).unwrap(), values);
+
+        // Wrong number of values, or a truncated run
+        assert!(decode_rle(&encoded, 1, values.len() + 1).is_err());
+        assert!(decode_rle(&encoded, 1, values.len() - 1).is_err());
+        assert!(decode_rle(&encoded[..encoded.len() - 1], 1, values.len()).is_err());
+    }
+
+    #[test]
+    fn long_runs_are_split() {
+        // 10 equal 2-byte values with runs of at most 4 values
+        let values: Vec<u8> = [7u8, 1].iter().cloned().cycle().take(20).collect();
+        let mut encoded = Vec::new();
+        encode_rle_with_max_run(&values, 2, 4, &mut encoded);
+        assert_eq!(encoded, vec!(4, 0, 0, 0, 7, 1, 4, 0, 0, 0, 7, 1, 2, 0, 0, 0, 7, 1));
+        assert_eq!(decode_rle(&encoded, 2, 10).unwrap(), values);
+    }
+
+    #[test]
+    fn frame_of_reference_round_trip() {
+        let series = vec!(-3, 0, 7, 1000, -3, i64::MAX);
+        let mut encoded = Vec::new();
+        let base = encode_frame_of_reference(&series, &mut encoded);
+        assert_eq!(base, -3);
+        assert_eq!(decode_frame_of_reference(&encoded, base, series.len()).unwrap(), series);
+
+        // The full range needs all the 64 bits
+        let series = vec!(i64::MIN, i64::MAX, 0);
+        let mut encoded = Vec::new();
+        let base = encode_frame_of_reference(&series, &mut encoded);
+        assert_eq!(encoded[0], 64);
+        assert_eq!(decode_frame_of_reference(&encoded, base, series.len()).unwrap(), series);
+
+        // All values equal take no bits at all
+        let mut encoded = Vec::new();
+        let base = encode_frame_of_reference(&[42; 100], &mut encoded);
+        assert_eq!(encoded, vec!(0));
+        assert_eq!(decode_frame_of_reference(&encoded, base, 100).unwrap(), vec!(42; 100));
+
+        assert!(decode_frame_of_reference(&encoded, base, 101).is_ok());
+        assert!(decode_frame_of_reference(&[3, 0], 0, 10).is_err());
+        assert!(decode_frame_of_reference(&[], 0, 0).is_err());
+    }
+
+    #[test]
+    fn clustered_values_are_smaller_with_frame_of_reference() {
+        // Readings between 20000 and 20049, 6 bits each instead of 32
+        let series: Vec<i64> = (0..1000i64).map(|i| 20000 + i * 7 % 50).collect();
+        let mut encoded = Vec::new();
+        let base = encode_frame_of_reference(&series, &mut encoded);
+        assert_eq!(base, 20000);
+        assert_eq!(decode_frame_of_reference(&encoded, base, series.len()).unwrap(), series);
+
+        let raw_size = series.len() * 4;
+        assert_eq!(encoded.len(), 1 + 750);
+        assert!(encoded.len() * 5 < raw_size);
+    }
+
+    #[test]
+    fn dictionary_codes_never_change() {
+        let mut dictionary = GlobalDictionary::new();
+        assert_eq!(dictionary.insert(b"ES"), 0);
+        assert_eq!(dictionary.insert(b"AR"), 1);
+        assert_eq!(dictionary.insert(b"ES"), 0);
+        assert_eq!(dictionary.insert(b""), 2);
+        assert_eq!(dictionary.code(b"AR"), Some(1));
+        assert_eq!(dictionary.code(b"FR"), None);
+        assert_eq!(dictionary.value(2), Some(&b""[..]));
+        assert_eq!(dictionary.value(3), None);
+        assert_eq!(dictionary.value(-1), None);
+
+        let copy = GlobalDictionary::from_values(dictionary.values().to_vec());
+        assert_eq!(copy.len(), 3);
+        assert_eq!(copy.code(b"ES"), Some(0));
+        assert_eq!(copy.value(1), Some(&b"AR"[..]));
+    }
 }
@@ -1,6 +1,8 @@
 use std::path::PathBuf;
 use std::io;
 
+use ::storage::ColumnDatatype;
+
 #[derive(Debug)]
 pub enum StorageError {
     FileAlreadyExists,
@@ -9,7 +11,17 @@ pub enum StorageError {
     IoError(io::Error),
     InvalidNumberOfColumns(usize, usize),
     TypeError,
-    InvalidLength(usize, usize)
+    /// A value of the wrong type for the column with that index
+    TypeMismatch { column: usize, expected: ColumnDatatype },
+    InvalidLength(usize, usize),
+    PendingInserters,
+    CheckFailed { column: String },
+    NullNotAllowed(String),
+    InvalidRowIndex(usize),
+    InvalidStripeIndex(usize),
+    /// The chunks of the stripe with that index don't match the checksum in its header
+    ChecksumMismatch { stripe: usize },
+    ValueTooLarge { column: String, size: usize, limit: usize }
 }
 
 /*impl fmt::Debug for StorageError {
@@ -27,4 +39,14 @@ impl From<io::Error> for StorageError {
     fn from(err: io::Error) -> StorageError { StorageError::IoError(err) }
 }
 
+impl From<::capnp::Error> for StorageError {
+    fn from(err: ::capnp::Error) -> StorageError { StorageError::InvalidFormat(err.description) }
+}
+
+impl From<::capnp::NotInSchema> for StorageError {
+    fn from(err: ::capnp::NotInSchema) -> StorageError {
+        StorageError::InvalidFormat(format!("Value {} is not present in the schema", err.0))
+    }
+}
+
 pub type StorageResult<T> = Result<T, StorageError>;
@@ -0,0 +1,37 @@
+// ----------------------------------------------------------------------------
+/// How NULL values are rendered when exporting, see ExportOptions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NullFormat {
+    /// The usual NULL of the format: an empty field in CSV, null in JSON
+    Native,
+    /// An empty field, which in JSON is an empty string
+    Empty,
+    /// The literal NULL
+    Literal,
+    /// \N, as in the text format of PostgreSQL's COPY
+    Backslash
+}
+
+impl NullFormat {
+    /// The text of a NULL value, None for the format's own representation
+    pub fn text(&self) -> Option<&'static str> {
+        match *self {
+            NullFormat::Native => None,
+            NullFormat::Empty => Some(""),
+            NullFormat::Literal => Some("NULL"),
+            NullFormat::Backslash => Some("\\N")
+        }
+    }
+}
+
+/// Options of export_csv and export_jsonl
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportOptions {
+    pub null: NullFormat
+}
+
+impl Default for ExportOptions {
+    fn default() -> ExportOptions {
+        ExportOptions { null: NullFormat::Native }
+    }
+}
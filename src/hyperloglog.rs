@@ -0,0 +1,107 @@
+use std::cmp;
+
+use ::checksum::Fnv1a64;
+
+/// Number of bits of the hash used to choose a register
+const PRECISION: u32 = 12;
+const NUM_REGISTERS: usize = 1 << PRECISION;
+
+// ----------------------------------------------------------------------------
+/// The 64-bit finalizer of MurmurHash3: every bit of the input affects every bit of the
+/// output
+fn fmix64(mut h: u64) -> u64 {
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xFF51AFD7ED558CCD);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xC4CEB9FE1A85EC53);
+    h ^ (h >> 33)
+}
+
+/// Estimates the number of distinct values seen, using 4 KB whatever their number. With
+/// 4096 registers the standard error is 1.04/sqrt(4096), about 1.6%, so estimates are
+/// usually within 2% of the real count (and exact-ish for small counts).
+pub struct HyperLogLog {
+    registers: Vec<u8>
+}
+
+impl HyperLogLog {
+    pub fn new() -> HyperLogLog {
+        HyperLogLog { registers: vec!(0; NUM_REGISTERS) }
+    }
+
+    pub fn insert(&mut self, bytes: &[u8]) {
+        // FNV-1a is specified, so the estimates stored in the chunk headers don't depend on
+        // the toolchain. It mixes short values poorly into the high bits, which pick the
+        // register, so it goes through the finalizer of MurmurHash3 too.
+        let mut hasher = Fnv1a64::new();
+        hasher.update(bytes);
+        let hash = fmix64(hasher.finish());
+
+        let index = (hash >> (64 - PRECISION)) as usize;
+        // Position of the first set bit in the remaining bits, counting from 1
+        let rank = cmp::min((hash << PRECISION).leading_zeros() + 1, 64 - PRECISION + 1) as u8;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    pub fn estimate(&self) -> usize {
+        let m = NUM_REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum = self.registers.iter().map(|&r| 2f64.powi(-(r as i32))).fold(0.0, |a, b| a + b);
+        let estimate = alpha * m * m / sum;
+
+        // Small cardinalities leave registers empty, linear counting is more accurate then
+        let empty_registers = self.registers.iter().filter(|&&r| r == 0).count();
+        if estimate <= 2.5 * m && empty_registers > 0 {
+            return (m * (m / empty_registers as f64).ln()).round() as usize;
+        }
+
+        estimate.round() as usize
+    }
+
+    pub fn clear(&mut self) {
+        for r in self.registers.iter_mut() {
+            *r = 0;
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+#[cfg(test)]
+mod test {
+    use ::hyperloglog::HyperLogLog;
+
+    fn within(estimate: usize, expected: usize, tolerance: f64) -> bool {
+        (estimate as f64 - expected as f64).abs() <= expected as f64 * tolerance
+    }
+
+    #[test]
+    fn distinct_values_are_estimated() {
+        let mut hll = HyperLogLog::new();
+        assert_eq!(hll.estimate(), 0);
+
+        for i in 0..10000u32 {
+            hll.insert(&(i % 100).to_le_bytes());
+        }
+        assert!(within(hll.estimate(), 100, 0.02), "{}", hll.estimate());
+
+        for i in 0..100000u32 {
+            hll.insert(&i.to_le_bytes());
+        }
+        assert!(within(hll.estimate(), 100000, 0.05), "{}", hll.estimate());
+
+        hll.clear();
+        assert_eq!(hll.estimate(), 0);
+    }
+
+    #[test]
+    fn estimates_do_not_depend_on_the_toolchain() {
+        // The estimates are stored in the chunk headers, so they must not change
+        let mut hll = HyperLogLog::new();
+        for i in 0..50000u64 {
+            hll.insert(&i.to_le_bytes());
+        }
+        assert_eq!(hll.estimate(), 49267);
+    }
+}
@@ -0,0 +1,156 @@
+use std::io::Write;
+use std::str;
+
+use ::storage::ColumnValue;
+use ::storage_reader::StorageReader;
+use ::error::StorageResult;
+use ::export::ExportOptions;
+
+// ----------------------------------------------------------------------------
+/// A JSON string literal, with the quotes
+fn escape_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c)
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// Standard base64, with padding
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for group in bytes.chunks(3) {
+        let b = [group[0], *group.get(1).unwrap_or(&0), *group.get(2).unwrap_or(&0)];
+        let n = ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | (b[2] as u32);
+        for i in 0..4 {
+            if i <= group.len() {
+                encoded.push(ALPHABET[((n >> (18 - 6*i)) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Renders a value as JSON. Binary values are rendered as strings if they are UTF-8, or
+/// base64-encoded otherwise. Infinite and NaN floats have no JSON representation, so they
+/// are rendered as null. NULL values are null unless the options render them as a string.
+fn render_value(value: &ColumnValue, options: &ExportOptions) -> String {
+    match *value {
+        ColumnValue::Null => options.null.text().map_or("null".to_string(), escape_string),
+        ColumnValue::Byte(v) => v.to_string(),
+        ColumnValue::Int16(v) => v.to_string(),
+        ColumnValue::Int32(v) => v.to_string(),
+        ColumnValue::Int64(v) => v.to_string(),
+        ColumnValue::Float(v) => if v.is_finite() { v.to_string() } else { "null".to_string() },
+        ColumnValue::Timestamp(v) => v.to_string(),
+        ColumnValue::FixedLength(ref v) | ColumnValue::VariableLength(ref v) => match str::from_utf8(v) {
+            Ok(s) => escape_string(s),
+            Err(_) => escape_string(&base64(v))
+        }
+    }
+}
+
+/// Writes all the rows of a storage as JSON lines: one object per row, keyed by column name
+pub fn export_jsonl<W: Write>(reader: &mut StorageReader, out: &mut W, options: &ExportOptions) -> StorageResult<()> {
+    let names: Vec<String> = reader.storage().columns().iter().map(|c| escape_string(&c.name)).collect();
+
+    for row in reader.rows() {
+        let row = try!(row);
+        let fields: Vec<String> = names.iter().zip(row.iter())
+            .map(|(name, value)| format!("{}:{}", name, render_value(value, options)))
+            .collect();
+        try!(writeln!(out, "{{{}}}", fields.join(",")));
+    }
+
+    Ok(())
+}
+
+// ----------------------------------------------------------------------------
+#[cfg(test)]
+mod test {
+    use ::jsonl::*;
+    use ::export::{ExportOptions, NullFormat};
+    use ::storage::{StorageBuilder, ColumnDatatype, ColumnValue};
+    use ::storage_reader::StorageReader;
+
+    fn small_storage() -> StorageReader {
+        let storage = StorageBuilder::new()
+            .column("id", ColumnDatatype::Int32)
+            .column("value", ColumnDatatype::Float)
+            .column("name", ColumnDatatype::VariableLength)
+            .in_memory().unwrap();
+
+        let mut insertion_manager = storage.begin_inserting();
+        {
+            let mut inserter = insertion_manager.create_inserter();
+            inserter.enqueue_row(&vec!(ColumnValue::Int32(1), ColumnValue::Float(1.5), ColumnValue::VariableLength("say \"hi\"".to_string().into()))).unwrap();
+            inserter.enqueue_row(&vec!(ColumnValue::Null, ColumnValue::Float(-2.0), ColumnValue::VariableLength(vec!(0xFF, 0, 1)))).unwrap();
+        }
+
+        StorageReader::from_bytes(insertion_manager.finish_to_bytes().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn rows_are_exported_as_json_lines() {
+        let mut reader = small_storage();
+        let mut out = Vec::new();
+        export_jsonl(&mut reader, &mut out, &ExportOptions::default()).unwrap();
+
+        assert_eq!(String::from_utf8(out).unwrap(),
+            "{\"id\":1,\"value\":1.5,\"name\":\"say \\\"hi\\\"\"}\n\
+             {\"id\":null,\"value\":-2,\"name\":\"/wAB\"}\n");
+    }
+
+    #[test]
+    fn nulls_are_rendered_as_in_the_options() {
+        let expected = [(NullFormat::Native, "null"), (NullFormat::Empty, "\"\""),
+                        (NullFormat::Literal, "\"NULL\""), (NullFormat::Backslash, "\"\\\\N\"")];
+        for &(null, rendered) in expected.iter() {
+            let mut reader = small_storage();
+            let mut out = Vec::new();
+            export_jsonl(&mut reader, &mut out, &ExportOptions { null: null }).unwrap();
+
+            let second_line = String::from_utf8(out).unwrap().lines().nth(1).unwrap().to_string();
+            assert_eq!(second_line, format!("{{\"id\":{},\"value\":-2,\"name\":\"/wAB\"}}", rendered));
+        }
+    }
+
+    #[test]
+    fn exported_lines_are_valid_json() {
+        use ::serde_json;
+
+        let mut reader = small_storage();
+        let mut out = Vec::new();
+        export_jsonl(&mut reader, &mut out, &ExportOptions::default()).unwrap();
+
+        let lines: Vec<serde_json::Value> = String::from_utf8(out).unwrap().lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["name"], "say \"hi\"");
+        assert!(lines[1]["id"].is_null());
+    }
+}
@@ -1,16 +1,43 @@
 extern crate capnp;
 extern crate libc;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(feature = "gzip")]
+extern crate flate2;
+#[cfg(test)]
+extern crate serde_json;
+#[cfg(feature = "derive")]
+extern crate table_derive;
+// The derived code refers to ::table, which needs a name inside this crate too
+#[cfg(all(test, feature = "derive"))]
+extern crate self as table;
 
 pub mod storage;
 pub mod storage_inserter;
 pub mod error;
+pub mod storage_backend;
+pub mod storage_reader;
+pub mod nulls_bitmap;
+pub mod csv;
+pub mod jsonl;
+pub mod export;
+pub mod row;
 
+#[cfg(unix)]
 mod os;
 mod proto_structs;
 mod encoding;
 mod compression;
-mod storage_reader;
-mod storage_backend;
+mod checksum;
+mod hyperloglog;
+
+pub use encoding::{Encoding, Encoder, DictionaryMode, GlobalDictionary};
+pub use compression::{Compression, Compressor};
+#[cfg(feature = "derive")]
+pub use table_derive::Row;
 
 #[cfg(test)]
 mod test;
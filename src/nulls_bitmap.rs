@@ -0,0 +1,217 @@
+use std::iter::Iterator;
+
+use ::encoding::{write_varint, read_varint};
+use ::error::{StorageError, StorageResult};
+
+// ----------------------------------------------------------------------------
+/// How the NULLs of a chunk are stored
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum NullsEncoding {
+    /// The usual representation of the chunk type (e.g. a bitmap for numeric chunks)
+    Plain,
+    /// The lengths of the runs of present and NULL values, see NullsBitmap::write_runs
+    RunLength
+}
+
+// ----------------------------------------------------------------------------
+/// One bit per value, set if the value is present and clear if it is NULL.
+/// Bits are packed starting from the least significant bit of each byte.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct NullsBitmap {
+    bits: Vec<u8>,
+    num_values: usize
+}
+
+impl NullsBitmap {
+    pub fn new() -> NullsBitmap {
+        NullsBitmap { bits: Vec::new(), num_values: 0 }
+    }
+
+    pub fn with_capacity(num_values: usize) -> NullsBitmap {
+        NullsBitmap { bits: Vec::with_capacity(num_values.div_ceil(8)), num_values: 0 }
+    }
+
+    /// Rebuilds a bitmap from its raw bits, as returned by get_raw_bits
+    pub fn from_raw_bits(bits: &[u8], num_values: usize) -> NullsBitmap {
+        let num_bytes = num_values.div_ceil(8);
+        assert!(bits.len() >= num_bytes, "Not enough bits for {} values", num_values);
+
+        NullsBitmap { bits: bits[..num_bytes].to_vec(), num_values: num_values }
+    }
+
+    /// Makes room for at least num_values more values
+    pub fn reserve(&mut self, num_values: usize) {
+        let needed = (self.num_values + num_values).div_ceil(8);
+        if needed > self.bits.len() {
+            self.bits.reserve(needed - self.bits.len());
+        }
+    }
+
+    pub fn push(&mut self, has_value: bool) {
+        if self.num_values % 8 == 0 {
+            self.bits.push(0);
+        }
+        if has_value {
+            *self.bits.last_mut().unwrap() |= 1 << (self.num_values % 8);
+        }
+        self.num_values += 1;
+    }
+
+    /// Whether the value at index is present (i.e., it's not NULL)
+    pub fn get(&self, index: usize) -> bool {
+        assert!(index < self.num_values, "Index {} out of bounds ({} values)", index, self.num_values);
+        self.bits[index / 8] & (1 << (index % 8)) != 0
+    }
+
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item=bool> + 'a {
+        (0..self.num_values).map(move |i| self.get(i))
+    }
+
+    /// Number of values that are present. Padding bits in the last byte are ignored.
+    pub fn count_non_nulls(&self) -> usize {
+        let full_bytes = self.num_values / 8;
+        let mut count: usize = self.bits[..full_bytes].iter().map(|b| b.count_ones() as usize).fold(0, |a, b| a + b);
+
+        let remaining_bits = self.num_values % 8;
+        if remaining_bits > 0 {
+            let mask = (1u8 << remaining_bits) - 1;
+            count += (self.bits[full_bytes] & mask).count_ones() as usize;
+        }
+
+        count
+    }
+
+    pub fn count_nulls(&self) -> usize { self.num_values - self.count_non_nulls() }
+
+    pub fn len(&self) -> usize { self.num_values }
+    pub fn is_empty(&self) -> bool { self.num_values == 0 }
+    pub fn get_raw_bits(&self) -> &[u8] { &self.bits }
+
+    /// Writes the lengths of the alternating runs of present and NULL values as varints,
+    /// starting with a (possibly empty) run of present values. This is much smaller than
+    /// the bitmap when the NULLs are clustered.
+    pub fn write_runs(&self, out: &mut Vec<u8>) {
+        let mut run_value = true;
+        let mut run_length: u64 = 0;
+        for has_value in self.iter() {
+            if has_value != run_value {
+                write_varint(run_length, out);
+                run_value = has_value;
+                run_length = 0;
+            }
+            run_length += 1;
+        }
+        if run_length > 0 {
+            write_varint(run_length, out);
+        }
+    }
+
+    /// Reads the runs written by write_runs, advancing bytes past them
+    pub fn read_runs(bytes: &mut &[u8], num_values: usize) -> StorageResult<NullsBitmap> {
+        let mut bitmap = NullsBitmap::with_capacity(num_values);
+        let mut run_value = true;
+        while bitmap.len() < num_values {
+            let run_length = try!(read_varint(bytes));
+            if run_length > (num_values - bitmap.len()) as u64 {
+                return Err(StorageError::InvalidFormat("NULL runs exceed the number of values".to_string()));
+            }
+
+            for _ in 0..run_length {
+                bitmap.push(run_value);
+            }
+            run_value = !run_value;
+        }
+
+        Ok(bitmap)
+    }
+
+    pub fn clear(&mut self) {
+        self.bits.clear();
+        self.num_values = 0;
+    }
+}
+
+// ----------------------------------------------------------------------------
+#[cfg(test)]
+mod test {
+    use ::nulls_bitmap::NullsBitmap;
+
+    #[test]
+    fn test_nulls_bitmap() {
+        let mut bitmap = NullsBitmap::new();
+        for i in 0..10 {
+            bitmap.push(i % 3 != 0);
+        }
+
+        assert_eq!(bitmap.len(), 10);
+        assert_eq!(bitmap.get_raw_bits(), &[0b10110110, 0b00000001]);
+    }
+
+    #[test]
+    fn test_nulls_bitmap_indexed_reads() {
+        let mut bitmap = NullsBitmap::new();
+        for i in 0..10 {
+            bitmap.push(i % 3 != 0);
+        }
+
+        for i in 0..10 {
+            assert_eq!(bitmap.get(i), i % 3 != 0);
+        }
+
+        let values: Vec<bool> = bitmap.iter().collect();
+        assert_eq!(values, (0..10).map(|i| i % 3 != 0).collect::<Vec<bool>>());
+
+        let rebuilt = NullsBitmap::from_raw_bits(bitmap.get_raw_bits(), bitmap.len());
+        assert_eq!(rebuilt, bitmap);
+    }
+
+    #[test]
+    fn test_nulls_bitmap_counts() {
+        let mut bitmap = NullsBitmap::new();
+        for i in 0..9 {
+            bitmap.push(i % 3 != 0);
+        }
+
+        assert_eq!(bitmap.count_non_nulls(), 6);
+        assert_eq!(bitmap.count_nulls(), 3);
+
+        // Padding bits set in the last byte must not count as values
+        let bitmap = NullsBitmap::from_raw_bits(&[0xFF, 0xFF], 9);
+        assert_eq!(bitmap.count_non_nulls(), 9);
+        assert_eq!(bitmap.count_nulls(), 0);
+
+        assert_eq!(NullsBitmap::new().count_nulls(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected="out of bounds")]
+    fn test_nulls_bitmap_bounds() {
+        let mut bitmap = NullsBitmap::new();
+        bitmap.push(true);
+        bitmap.get(1);
+    }
+
+    #[test]
+    fn test_nulls_bitmap_runs() {
+        let mut bitmap = NullsBitmap::new();
+        for i in 0..1000 {
+            bitmap.push(i >= 10 && i < 20);
+        }
+
+        // A first empty run of present values, then 10 NULLs, 10 values and 980 NULLs
+        let mut runs = Vec::new();
+        bitmap.write_runs(&mut runs);
+        assert_eq!(runs, vec!(0, 10, 10, 0xD4, 0x07));
+
+        let mut bytes = &runs[..];
+        assert_eq!(NullsBitmap::read_runs(&mut bytes, 1000).unwrap(), bitmap);
+        assert!(bytes.is_empty());
+
+        assert!(NullsBitmap::read_runs(&mut &runs[..], 999).is_err());
+        assert!(NullsBitmap::read_runs(&mut &runs[..], 1001).is_err());
+
+        let mut runs = Vec::new();
+        NullsBitmap::new().write_runs(&mut runs);
+        assert!(runs.is_empty());
+    }
+}
@@ -0,0 +1,66 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::ptr;
+use std::slice;
+use std::usize;
+use std::os::unix::io::AsRawFd;
+
+use libc;
+
+// ----------------------------------------------------------------------------
+/// A file mapped read-only in memory. It is unmapped when dropped.
+///
+/// Nothing guards the mapping against the file being truncated by another process: the
+/// pages past the new end are gone, and reading them raises SIGBUS instead of an error.
+/// The file must not be modified while it is mapped.
+pub struct MappedFile {
+    ptr: *mut libc::c_void,
+    len: usize
+}
+
+// The mapping is read-only, so it can be shared between threads
+unsafe impl Send for MappedFile {}
+unsafe impl Sync for MappedFile {}
+
+impl MappedFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<MappedFile> {
+        let file = try!(File::open(path));
+        let len = try!(file.metadata()).len();
+        // Can only happen on 32-bit hosts
+        if len > usize::MAX as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "File is too large to be mapped in memory"));
+        }
+        let len = len as usize;
+
+        // Empty mappings are not allowed
+        if len == 0 {
+            return Ok(MappedFile { ptr: ptr::null_mut(), len: 0 });
+        }
+
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ, libc::MAP_PRIVATE, file.as_raw_fd(), 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        // The mapping remains valid after closing the file
+        Ok(MappedFile { ptr: ptr, len: len })
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe { libc::munmap(self.ptr, self.len); }
+        }
+    }
+}
@@ -1,8 +1,18 @@
 use ::storage_capnp::stripe_header::Builder as StripeHeaderBuilder;
+use ::storage_capnp::stripe_header::Reader as StripeHeaderReader;
 use ::storage_capnp::column_chunk_header::Builder as ColumnChunkHeaderBuilder;
+use ::storage_capnp::column_chunk_header::Reader as ColumnChunkHeaderReader;
+use ::storage_capnp::storage_footer::Builder as StorageFooterBuilder;
+use ::storage_capnp::storage_footer::Reader as StorageFooterReader;
+use ::storage_capnp::storage_footer::column::Reader as ColumnReader;
+use ::storage_capnp::storage_footer::column::ColumnType;
+use ::storage_capnp::storage_footer::column::DictionaryMode as ProtoDictionaryMode;
 
-use ::encoding::Encoding;
+use ::encoding::{Encoding, DictionaryMode};
+use ::nulls_bitmap::NullsEncoding;
 use ::compression::Compression;
+use ::storage::ColumnDatatype;
+use ::error::{StorageError, StorageResult};
 
 // ----------------------------------------------------------------------------
 pub trait ProtocolBuildable<'a> {
@@ -24,6 +34,85 @@ pub trait ProtocolBuildable<'a> {
     */
 }
 
+// ----------------------------------------------------------------------------
+/// The counterpart of ProtocolBuildable: reconstructs a struct from a Capnp reader.
+pub trait ProtocolReadable<'a>: Sized {
+    type Reader: ::capnp::traits::FromPointerReader<'a>;
+
+    fn read_message(reader: &Self::Reader) -> StorageResult<Self>;
+}
+
+// ----------------------------------------------------------------------------
+// The only conversions between the chunk enums and their Capnp counterparts. The base of
+// the frame of reference encoding is stored in its own field of the chunk header.
+fn encoding_to_proto(encoding: Encoding) -> ::storage_capnp::Encoding {
+    match encoding {
+        Encoding::Raw => ::storage_capnp::Encoding::Raw,
+        Encoding::Delta => ::storage_capnp::Encoding::Delta,
+        Encoding::RLE => ::storage_capnp::Encoding::Rle,
+        Encoding::DeltaOfDelta => ::storage_capnp::Encoding::DeltaOfDelta,
+        Encoding::FrameOfReference(_) => ::storage_capnp::Encoding::FrameOfReference,
+        Encoding::Dictionary => ::storage_capnp::Encoding::Dictionary
+    }
+}
+
+fn encoding_from_proto(encoding: ::storage_capnp::Encoding, reference_base: i64) -> Encoding {
+    match encoding {
+        ::storage_capnp::Encoding::Raw => Encoding::Raw,
+        ::storage_capnp::Encoding::Delta => Encoding::Delta,
+        ::storage_capnp::Encoding::Rle => Encoding::RLE,
+        ::storage_capnp::Encoding::DeltaOfDelta => Encoding::DeltaOfDelta,
+        ::storage_capnp::Encoding::FrameOfReference => Encoding::FrameOfReference(reference_base),
+        ::storage_capnp::Encoding::Dictionary => Encoding::Dictionary
+    }
+}
+
+fn dictionary_mode_to_proto(mode: DictionaryMode) -> ProtoDictionaryMode {
+    match mode {
+        DictionaryMode::None => ProtoDictionaryMode::None,
+        DictionaryMode::Auto => ProtoDictionaryMode::Auto,
+        DictionaryMode::Always => ProtoDictionaryMode::Always
+    }
+}
+
+fn dictionary_mode_from_proto(mode: ProtoDictionaryMode) -> DictionaryMode {
+    match mode {
+        ProtoDictionaryMode::None => DictionaryMode::None,
+        ProtoDictionaryMode::Auto => DictionaryMode::Auto,
+        ProtoDictionaryMode::Always => DictionaryMode::Always
+    }
+}
+
+fn compression_to_proto(compression: Compression) -> ::storage_capnp::Compression {
+    match compression {
+        Compression::None => ::storage_capnp::Compression::None,
+        Compression::Snappy => ::storage_capnp::Compression::Snappy,
+        Compression::Gzip => ::storage_capnp::Compression::Gzip
+    }
+}
+
+fn compression_from_proto(compression: ::storage_capnp::Compression) -> Compression {
+    match compression {
+        ::storage_capnp::Compression::None => Compression::None,
+        ::storage_capnp::Compression::Snappy => Compression::Snappy,
+        ::storage_capnp::Compression::Gzip => Compression::Gzip
+    }
+}
+
+/// The column type and the value size (0 if not fixed length) of a datatype
+pub fn datatype_to_proto(datatype: ColumnDatatype) -> (ColumnType, i32) {
+    match datatype {
+        ColumnDatatype::Byte => (ColumnType::Byte, 0),
+        ColumnDatatype::Int32 => (ColumnType::Int32, 0),
+        ColumnDatatype::Int64 => (ColumnType::Int64, 0),
+        ColumnDatatype::Float => (ColumnType::Float, 0),
+        ColumnDatatype::FixedLength(s) => (ColumnType::FixedLength, s),
+        ColumnDatatype::VariableLength => (ColumnType::VariableLength, 0),
+        ColumnDatatype::Timestamp => (ColumnType::Timestamp, 0),
+        ColumnDatatype::Int16 => (ColumnType::Int16, 0),
+    }
+}
+
 // ----------------------------------------------------------------------------
 /// This is the translation of Capnp's structs to Rust.
 pub struct ColumnChunkHeader {
@@ -31,19 +120,59 @@ pub struct ColumnChunkHeader {
     pub compressed_size: usize,
     pub uncompressed_size: usize,
     pub encoding: Encoding,
+    pub nulls_encoding: NullsEncoding,
     pub compression: Compression,
+    pub min_max: Option<(i64, i64)>,
+    /// Like min_max, for float chunks. NaN values are left out.
+    pub float_min_max: Option<(f32, f32)>,
+    pub null_count: usize,
+    /// Approximate, None in chunks written before it was tracked
+    pub distinct_count: Option<usize>
 }
 
 pub struct StripeHeader {
     pub num_rows: usize,
     pub column_chunks: Vec<ColumnChunkHeader>,
-    pub stripe_size: usize
+    pub stripe_size: usize,
+    pub crc32: u32
 }
 
 #[derive(Debug, Clone)]
 pub struct Stripe {
     pub absolute_offset: usize,
-    pub num_rows: usize
+    pub num_rows: usize,
+    /// Size of the header and the chunks
+    pub size: usize
+}
+
+pub struct ColumnSchema {
+    pub name: String,
+    pub datatype: ColumnDatatype,
+    pub nullable: bool,
+    pub fixed_string: bool,
+    /// For appended columns, the bytes of the default value (None for NULL)
+    pub appended: Option<Option<Vec<u8>>>,
+    /// Key/value pairs, sorted by key
+    pub metadata: Vec<(String, String)>,
+    pub dictionary_mode: DictionaryMode,
+    /// The values of the dictionary of the column, in code order
+    pub dictionary: Vec<Vec<u8>>,
+    /// Fill byte of shorter fixed length values
+    pub padding: Option<u8>
+}
+
+pub struct StorageFooter {
+    pub num_rows: usize,
+    pub columns: Vec<ColumnSchema>,
+    pub stripes: Vec<Stripe>,
+    pub rows_per_stripe: Option<usize>,
+    /// None in storages written before it was recorded
+    pub schema_hash: Option<u64>,
+    /// The settings of the inserters, see Storage::open_for_append
+    pub compression: Compression,
+    pub compression_level: u32,
+    pub auto_encoding: bool,
+    pub max_value_bytes: Option<usize>
 }
 
 impl<'a> ProtocolBuildable<'a> for StripeHeader {
@@ -52,6 +181,7 @@ impl<'a> ProtocolBuildable<'a> for StripeHeader {
     fn build_message(&self, builder: &mut Self::Builder) {
         builder.set_num_rows(self.num_rows as u32);
         builder.set_stripe_size(self.stripe_size as u64);
+        builder.set_crc32(self.crc32);
         let mut column_chunks_builder = builder.borrow().init_column_chunks(self.column_chunks.len() as u32);
         for (c, column_chunk) in self.column_chunks.iter().enumerate() {
             let mut column_chunk_builder = column_chunks_builder.borrow().get(c as u32);
@@ -67,14 +197,241 @@ impl<'a> ProtocolBuildable<'a> for ColumnChunkHeader {
         builder.set_relative_offset(self.relative_offset as u64);
         builder.set_compressed_size(self.compressed_size as u32);
         builder.set_uncompressed_size(self.uncompressed_size as u32);
-        builder.set_encoding(match self.encoding {
-            Encoding::Raw => ::storage_capnp::Encoding::Raw,
-            Encoding::Delta => ::storage_capnp::Encoding::Delta,
-            Encoding::RLE => ::storage_capnp::Encoding::Rle
-        });
-        builder.set_compression(match self.compression {
-            Compression::None => ::storage_capnp::Compression::None,
-            Compression::Snappy => ::storage_capnp::Compression::Snappy,
-        });
+        builder.set_encoding(encoding_to_proto(self.encoding));
+        if let Encoding::FrameOfReference(base) = self.encoding {
+            builder.set_reference_base(base);
+        }
+        builder.set_run_length_nulls(self.nulls_encoding == NullsEncoding::RunLength);
+        builder.set_compression(compression_to_proto(self.compression));
+        builder.set_null_count(self.null_count as u32);
+        if let Some(distinct_count) = self.distinct_count {
+            builder.set_has_distinct_count(true);
+            builder.set_distinct_count(distinct_count as u64);
+        }
+        if let Some((min, max)) = self.min_max {
+            builder.set_has_min_max(true);
+            builder.set_min_value(min);
+            builder.set_max_value(max);
+        }
+        if let Some((min, max)) = self.float_min_max {
+            builder.set_has_float_min_max(true);
+            builder.set_min_float(min);
+            builder.set_max_float(max);
+        }
+    }
+}
+
+impl<'a> ProtocolReadable<'a> for StripeHeader {
+    type Reader = StripeHeaderReader<'a>;
+
+    fn read_message(reader: &Self::Reader) -> StorageResult<Self> {
+        let column_chunks_reader = try!(reader.get_column_chunks());
+        let mut column_chunks = Vec::with_capacity(column_chunks_reader.len() as usize);
+        for column_chunk_reader in column_chunks_reader.iter() {
+            column_chunks.push(try!(ColumnChunkHeader::read_message(&column_chunk_reader)));
+        }
+
+        Ok(StripeHeader {
+            num_rows: reader.get_num_rows() as usize,
+            column_chunks: column_chunks,
+            stripe_size: reader.get_stripe_size() as usize,
+            crc32: reader.get_crc32()
+        })
+    }
+}
+
+impl<'a> ProtocolReadable<'a> for ColumnChunkHeader {
+    type Reader = ColumnChunkHeaderReader<'a>;
+
+    fn read_message(reader: &Self::Reader) -> StorageResult<Self> {
+        let encoding = encoding_from_proto(try!(reader.get_encoding()), reader.get_reference_base());
+        let compression = compression_from_proto(try!(reader.get_compression()));
+
+        Ok(ColumnChunkHeader {
+            relative_offset: reader.get_relative_offset() as usize,
+            compressed_size: reader.get_compressed_size() as usize,
+            uncompressed_size: reader.get_uncompressed_size() as usize,
+            encoding: encoding,
+            nulls_encoding: if reader.get_run_length_nulls() { NullsEncoding::RunLength } else { NullsEncoding::Plain },
+            compression: compression,
+            min_max: if reader.get_has_min_max() { Some((reader.get_min_value(), reader.get_max_value())) } else { None },
+            float_min_max: if reader.get_has_float_min_max() { Some((reader.get_min_float(), reader.get_max_float())) } else { None },
+            null_count: reader.get_null_count() as usize,
+            distinct_count: if reader.get_has_distinct_count() { Some(reader.get_distinct_count() as usize) } else { None }
+        })
+    }
+}
+
+impl<'a> ProtocolBuildable<'a> for StorageFooter {
+    type Builder = StorageFooterBuilder<'a>;
+
+    fn build_message(&self, builder: &mut Self::Builder) {
+        builder.set_num_rows(self.num_rows as i64);
+        builder.set_rows_per_stripe(self.rows_per_stripe.unwrap_or(0) as u64);
+        builder.set_schema_hash(self.schema_hash.unwrap_or(0));
+        builder.set_compression(compression_to_proto(self.compression));
+        builder.set_compression_level(self.compression_level);
+        builder.set_auto_encoding(self.auto_encoding);
+        builder.set_max_value_bytes(self.max_value_bytes.unwrap_or(0) as u64);
+
+        {
+            let mut columns_builder = builder.borrow().init_columns(self.columns.len() as u32);
+            for (c, column) in self.columns.iter().enumerate() {
+                let mut column_builder = columns_builder.borrow().get(c as u32);
+                column_builder.set_name(&column.name);
+                let (column_type, value_size) = datatype_to_proto(column.datatype);
+                column_builder.set_type(column_type);
+                column_builder.set_value_size(value_size);
+                column_builder.set_not_null(!column.nullable);
+                column_builder.set_fixed_string(column.fixed_string);
+                if let Some(ref default) = column.appended {
+                    column_builder.set_appended(true);
+                    if let Some(ref bytes) = *default {
+                        column_builder.set_has_default(true);
+                        column_builder.set_default_value(bytes);
+                    }
+                }
+
+                let mut metadata_builder = column_builder.borrow().init_metadata(column.metadata.len() as u32);
+                for (m, &(ref key, ref value)) in column.metadata.iter().enumerate() {
+                    let mut entry_builder = metadata_builder.borrow().get(m as u32);
+                    entry_builder.set_key(key);
+                    entry_builder.set_value(value.as_bytes());
+                }
+
+                column_builder.set_dictionary_mode(dictionary_mode_to_proto(column.dictionary_mode));
+                let mut dictionary_builder = column_builder.borrow().init_dictionary(column.dictionary.len() as u32);
+                for (v, value) in column.dictionary.iter().enumerate() {
+                    dictionary_builder.set(v as u32, value);
+                }
+
+                if let Some(padding) = column.padding {
+                    column_builder.set_has_padding(true);
+                    column_builder.set_padding(padding);
+                }
+            }
+        }
+
+        let mut stripes_builder = builder.borrow().init_stripes(self.stripes.len() as u32);
+        for (s, stripe) in self.stripes.iter().enumerate() {
+            let mut stripe_builder = stripes_builder.borrow().get(s as u32);
+            stripe_builder.set_absolute_offset(stripe.absolute_offset as u64);
+            stripe_builder.set_num_rows(stripe.num_rows as u64);
+            stripe_builder.set_size(stripe.size as u64);
+        }
+    }
+}
+
+impl<'a> ProtocolReadable<'a> for StorageFooter {
+    type Reader = StorageFooterReader<'a>;
+
+    fn read_message(reader: &Self::Reader) -> StorageResult<Self> {
+        let mut columns = Vec::new();
+        for column_reader in try!(reader.get_columns()).iter() {
+            let datatype = match try!(column_reader.get_type()) {
+                ColumnType::Byte => ColumnDatatype::Byte,
+                ColumnType::Int32 => ColumnDatatype::Int32,
+                ColumnType::Int64 => ColumnDatatype::Int64,
+                ColumnType::Float => ColumnDatatype::Float,
+                ColumnType::FixedLength => ColumnDatatype::FixedLength(column_reader.get_value_size()),
+                ColumnType::VariableLength => ColumnDatatype::VariableLength,
+                ColumnType::Timestamp => ColumnDatatype::Timestamp,
+                ColumnType::Int16 => ColumnDatatype::Int16,
+            };
+            columns.push(ColumnSchema {
+                name: String::from(try!(column_reader.get_name())),
+                datatype: datatype,
+                nullable: !column_reader.get_not_null(),
+                fixed_string: column_reader.get_fixed_string(),
+                appended: match (column_reader.get_appended(), column_reader.get_has_default()) {
+                    (false, _) => None,
+                    (true, false) => Some(None),
+                    (true, true) => Some(Some(try!(column_reader.get_default_value()).to_vec()))
+                },
+                metadata: try!(read_metadata(&column_reader)),
+                dictionary_mode: dictionary_mode_from_proto(try!(column_reader.get_dictionary_mode())),
+                dictionary: try!(read_dictionary(&column_reader)),
+                padding: if column_reader.get_has_padding() { Some(column_reader.get_padding()) } else { None }
+            });
+        }
+
+        let stripes = try!(reader.get_stripes()).iter()
+            .map(|stripe_reader| Stripe {
+                absolute_offset: stripe_reader.get_absolute_offset() as usize,
+                num_rows: stripe_reader.get_num_rows() as usize,
+                size: stripe_reader.get_size() as usize
+            })
+            .collect();
+
+        Ok(StorageFooter {
+            num_rows: reader.get_num_rows() as usize,
+            columns: columns,
+            stripes: stripes,
+            rows_per_stripe: match reader.get_rows_per_stripe() { 0 => None, n => Some(n as usize) },
+            schema_hash: match reader.get_schema_hash() { 0 => None, h => Some(h) },
+            compression: compression_from_proto(try!(reader.get_compression())),
+            compression_level: reader.get_compression_level(),
+            auto_encoding: reader.get_auto_encoding(),
+            max_value_bytes: match reader.get_max_value_bytes() { 0 => None, n => Some(n as usize) }
+        })
+    }
+}
+
+fn read_metadata(column_reader: &ColumnReader) -> StorageResult<Vec<(String, String)>> {
+    let mut metadata = Vec::new();
+    for entry_reader in try!(column_reader.get_metadata()).iter() {
+        let value = try!(entry_reader.get_value()).to_vec();
+        let value = try!(String::from_utf8(value)
+            .map_err(|_| StorageError::InvalidFormat("Column metadata is not valid UTF-8".to_string())));
+        metadata.push((String::from(try!(entry_reader.get_key())), value));
+    }
+    Ok(metadata)
+}
+
+fn read_dictionary(column_reader: &ColumnReader) -> StorageResult<Vec<Vec<u8>>> {
+    let dictionary_reader = try!(column_reader.get_dictionary());
+    let mut dictionary = Vec::with_capacity(dictionary_reader.len() as usize);
+    for v in 0..dictionary_reader.len() {
+        dictionary.push(try!(dictionary_reader.get(v)).to_vec());
+    }
+    Ok(dictionary)
+}
+
+// ----------------------------------------------------------------------------
+#[cfg(test)]
+mod test {
+    use capnp::traits::{FromU16, ToU16};
+
+    use ::proto_structs::*;
+
+    #[test]
+    fn every_encoding_has_one_proto_value() {
+        let encodings = [Encoding::Raw, Encoding::Delta, Encoding::RLE, Encoding::DeltaOfDelta, Encoding::FrameOfReference(-7), Encoding::Dictionary];
+        for &encoding in encodings.iter() {
+            assert_eq!(encoding_from_proto(encoding_to_proto(encoding), -7), encoding);
+        }
+
+        // ...and every value in the schema is one of them
+        let mut n = 0;
+        while let Ok(proto) = ::storage_capnp::Encoding::from_u16(n) {
+            assert!(encoding_to_proto(encoding_from_proto(proto, 0)) == proto);
+            n += 1;
+        }
+        assert_eq!(n as usize, encodings.len());
+    }
+
+    #[test]
+    fn every_compression_has_one_proto_value() {
+        let compressions = [Compression::None, Compression::Snappy, Compression::Gzip];
+        for &compression in compressions.iter() {
+            assert_eq!(compression_from_proto(compression_to_proto(compression)), compression);
+        }
+
+        let mut n = 0;
+        while let Ok(proto) = ::storage_capnp::Compression::from_u16(n) {
+            assert_eq!(compression_to_proto(compression_from_proto(proto)).to_u16(), n);
+            n += 1;
+        }
+        assert_eq!(n as usize, compressions.len());
     }
 }
@@ -0,0 +1,124 @@
+use ::storage::{ColumnDatatype, ColumnValue, NumericValue};
+use ::error::{StorageError, StorageResult};
+
+// ----------------------------------------------------------------------------
+/// A Rust type that can be stored in a column
+pub trait ColumnType: Sized {
+    fn datatype() -> ColumnDatatype;
+    /// Whether the column accepts NULLs, only for Option
+    fn nullable() -> bool { false }
+    fn to_value(&self) -> ColumnValue;
+    fn from_value(value: &ColumnValue) -> StorageResult<Self>;
+}
+
+impl<N> ColumnType for N
+    where N: NumericValue + Copy
+{
+    fn datatype() -> ColumnDatatype { N::datatype() }
+    fn to_value(&self) -> ColumnValue { self.into_column_value() }
+    fn from_value(value: &ColumnValue) -> StorageResult<Self> {
+        N::extract_value_exact(value).ok_or(StorageError::TypeError)
+    }
+}
+
+/// UTF-8 variable length values
+impl ColumnType for String {
+    fn datatype() -> ColumnDatatype { ColumnDatatype::VariableLength }
+    fn to_value(&self) -> ColumnValue { ColumnValue::VariableLength(self.as_bytes().to_vec()) }
+    fn from_value(value: &ColumnValue) -> StorageResult<Self> {
+        match *value {
+            ColumnValue::VariableLength(ref v) => String::from_utf8(v.clone())
+                .map_err(|_| StorageError::InvalidFormat("Value is not valid UTF-8".to_string())),
+            _ => Err(StorageError::TypeError)
+        }
+    }
+}
+
+impl ColumnType for Vec<u8> {
+    fn datatype() -> ColumnDatatype { ColumnDatatype::VariableLength }
+    fn to_value(&self) -> ColumnValue { ColumnValue::VariableLength(self.clone()) }
+    fn from_value(value: &ColumnValue) -> StorageResult<Self> {
+        match *value {
+            ColumnValue::VariableLength(ref v) => Ok(v.clone()),
+            _ => Err(StorageError::TypeError)
+        }
+    }
+}
+
+/// NULL is None
+impl<T> ColumnType for Option<T>
+    where T: ColumnType
+{
+    fn datatype() -> ColumnDatatype { T::datatype() }
+    fn nullable() -> bool { true }
+    fn to_value(&self) -> ColumnValue {
+        match *self {
+            Some(ref v) => v.to_value(),
+            None => ColumnValue::Null
+        }
+    }
+    fn from_value(value: &ColumnValue) -> StorageResult<Self> {
+        match *value {
+            ColumnValue::Null => Ok(None),
+            ref v => T::from_value(v).map(Some)
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+/// A struct whose fields are the columns of a row, in order. Usually implemented with
+/// #[derive(Row)] from the table-derive crate (enabled by the derive feature), e.g.:
+///
+/// ```ignore
+/// #[derive(Row)]
+/// struct Event { id: i32, name: Option<String> }
+/// ```
+pub trait Row: Sized {
+    /// The name, type and nullability of each column
+    fn schema() -> Vec<(&'static str, ColumnDatatype, bool)>;
+    fn to_row(&self) -> Vec<ColumnValue>;
+    /// Fails with a TypeMismatch error if a value doesn't have the type of its field
+    fn from_row(row: &[ColumnValue]) -> StorageResult<Self>;
+}
+
+/// Converts the value of the column with the given index, for the implementations of Row
+pub fn field_from_value<T: ColumnType>(row: &[ColumnValue], column: usize) -> StorageResult<T> {
+    match row.get(column) {
+        Some(value) => T::from_value(value).map_err(|e| match e {
+            StorageError::TypeError => StorageError::TypeMismatch { column: column, expected: T::datatype() },
+            e => e
+        }),
+        None => Err(StorageError::InvalidNumberOfColumns(row.len(), column + 1))
+    }
+}
+
+// ----------------------------------------------------------------------------
+#[cfg(test)]
+mod test {
+    use ::row::*;
+    use ::storage::Timestamp;
+
+    #[test]
+    fn values_are_converted_to_their_columns() {
+        assert_eq!(<i32 as ColumnType>::datatype(), ColumnDatatype::Int32);
+        assert!(!i32::nullable());
+        assert!(<Option<String>>::nullable());
+
+        assert_eq!(Timestamp(5).to_value(), ColumnValue::Timestamp(5));
+        assert_eq!("abc".to_string().to_value(), ColumnValue::VariableLength(b"abc".to_vec()));
+        assert_eq!(<Option<i64>>::None.to_value(), ColumnValue::Null);
+
+        assert_eq!(i16::from_value(&ColumnValue::Int16(-3)).unwrap(), -3);
+        assert_eq!(<Option<f32>>::from_value(&ColumnValue::Null).unwrap(), None);
+        assert!(i32::from_value(&ColumnValue::Null).is_err());
+        assert!(String::from_value(&ColumnValue::VariableLength(vec!(0xFF))).is_err());
+
+        let row = vec!(ColumnValue::Int32(1), ColumnValue::Int64(2));
+        assert_eq!(field_from_value::<i32>(&row, 0).unwrap(), 1);
+        match field_from_value::<i32>(&row, 1) {
+            Err(StorageError::TypeMismatch { column: 1, expected: ColumnDatatype::Int32 }) => {},
+            r => panic!("Expected TypeMismatch, got {:?}", r)
+        }
+        assert!(field_from_value::<i32>(&row, 2).is_err());
+    }
+}
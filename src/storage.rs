@@ -1,34 +1,55 @@
-use std::path::{Path, PathBuf};
-use std::io::{Read, Write, Seek, SeekFrom, Cursor};
+use std::path::Path;
+use std::fs::OpenOptions;
+use std::io::{Read, Write, Seek, SeekFrom, BufWriter};
 use std::fmt;
 use std::collections::hash_map::HashMap;
-use std::fs::File;
 use std::iter::Iterator;
 use std::str;
-use std::{i8, i32, i64, f32};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::cmp;
+use std::mem;
+use std::{i8, i16, i32, i64, f32};
+
+use capnp::message::{Builder as ProtoBuilder, ReaderOptions};
+use capnp::traits::ToU16;
 
 use ::proto_structs;
-use ::storage_inserter::InsertionManager;
+use ::proto_structs::{ProtocolBuildable, ProtocolReadable};
+use ::storage_inserter::{InsertionManager, get_slice_bytes};
+use ::storage_reader::{StorageStripeIterator, get_slice_values};
+use ::storage_backend::StorageBackend;
 use ::error::{StorageError, StorageResult};
+use ::compression::Compressor;
+use ::encoding::{Encoder, DictionaryMode, GlobalDictionary};
+use ::checksum::{Crc32, Fnv1a64};
+
+/// Version of the storage format, written in the header. Bump it on incompatible changes.
+/// All the integers and floats in a storage are little-endian, whatever the host.
+pub const FORMAT_VERSION: u16 = 2;
 
 // ----------------------------------------------------------------------------
 /// Basic types suppored by the storage backend
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum ColumnDatatype {
-    Byte, Int32, Int64,
+    Byte, Int16, Int32, Int64,
     Float,
-    FixedLength(i32), VariableLength
+    FixedLength(i32), VariableLength,
+    /// Microseconds since the epoch, stored with delta-of-delta encoding
+    Timestamp
 }
 
 impl fmt::Display for ColumnDatatype {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             ColumnDatatype::Byte => write!(f, "Byte"),
+            ColumnDatatype::Int16 => write!(f, "Int16"),
             ColumnDatatype::Int32 => write!(f, "Int32"),
             ColumnDatatype::Int64 => write!(f, "Int64"),
             ColumnDatatype::Float => write!(f, "Float"),
             ColumnDatatype::FixedLength(s) => write!(f, "FixedLength({})", s),
             ColumnDatatype::VariableLength => write!(f, "VariableLength"),
+            ColumnDatatype::Timestamp => write!(f, "Timestamp"),
         }
     }
 }
@@ -41,37 +62,134 @@ pub struct DatatypeInfo {
 }
 
 impl DatatypeInfo {
-    fn new(datatype: &ColumnDatatype) -> DatatypeInfo {
+    pub(crate) fn new(datatype: &ColumnDatatype) -> DatatypeInfo {
         match *datatype {
             ColumnDatatype::Byte => DatatypeInfo { is_numeric: true, is_fixed_size: true, value_size: Some(1) },
+            ColumnDatatype::Int16 => DatatypeInfo { is_numeric: true, is_fixed_size: true, value_size: Some(2) },
             ColumnDatatype::Int32 => DatatypeInfo { is_numeric: true, is_fixed_size: true, value_size: Some(4) },
             ColumnDatatype::Int64 => DatatypeInfo { is_numeric: true, is_fixed_size: true, value_size: Some(8) },
             ColumnDatatype::Float => DatatypeInfo { is_numeric: true, is_fixed_size: true, value_size: Some(4) },
             ColumnDatatype::FixedLength(s) => DatatypeInfo { is_numeric: false, is_fixed_size: true, value_size: Some(s as usize) },
             ColumnDatatype::VariableLength => DatatypeInfo { is_numeric: false, is_fixed_size: false, value_size: None },
+            ColumnDatatype::Timestamp => DatatypeInfo { is_numeric: true, is_fixed_size: true, value_size: Some(8) },
         }
     }
 }
 
+// ----------------------------------------------------------------------------
+/// Statistics of a whole column, see Storage::column_stats. The minimum and maximum are
+/// only tracked for integer types, and they are None if all the values are NULL.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnStats {
+    pub min: Option<i64>,
+    pub max: Option<i64>,
+    pub null_count: usize,
+    pub num_rows: usize
+}
+
+/// Statistics of a stripe, see Storage::row_group_stats
+#[derive(Debug, Clone, PartialEq)]
+pub struct StripeStats {
+    /// Absolute offset of the stripe in the storage
+    pub offset: usize,
+    pub num_rows: usize,
+    /// One per column, in the order of the columns
+    pub columns: Vec<ColumnStats>
+}
+
+// ----------------------------------------------------------------------------
+/// A predicate that every value inserted in a column must satisfy
+pub type ColumnCheck = Fn(&ColumnValue) -> bool + Send + Sync;
+
 // ----------------------------------------------------------------------------
 pub struct Column {
     pub name: String,
     pub datatype: ColumnDatatype,
     pub datatype_info: DatatypeInfo,
-    num_column: usize
+    nullable: bool,
+    /// Fill byte for fixed length values shorter than the column, None to reject them
+    padding: Option<u8>,
+    /// Whether the fixed length values are strings, see StorageBuilder::column_fixed_string
+    fixed_string: bool,
+    /// Only for columns added by Storage::append_column: the value of the rows in the
+    /// stripes written before (Null if there is no default)
+    default_value: Option<ColumnValue>,
+    /// Free-form key/value pairs, e.g. for data catalogs. They don't affect the values.
+    metadata: HashMap<String, String>,
+    dictionary_mode: DictionaryMode,
+    /// Shared with the inserters, which add the values of new chunks. Some unless the
+    /// mode is None.
+    dictionary: Option<Arc<Mutex<GlobalDictionary>>>,
+    num_column: usize,
+    checks: Vec<Arc<ColumnCheck>>
 }
 
 impl Column {
+    fn new(name: &str, datatype: ColumnDatatype, nullable: bool, num_column: usize) -> Column {
+        Column {
+            name: String::from(name),
+            datatype: datatype,
+            datatype_info: DatatypeInfo::new(&datatype),
+            nullable: nullable,
+            padding: None,
+            fixed_string: false,
+            default_value: None,
+            metadata: HashMap::new(),
+            dictionary_mode: DictionaryMode::None,
+            dictionary: None,
+            num_column: num_column,
+            checks: Vec::new()
+        }
+    }
+
     pub fn build(name: &str, datatype: ColumnDatatype) -> ColumnBuilder {
         ColumnBuilder {
             name: String::from(name),
             datatype: datatype,
+            nullable: true,
+            padding: None,
+            fixed_string: false,
+            default_value: None,
+            metadata: HashMap::new(),
+            dictionary_mode: DictionaryMode::None,
+            dictionary: Vec::new()
         }
     }
 
     pub fn datatype(&self) -> &ColumnDatatype { &self.datatype }
     pub fn name(&self) -> &str { &self.name }
+    pub fn is_nullable(&self) -> bool { self.nullable }
+    pub fn padding(&self) -> Option<u8> { self.padding }
+    pub fn is_fixed_string(&self) -> bool { self.fixed_string }
+    /// The value held by the stripes written before the column was appended, if it was
+    pub fn default_value(&self) -> Option<&ColumnValue> { self.default_value.as_ref() }
+    pub fn metadata(&self) -> &HashMap<String, String> { &self.metadata }
+    /// The free-text comment of the column, stored as the "comment" metadata key
+    pub fn comment(&self) -> Option<&str> { self.metadata.get("comment").map(|c| c.as_str()) }
+    pub fn dictionary_mode(&self) -> DictionaryMode { self.dictionary_mode }
+
+    /// The dictionary shared by the chunks of the column, see StorageBuilder::column_with_dictionary
+    pub fn dictionary(&self) -> Option<MutexGuard<'_, GlobalDictionary>> {
+        self.dictionary.as_ref().map(|d| d.lock().unwrap())
+    }
+
+    pub(crate) fn shared_dictionary(&self) -> Option<Arc<Mutex<GlobalDictionary>>> {
+        self.dictionary.clone()
+    }
+
+    fn set_dictionary(&mut self, mode: DictionaryMode, values: Vec<Vec<u8>>) {
+        self.dictionary_mode = mode;
+        self.dictionary = match mode {
+            DictionaryMode::None => None,
+            _ => Some(Arc::new(Mutex::new(GlobalDictionary::from_values(values))))
+        };
+    }
     pub fn num_column_in_storage(&self) -> usize { self.num_column }
+
+    /// Whether the value passes all the checks of this column
+    pub fn check_value(&self, value: &ColumnValue) -> bool {
+        self.checks.iter().all(|check| check(value))
+    }
 }
 
 // ----------------------------------------------------------------------------
@@ -79,56 +197,88 @@ impl Column {
 pub struct ColumnBuilder {
     name: String,
     datatype: ColumnDatatype,
+    nullable: bool,
+    padding: Option<u8>,
+    fixed_string: bool,
+    default_value: Option<ColumnValue>,
+    metadata: HashMap<String, String>,
+    dictionary_mode: DictionaryMode,
+    /// The values the dictionary starts with, when copying a storage
+    dictionary: Vec<Vec<u8>>
 }
 
-// ----------------------------------------------------------------------------
-enum __StorageBackend {
-    Memory(Vec<u8>),
-    File(PathBuf)
-}
-
-// ----------------------------------------------------------------------------
-pub trait StorageBackend : Read + Write + Seek {}
-impl StorageBackend for File {}
-impl StorageBackend for Cursor<Vec<u8>> {}
-
 // ----------------------------------------------------------------------------
 pub struct Storage
 {
     pub num_rows: usize,
     pub columns: Vec<Column>,
-    pub backend: Box<StorageBackend>,
-    pub stripes: Vec<proto_structs::Stripe>
+    pub backend: StorageBackend,
+    stripes: Vec<proto_structs::Stripe>,
+    max_value_bytes: Option<usize>,
+    rows_per_stripe: Option<usize>,
+    compressor: Compressor,
+    encoder: Encoder,
+    /// Whether the backend ends with the footer of the current index, see close
+    footer_written: bool
 }
 
 impl Storage
 {
-    fn init(backend: Box<StorageBackend>, builder: &StorageBuilder) -> StorageResult<Storage> {
-        // Make sure the column names are not duplicated
+    fn init(backend: StorageBackend, builder: &StorageBuilder) -> StorageResult<Storage> {
+        // Make sure the column names are not empty or duplicated. Names are compared as they
+        // are, like column_by_name does, so "id" and "ID" are different columns.
         let mut name_count: HashMap<&str, i32> = HashMap::new();
         for ref column in builder.columns.iter() {
+            try!(Self::check_column_name(&column.name));
             let cnt = name_count.entry(&column.name).or_insert(0);
             *cnt += 1;
             if *cnt > 1 {
                 return Err(StorageError::InvalidFormat(format!("Column '{}' is specified more than once", column.name)));
             }
+
+            // Fixed length values must take some space
+            if let ColumnDatatype::FixedLength(size) = column.datatype {
+                if size <= 0 {
+                    return Err(StorageError::InvalidFormat(format!("Column '{}' has an invalid value size ({})", column.name, size)));
+                }
+            }
+        }
+
+        if builder.rows_per_stripe == Some(0) {
+            return Err(StorageError::InvalidFormat("Stripes must have at least one row".to_string()));
         }
 
         // Create the columns
-        let columns: Vec<Column> = builder.columns.iter().enumerate().map(|(i,b)| {
-            Column {
-                name: b.name.clone(),
-                datatype: b.datatype,
-                datatype_info: DatatypeInfo::new(&b.datatype),
-                num_column: i
+        let mut columns: Vec<Column> = builder.columns.iter().enumerate()
+            .map(|(i,b)| {
+                let mut column = Column::new(&b.name, b.datatype, b.nullable, i);
+                column.padding = b.padding;
+                column.fixed_string = b.fixed_string;
+                column.default_value = b.default_value.clone();
+                column.metadata = b.metadata.clone();
+                column.set_dictionary(b.dictionary_mode, b.dictionary.clone());
+                column
+            })
+            .collect();
+
+        // Attach the checks to their columns
+        for &(ref name, ref check) in builder.checks.iter() {
+            match columns.iter_mut().find(|c| c.name == *name) {
+                Some(column) => column.checks.push(check.clone()),
+                None => return Err(StorageError::InvalidFormat(format!("Check specified for unknown column '{}'", name)))
             }
-        }).collect();
+        }
 
         let mut storage = Storage {
             num_rows: 0,
             columns: columns,
             backend: backend,
-            stripes: Vec::new()
+            stripes: Vec::new(),
+            max_value_bytes: builder.max_value_bytes,
+            rows_per_stripe: builder.rows_per_stripe,
+            compressor: builder.compressor,
+            encoder: builder.encoder,
+            footer_written: false
         };
 
         try!(storage.write_header());
@@ -136,49 +286,647 @@ impl Storage
         Ok(storage)
     }
 
+    /// Columns must have a name that can be looked up and used in the headers of exports
+    fn check_column_name(name: &str) -> StorageResult<()> {
+        if name.trim().is_empty() {
+            return Err(StorageError::InvalidFormat(format!("Column name '{}' is empty", name)));
+        }
+        Ok(())
+    }
+
+    /// The header is the signature followed by the format version
     pub fn write_header(&mut self) -> StorageResult<()> {
-        try!(self.backend.write(Self::signature()));
+        let mut writer = try!(self.backend.writer());
+        try!(writer.write_all(Self::signature()));
+        try!(writer.write_all(&FORMAT_VERSION.to_le_bytes()));
         Ok(())
     }
 
+    /// Opens an existing storage file
+    pub fn open<P: AsRef<Path>>(path: P) -> StorageResult<Storage> {
+        Self::load(StorageBackend::in_path(path))
+    }
+
+    /// Opens an existing storage file to insert more rows into it. The old footer is removed,
+    /// and a new one is written by InsertionManager::finish_inserting, so the file is not
+    /// valid until then.
+    ///
+    /// The new rows are written with the settings the storage was created with (compressor,
+    /// encoder, rows per stripe, value size limit and padding), which are kept in the
+    /// footer. Two things are not: checks are closures, so they have to be attached again
+    /// with add_check, and a compression this build cannot write (e.g. gzip without the
+    /// gzip feature) is replaced by no compression in the new stripes.
+    pub fn open_for_append<P: AsRef<Path>>(path: P) -> StorageResult<Storage> {
+        let mut storage = try!(Self::open(&path));
+        let footer_offset = try!(Self::read_footer_offset(&mut try!(storage.backend.reader())));
+
+        // New stripes are appended at the end of the file, so drop the footer
+        let file = try!(OpenOptions::new().write(true).open(&path));
+        try!(file.set_len(footer_offset));
+        storage.footer_written = false;
+
+        Ok(storage)
+    }
+
+    /// The footer ends with its offset and the signature
+    fn read_footer_offset<R: Read + Seek + ?Sized>(reader: &mut R) -> StorageResult<u64> {
+        let signature = Self::signature();
+        let mut buf = vec![0u8; signature.len()];
+
+        let mut offset_buf = [0u8; 8];
+        try!(reader.seek(SeekFrom::End(-((signature.len() + offset_buf.len()) as i64))));
+        try!(reader.read_exact(&mut offset_buf));
+        try!(reader.read_exact(&mut buf));
+        if &buf[..] != signature {
+            return Err(StorageError::InvalidFormat("Invalid footer signature".to_string()));
+        }
+
+        Ok(u64::from_le_bytes(offset_buf))
+    }
+
+    /// Loads the storage held by the backend, reading its schema and stripe index from the footer
+    pub fn load(backend: StorageBackend) -> StorageResult<Storage> {
+        let footer = {
+            let mut reader = try!(backend.reader());
+            let signature = Self::signature();
+            let mut buf = vec![0u8; signature.len()];
+
+            try!(reader.read_exact(&mut buf));
+            if &buf[..] != signature {
+                return Err(StorageError::InvalidFormat("Invalid header signature".to_string()));
+            }
+
+            let mut version_buf = [0u8; 2];
+            try!(reader.read_exact(&mut version_buf));
+            let version = u16::from_le_bytes(version_buf);
+            if version != FORMAT_VERSION {
+                return Err(StorageError::InvalidFormat(format!("Unsupported format version {}", version)));
+            }
+
+            // The footer is followed by its checksum, which is verified before parsing it
+            let footer_offset = try!(Self::read_footer_offset(&mut reader));
+            let checksum_offset = try!(reader.seek(SeekFrom::End(-((signature.len() + mem::size_of::<u64>() + mem::size_of::<u32>()) as i64))));
+            if footer_offset > checksum_offset {
+                return Err(StorageError::InvalidFormat(format!("Invalid footer offset {}, the footer may be corrupted", footer_offset)));
+            }
+
+            let mut footer_bytes = vec![0u8; (checksum_offset - footer_offset) as usize];
+            let mut checksum_buf = [0u8; 4];
+            try!(reader.seek(SeekFrom::Start(footer_offset)));
+            try!(reader.read_exact(&mut footer_bytes));
+            try!(reader.read_exact(&mut checksum_buf));
+
+            let mut crc = Crc32::new();
+            crc.update(&footer_bytes);
+            if crc.finish() != u32::from_le_bytes(checksum_buf) {
+                return Err(StorageError::InvalidFormat("Footer checksum mismatch, the footer is corrupted".to_string()));
+            }
+
+            let message = try!(::capnp::serialize::read_message(&mut &footer_bytes[..], ReaderOptions::new()));
+            let footer_reader = try!(message.get_root::<<proto_structs::StorageFooter as ProtocolReadable>::Reader>());
+            try!(proto_structs::StorageFooter::read_message(&footer_reader))
+        };
+
+        let mut columns = Vec::with_capacity(footer.columns.len());
+        for (i, c) in footer.columns.iter().enumerate() {
+            let mut column = Column::new(&c.name, c.datatype, c.nullable, i);
+            column.fixed_string = c.fixed_string;
+            // Fixed strings are always zero-padded, even if the padding was not recorded
+            column.padding = if c.fixed_string { Some(0) } else { c.padding };
+            column.default_value = match c.appended {
+                Some(Some(ref bytes)) => Some(try!(ColumnValue::from_bytes(&c.datatype, bytes))),
+                Some(None) => Some(ColumnValue::Null),
+                None => None
+            };
+            column.metadata = c.metadata.iter().cloned().collect();
+            column.set_dictionary(c.dictionary_mode, c.dictionary.clone());
+            columns.push(column);
+        }
+
+        let storage = Storage {
+            num_rows: footer.num_rows,
+            columns: columns,
+            backend: backend,
+            stripes: footer.stripes,
+            max_value_bytes: footer.max_value_bytes,
+            rows_per_stripe: footer.rows_per_stripe,
+            compressor: Compressor::with_compression(footer.compression, footer.compression_level).unwrap_or(Compressor::None),
+            encoder: if footer.auto_encoding { Encoder::Auto } else { Encoder::Raw },
+            footer_written: true
+        };
+
+        if let Some(schema_hash) = footer.schema_hash {
+            if schema_hash != storage.schema_hash() {
+                return Err(StorageError::InvalidFormat("Schema hash mismatch, the footer is corrupted".to_string()));
+            }
+        }
+
+        Ok(storage)
+    }
+
+    /// Writes the footer: the schema and the stripe index, followed by its checksum, the
+    /// footer's offset and the signature.
     pub fn write_footer(&mut self) -> StorageResult<()> {
-        try!(self.backend.seek(SeekFrom::End(0)));
-        try!(self.backend.write(Self::signature()));
+        let footer = try!(self.serialized_footer());
+        let mut crc = Crc32::new();
+        crc.update(&footer);
+
+        let mut writer = BufWriter::new(try!(self.backend.writer()));
+        let footer_offset = try!(writer.seek(SeekFrom::End(0)));
+        try!(writer.write_all(&footer));
+        try!(writer.write_all(&crc.finish().to_le_bytes()));
+        try!(writer.write_all(&footer_offset.to_le_bytes()));
+
+        // The signature marks the storage as complete, so everything else must be written first
+        try!(writer.flush());
+        try!(writer.write_all(Self::signature()));
+        try!(writer.flush());
+        drop(writer);
+
+        self.footer_written = true;
         Ok(())
     }
 
+    /// Creates a storage at dest with the rows of all the sources, in order. The sources must
+    /// have the same schema, which is checked before creating dest. Their stripes are copied
+    /// as they are, without decoding or compressing them again.
+    pub fn merge<P: AsRef<Path>>(dest: P, sources: &[&Path]) -> StorageResult<Storage> {
+        let sources: Vec<Storage> = try!(sources.iter().map(|path| Storage::open(path)).collect());
+        let first = match sources.first() {
+            Some(s) => s,
+            None => return Err(StorageError::InvalidFormat("There are no storages to merge".to_string()))
+        };
+
+        let schema = |storage: &Storage| -> Vec<(String, ColumnDatatype, bool, bool, Option<ColumnValue>, DictionaryMode)> {
+            storage.columns.iter().map(|c| (c.name.clone(), c.datatype, c.nullable, c.fixed_string, c.default_value.clone(), c.dictionary_mode)).collect()
+        };
+        // The chunks hold codes into the dictionaries, so they must be the same too
+        let dictionaries = |storage: &Storage| -> Vec<Option<Vec<Vec<u8>>>> {
+            storage.columns.iter().map(|c| c.dictionary().map(|d| d.values().to_vec())).collect()
+        };
+        let expected = schema(first);
+        let expected_dictionaries = dictionaries(first);
+        for (i, source) in sources.iter().enumerate() {
+            if schema(source) != expected {
+                return Err(StorageError::InvalidFormat(format!("Storage {} has a different schema than storage 0", i)));
+            }
+            if dictionaries(source) != expected_dictionaries {
+                return Err(StorageError::InvalidFormat(format!("Storage {} has different dictionaries than storage 0", i)));
+            }
+            if !source.stripe_sizes_are_known() {
+                return Err(StorageError::InvalidFormat(format!("Storage {} has stripes of unknown size", i)));
+            }
+        }
+
+        let mut storage = try!(first.schema_builder().at(dest));
+        for source in sources.iter() {
+            try!(storage.copy_stripes(source));
+        }
+
+        try!(storage.write_footer());
+        Ok(storage)
+    }
+
+    /// Rewrites the storage at dest with only the stripes in its index, dropping any other
+    /// bytes (e.g. left by failed inserts). The stripes are copied as they are.
+    pub fn vacuum<P: AsRef<Path>>(&self, dest: P) -> StorageResult<Storage> {
+        if !self.stripe_sizes_are_known() {
+            return Err(StorageError::InvalidFormat("The storage has stripes of unknown size".to_string()));
+        }
+
+        let mut storage = try!(self.schema_builder().at(dest));
+        try!(storage.copy_stripes(self));
+        try!(storage.write_footer());
+        Ok(storage)
+    }
+
+    /// Stripes written before their size was recorded cannot be copied
+    fn stripe_sizes_are_known(&self) -> bool {
+        self.stripes.iter().all(|s| s.size > 0 || s.num_rows == 0)
+    }
+
+    /// A builder for a storage with the same schema and settings
+    fn schema_builder(&self) -> StorageBuilder {
+        let mut builder = StorageBuilder::new();
+        for column in self.columns.iter() {
+            builder.columns.push(ColumnBuilder {
+                name: column.name.clone(),
+                datatype: column.datatype,
+                nullable: column.nullable,
+                padding: column.padding,
+                fixed_string: column.fixed_string,
+                default_value: column.default_value.clone(),
+                metadata: column.metadata.clone(),
+                dictionary_mode: column.dictionary_mode,
+                dictionary: column.dictionary().map_or(Vec::new(), |d| d.values().to_vec())
+            });
+        }
+        builder.rows_per_stripe = self.rows_per_stripe;
+        builder.max_value_bytes = self.max_value_bytes;
+        builder.compressor = self.compressor;
+        builder.encoder = self.encoder;
+        builder
+    }
+
+    /// Appends the stripes of source at the end of the backend, and adds them to the index
+    fn copy_stripes(&mut self, source: &Storage) -> StorageResult<()> {
+        let mut reader = try!(source.backend.reader());
+        for stripe in source.stripes.iter() {
+            let mut bytes = vec![0u8; stripe.size];
+            try!(reader.seek(SeekFrom::Start(stripe.absolute_offset as u64)));
+            try!(reader.read_exact(&mut bytes));
+
+            // The chunks' offsets are relative to their stripe, so only the index changes
+            let absolute_offset = {
+                let mut writer = try!(self.backend.writer());
+                let offset = try!(writer.seek(SeekFrom::End(0)));
+                try!(writer.write_all(&bytes));
+                try!(writer.flush());
+                offset as usize
+            };
+            self.append_stripe(&proto_structs::Stripe {
+                absolute_offset: absolute_offset,
+                num_rows: stripe.num_rows,
+                size: stripe.size
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Drops the trailing stripes so that only the first num_rows rows are kept, e.g. to roll
+    /// back a failed load. num_rows must fall on a stripe boundary. The backend is cut at the
+    /// first dropped stripe and a new footer is written.
+    pub fn truncate_to_rows(&mut self, num_rows: usize) -> StorageResult<()> {
+        if num_rows > self.num_rows {
+            return Err(StorageError::InvalidRowIndex(num_rows));
+        }
+        if num_rows == self.num_rows {
+            return Ok(());
+        }
+
+        let mut num_stripes = 0;
+        let mut rows_kept = 0;
+        while rows_kept < num_rows {
+            rows_kept += self.stripes[num_stripes].num_rows;
+            num_stripes += 1;
+        }
+        if rows_kept != num_rows {
+            return Err(StorageError::InvalidFormat(format!("Row {} is not at the start of a stripe", num_rows)));
+        }
+
+        let end_offset = self.stripes[num_stripes].absolute_offset;
+        try!(self.backend.truncate(end_offset as u64));
+        self.stripes.truncate(num_stripes);
+        self.num_rows = num_rows;
+
+        self.write_footer()
+    }
+
+    /// Adds a nullable column at the end of the schema, without rewriting the stripes. The
+    /// rows already written hold the default value for it (which can be Null), and new rows
+    /// must have a value for it. The new footer is written after the old one, which is left
+    /// as unused bytes (see vacuum).
+    pub fn append_column(&mut self, name: &str, datatype: ColumnDatatype, default: ColumnValue) -> StorageResult<()> {
+        try!(Self::check_column_name(name));
+        if self.column_by_name(name).is_some() {
+            return Err(StorageError::InvalidFormat(format!("Column '{}' is specified more than once", name)));
+        }
+        if let ColumnDatatype::FixedLength(size) = datatype {
+            if size <= 0 {
+                return Err(StorageError::InvalidFormat(format!("Column '{}' has an invalid value size ({})", name, size)));
+            }
+        }
+        if !default.has_datatype(&datatype) {
+            return Err(StorageError::TypeMismatch { column: self.columns.len(), expected: datatype });
+        }
+
+        let mut column = Column::new(name, datatype, true, self.columns.len());
+        column.default_value = Some(default);
+        self.columns.push(column);
+
+        self.write_footer()
+    }
+
+    /// The footer message, without its offset and the signature
+    fn serialized_footer(&self) -> StorageResult<Vec<u8>> {
+        let footer = proto_structs::StorageFooter {
+            num_rows: self.num_rows,
+            columns: self.columns.iter()
+                .map(|c| proto_structs::ColumnSchema {
+                    name: c.name.clone(),
+                    datatype: c.datatype,
+                    nullable: c.nullable,
+                    fixed_string: c.fixed_string,
+                    appended: c.default_value.as_ref().map(|v| v.to_bytes()),
+                    metadata: {
+                        let mut metadata: Vec<(String, String)> = c.metadata.iter()
+                            .map(|(k, v)| (k.clone(), v.clone()))
+                            .collect();
+                        metadata.sort();
+                        metadata
+                    },
+                    dictionary_mode: c.dictionary_mode,
+                    dictionary: c.dictionary().map_or(Vec::new(), |d| d.values().to_vec()),
+                    padding: c.padding
+                })
+                .collect(),
+            stripes: self.stripes.clone(),
+            rows_per_stripe: self.rows_per_stripe,
+            schema_hash: Some(self.schema_hash()),
+            compression: self.compressor.compression(),
+            compression_level: self.compressor.level(),
+            auto_encoding: self.encoder == Encoder::Auto,
+            max_value_bytes: self.max_value_bytes
+        };
+
+        let mut builder = ProtoBuilder::new_default();
+        {
+            let mut footer_builder = builder.init_root::<<proto_structs::StorageFooter as ProtocolBuildable>::Builder>();
+            footer.build_message(&mut footer_builder);
+        }
+
+        let mut bytes = Vec::new();
+        try!(::capnp::serialize::write_message(&mut bytes, &builder));
+        Ok(bytes)
+    }
+
+    /// The size of the storage once its footer is written, computed from the stripe index
+    /// without reading the backend. Stripes written before their size was recorded in the
+    /// index are not counted.
+    pub fn estimated_size_on_disk(&self) -> usize {
+        let header_size = Self::signature().len() + mem::size_of_val(&FORMAT_VERSION);
+        let stripes_size = self.stripes.iter().map(|s| s.size).fold(0, |a, b| a + b);
+        let footer_size = self.serialized_footer().map(|f| f.len()).unwrap_or(0)
+            + mem::size_of::<u32>() + mem::size_of::<u64>() + Self::signature().len();
+
+        header_size + stripes_size + footer_size
+    }
+
     fn signature() -> &'static [u8] {
         // "Snel Columnar Storage"
         "SCS".as_bytes()
     }
 
+    /// Attaches a check to a column, like StorageBuilder::check. Checks are not kept in the
+    /// footer, so they have to be attached again after opening a storage for appending.
+    pub fn add_check(&mut self, column: &str, check: Box<ColumnCheck>) -> StorageResult<()> {
+        match self.columns.iter_mut().find(|c| c.name == column) {
+            Some(c) => {
+                c.checks.push(Arc::from(check));
+                Ok(())
+            },
+            None => Err(StorageError::InvalidFormat(format!("Check specified for unknown column '{}'", column)))
+        }
+    }
+
     pub fn columns(&self) -> &Vec<Column> { &self.columns }
     pub fn column(&self, idx: usize) -> &Column { &self.columns[idx] }
     pub fn column_by_name(&self, name: &str) -> Option<&Column> {
         self.columns.iter().find(|ref c| c.name == name)
     }
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        self.column_by_name(name).map(|c| c.num_column)
+    }
+    pub fn datatype_info(&self, name: &str) -> Option<&DatatypeInfo> {
+        self.column_by_name(name).map(|c| &c.datatype_info)
+    }
     pub fn num_columns(&self) -> usize { self.columns.len() }
     pub fn num_rows(&self) -> usize { self.num_rows }
 
+    /// The stripe index. Stripes can only be added by inserting rows, so this doesn't compile:
+    ///
+    /// ```compile_fail
+    /// # use table::storage::Storage;
+    /// # fn f(mut storage: Storage) {
+    /// let stripe = storage.stripes()[0].clone();
+    /// storage.append_stripe(&stripe);
+    /// # }
+    /// ```
+    pub fn stripes(&self) -> &[proto_structs::Stripe] { &self.stripes }
+    /// Walks the stripes, e.g. to read their headers. Nothing is read until a stripe is used.
+    pub fn stripes_iter(&self) -> StorageStripeIterator<'_> { StorageStripeIterator::new(self) }
+    pub fn max_value_bytes(&self) -> Option<usize> { self.max_value_bytes }
+    /// The number of rows per stripe set when the storage was created, if any
+    pub fn rows_per_stripe(&self) -> Option<usize> { self.rows_per_stripe }
+    /// The compression of the new stripes. Each chunk also records its own. It is persisted in
+    /// the footer, so it is kept when the storage is opened for appending.
+    pub fn compressor(&self) -> Compressor { self.compressor }
+    /// How the numeric chunks of the new stripes are encoded. Persisted in the footer too.
+    pub fn encoder(&self) -> Encoder { self.encoder }
+
+    /// A hash of the names and datatypes of the columns, in order. It is the same in every
+    /// run and version, so storages with the same layout can be matched by comparing it.
+    /// It is also stored in the footer.
+    pub fn schema_hash(&self) -> u64 {
+        let mut hash = Fnv1a64::new();
+        for column in self.columns.iter() {
+            let (column_type, value_size) = proto_structs::datatype_to_proto(column.datatype);
+            hash.update(&(column.name.len() as u64).to_le_bytes());
+            hash.update(column.name.as_bytes());
+            hash.update(&column_type.to_u16().to_le_bytes());
+            hash.update(&value_size.to_le_bytes());
+        }
+        hash.finish()
+    }
+
+    /// A printable description of the schema: one line per column and the number of rows
+    pub fn describe(&self) -> String {
+        let mut description = String::new();
+        for (i, column) in self.columns.iter().enumerate() {
+            description.push_str(&format!("{}: {} {}\n", i, column.name, column.datatype));
+        }
+        description.push_str(&format!("{} rows\n", self.num_rows));
+        description
+    }
+
     pub fn begin_inserting(self) -> InsertionManager {
         InsertionManager::new(self)
     }
 
-    //TODO: Make this function non-public
-    pub fn append_stripe(&mut self, stripe: &proto_structs::Stripe) {
+    /// Writes the footer, unless the backend already ends with an up-to-date one, and returns
+    /// the backend, e.g. to take the bytes of an in-memory storage with into_bytes
+    pub fn close(mut self) -> StorageResult<StorageBackend> {
+        if !self.footer_written {
+            try!(self.write_footer());
+        }
+        Ok(self.backend)
+    }
+
+    /// Calls f for each value of a numeric column (None for NULL values), decoding the
+    /// column stripe by stripe without materializing it.
+    pub fn visit_column<N, F>(&self, col: usize, mut f: F) -> StorageResult<()>
+        where N: NumericValue + PartialEq + Copy, F: FnMut(Option<N>)
+    {
+        if self.column(col).datatype != N::datatype() {
+            return Err(StorageError::TypeError);
+        }
+
+        for stripe in StorageStripeIterator::new(self) {
+            for value in try!(stripe.read_numeric_values::<N>(col)) {
+                f(value);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Counts the rows by adding up the number of rows in each stripe header, without
+    /// decoding any values. Useful to validate num_rows().
+    pub fn count_rows_from_headers(&self) -> StorageResult<usize> {
+        let mut num_rows = 0;
+        for stripe in StorageStripeIterator::new(self) {
+            num_rows += try!(stripe.get_header()).num_rows;
+        }
+
+        Ok(num_rows)
+    }
+
+    /// Aggregates the statistics in the chunk headers of a column, without decoding any
+    /// values. None if there is no column with that name.
+    pub fn column_stats(&self, name: &str) -> StorageResult<Option<ColumnStats>> {
+        let num_column = match self.column_index(name) {
+            Some(i) => i,
+            None => return Ok(None)
+        };
+
+        let mut stats = ColumnStats { min: None, max: None, null_count: 0, num_rows: 0 };
+        for stripe in StorageStripeIterator::new(self) {
+            let header = try!(stripe.get_header_unverified());
+            let stripe_stats = try!(self.chunk_stats(&header, num_column));
+            stats.num_rows += stripe_stats.num_rows;
+            stats.null_count += stripe_stats.null_count;
+            if let (Some(min), Some(max)) = (stripe_stats.min, stripe_stats.max) {
+                stats.min = Some(stats.min.map_or(min, |m| cmp::min(m, min)));
+                stats.max = Some(stats.max.map_or(max, |m| cmp::max(m, max)));
+            }
+        }
+
+        Ok(Some(stats))
+    }
+
+    /// The statistics of every stripe (row group) and of each of its columns, in the order
+    /// of the stripes and the columns. Only the headers are read, so external query engines
+    /// can use them to skip stripes without decoding any values.
+    pub fn row_group_stats(&self) -> StorageResult<Vec<StripeStats>> {
+        let mut result = Vec::with_capacity(self.stripes.len());
+        for (stripe, footer_stripe) in StorageStripeIterator::new(self).zip(self.stripes.iter()) {
+            let header = try!(stripe.get_header_unverified());
+            let mut columns = Vec::with_capacity(self.columns.len());
+            for num_column in 0..self.columns.len() {
+                columns.push(try!(self.chunk_stats(&header, num_column)));
+            }
+
+            result.push(StripeStats {
+                offset: footer_stripe.absolute_offset,
+                num_rows: header.num_rows,
+                columns: columns
+            });
+        }
+
+        Ok(result)
+    }
+
+    /// The statistics of a column in one stripe, from its chunk header or its default value
+    fn chunk_stats(&self, header: &proto_structs::StripeHeader, num_column: usize) -> StorageResult<ColumnStats> {
+        let mut stats = ColumnStats { min: None, max: None, null_count: 0, num_rows: header.num_rows };
+        match (header.column_chunks.get(num_column), self.column(num_column).default_value()) {
+            (Some(chunk_header), _) => {
+                stats.null_count = chunk_header.null_count;
+                if let Some((min, max)) = chunk_header.min_max {
+                    stats.min = Some(min);
+                    stats.max = Some(max);
+                }
+            },
+            // Appended after the stripe was written, so all the rows hold the default
+            (None, Some(default)) => match *default {
+                ColumnValue::Null => stats.null_count = header.num_rows,
+                ref v => {
+                    stats.min = v.to_i64();
+                    stats.max = v.to_i64();
+                }
+            },
+            (None, None) => return Err(StorageError::InvalidFormat(format!("Stripe has no chunk for column {}", num_column)))
+        }
+
+        Ok(stats)
+    }
+
+    /// Reads the rows with the given (global) indices, in the same order. The indices don't
+    /// need to be sorted and can be repeated: they are grouped by stripe, so each stripe
+    /// is decoded at most once.
+    pub fn read_rows(&self, indices: &[usize]) -> StorageResult<Vec<Vec<ColumnValue>>> {
+        let mut rows: Vec<Option<Vec<ColumnValue>>> = vec!(None; indices.len());
+
+        for (stripe_index, positions) in try!(self.group_rows_by_stripe(indices)) {
+            let stripe = StorageStripeIterator::new(self).nth(stripe_index).unwrap();
+            let columns = try!(stripe.read_columns());
+            for (position, row_in_stripe) in positions {
+                rows[position] = Some(columns.iter().map(|c| c[row_in_stripe].clone()).collect());
+            }
+        }
+
+        Ok(rows.into_iter().map(|r| r.unwrap()).collect())
+    }
+
+    /// For each stripe holding some of the rows, returns the stripe index and the list of
+    /// (position in indices, row number within the stripe)
+    fn group_rows_by_stripe(&self, indices: &[usize]) -> StorageResult<Vec<(usize, Vec<(usize, usize)>)>> {
+        let mut positions: Vec<usize> = (0..indices.len()).collect();
+        positions.sort_by_key(|&p| indices[p]);
+
+        let mut groups: Vec<(usize, Vec<(usize, usize)>)> = Vec::new();
+        let mut stripe_index = 0;
+        let mut stripe_begin = 0;
+        for position in positions {
+            let row = indices[position];
+
+            // Advance to the stripe that holds the row
+            while stripe_index < self.stripes.len() && row >= stripe_begin + self.stripes[stripe_index].num_rows {
+                stripe_begin += self.stripes[stripe_index].num_rows;
+                stripe_index += 1;
+            }
+            if stripe_index >= self.stripes.len() {
+                return Err(StorageError::InvalidRowIndex(row));
+            }
+
+            if groups.last().map(|&(s, _)| s) != Some(stripe_index) {
+                groups.push((stripe_index, Vec::new()));
+            }
+            groups.last_mut().unwrap().1.push((position, row - stripe_begin));
+        }
+
+        Ok(groups)
+    }
+
+    pub fn visit_column_i8<F: FnMut(Option<i8>)>(&self, col: usize, f: F) -> StorageResult<()> { self.visit_column(col, f) }
+    pub fn visit_column_i16<F: FnMut(Option<i16>)>(&self, col: usize, f: F) -> StorageResult<()> { self.visit_column(col, f) }
+    pub fn visit_column_i32<F: FnMut(Option<i32>)>(&self, col: usize, f: F) -> StorageResult<()> { self.visit_column(col, f) }
+    pub fn visit_column_i64<F: FnMut(Option<i64>)>(&self, col: usize, f: F) -> StorageResult<()> { self.visit_column(col, f) }
+    pub fn visit_column_f32<F: FnMut(Option<f32>)>(&self, col: usize, f: F) -> StorageResult<()> { self.visit_column(col, f) }
+    pub fn visit_column_timestamp<F: FnMut(Option<Timestamp>)>(&self, col: usize, f: F) -> StorageResult<()> { self.visit_column(col, f) }
+
+    /// Only the inserter adds stripes, once they have been written to the backend
+    pub(crate) fn append_stripe(&mut self, stripe: &proto_structs::Stripe) {
         self.stripes.push((*stripe).clone());
         self.num_rows += stripe.num_rows;
+        self.footer_written = false;
     }
 }
 
 // ----------------------------------------------------------------------------
 pub struct StorageBuilder {
-    columns: Vec<ColumnBuilder>
+    columns: Vec<ColumnBuilder>,
+    checks: Vec<(String, Arc<ColumnCheck>)>,
+    max_value_bytes: Option<usize>,
+    rows_per_stripe: Option<usize>,
+    compressor: Compressor,
+    encoder: Encoder
 }
 
 impl StorageBuilder {
     pub fn new() -> StorageBuilder {
-        StorageBuilder { columns: Vec::new() }
+        StorageBuilder { columns: Vec::new(), checks: Vec::new(), max_value_bytes: None, rows_per_stripe: None, compressor: Compressor::None, encoder: Encoder::Raw }
     }
 
     pub fn column(&mut self, name: &str, datatype: ColumnDatatype) -> &mut Self {
@@ -186,6 +934,98 @@ impl StorageBuilder {
         self
     }
 
+    /// A column that doesn't accept NULL values
+    pub fn column_not_null(&mut self, name: &str, datatype: ColumnDatatype) -> &mut Self {
+        let mut column = Column::build(name, datatype);
+        column.nullable = false;
+        self.columns.push(column);
+        self
+    }
+
+    /// A column with key/value metadata, which is stored in the footer and can be read back
+    /// with Column::metadata. The "comment" key holds the free-text comment of the column.
+    pub fn column_with_metadata(&mut self, name: &str, datatype: ColumnDatatype, metadata: HashMap<String, String>) -> &mut Self {
+        let mut column = Column::build(name, datatype);
+        column.metadata = metadata;
+        self.columns.push(column);
+        self
+    }
+
+    /// A variable length column whose chunks can store codes into a dictionary of its values,
+    /// which is written once in the footer instead of in every stripe
+    pub fn column_with_dictionary(&mut self, name: &str, mode: DictionaryMode) -> &mut Self {
+        let mut column = Column::build(name, ColumnDatatype::VariableLength);
+        column.dictionary_mode = mode;
+        self.columns.push(column);
+        self
+    }
+
+    /// A fixed length column where shorter values are right-padded with the fill byte when
+    /// inserted. Longer values are still rejected. The padding is persisted in the footer, so
+    /// it also applies to rows appended after reopening the storage.
+    pub fn column_padded(&mut self, name: &str, value_size: i32, fill: u8) -> &mut Self {
+        let mut column = Column::build(name, ColumnDatatype::FixedLength(value_size));
+        column.padding = Some(fill);
+        self.columns.push(column);
+        self
+    }
+
+    /// A fixed length column holding UTF-8 strings of up to width bytes, zero-padded like
+    /// ColumnValue::fixed_string does. Shorter values are padded when inserted, and exports
+    /// render the values as text without the padding. As with any fixed length column,
+    /// creating the storage fails if width is not positive.
+    pub fn column_fixed_string(&mut self, name: &str, width: i32) -> &mut Self {
+        let mut column = Column::build(name, ColumnDatatype::FixedLength(width));
+        column.padding = Some(0);
+        column.fixed_string = true;
+        self.columns.push(column);
+        self
+    }
+
+    /// Adds a column for each field of a typed row, with the nullability of its type
+    pub fn row_columns<R: ::row::Row>(&mut self) -> &mut Self {
+        for (name, datatype, nullable) in R::schema().into_iter() {
+            if nullable {
+                self.column(name, datatype);
+            } else {
+                self.column_not_null(name, datatype);
+            }
+        }
+        self
+    }
+
+    /// Adds a check on the values of a column. Rows with a value that does not pass it
+    /// are rejected when inserted.
+    pub fn check(&mut self, column: &str, check: Box<ColumnCheck>) -> &mut Self {
+        self.checks.push((String::from(column), Arc::from(check)));
+        self
+    }
+
+    /// Limits the size of fixed and variable length values. There is no limit by default.
+    pub fn max_value_bytes(&mut self, limit: usize) -> &mut Self {
+        self.max_value_bytes = Some(limit);
+        self
+    }
+
+    /// How many rows an inserter puts in each stripe. By default, it depends on the size
+    /// of the values.
+    pub fn rows_per_stripe(&mut self, num_rows: usize) -> &mut Self {
+        self.rows_per_stripe = Some(num_rows);
+        self
+    }
+
+    /// How the inserters compress the chunks. They are not compressed by default.
+    pub fn compressor(&mut self, compressor: Compressor) -> &mut Self {
+        self.compressor = compressor;
+        self
+    }
+
+    /// How the inserters encode the numeric chunks. They are raw by default.
+    pub fn encoder(&mut self, encoder: Encoder) -> &mut Self {
+        self.encoder = encoder;
+        self
+    }
+
     /// Creates the storage at the specified path
     pub fn at<P: AsRef<Path>>(&self, path_ref: P) -> StorageResult<Storage> {
         let path = path_ref.as_ref();
@@ -207,30 +1047,181 @@ impl StorageBuilder {
             }
         }
 
-        // Create the file that will hold this storage
-        let file = try!(File::create(&path_ref));
-
-        Storage::init(Box::new(file), self)
+        // The file that will hold this storage is created when the header is written
+        self.in_backend(StorageBackend::in_path(path))
     }
 
     pub fn in_memory(&self) -> StorageResult<Storage> {
-        let mem_backend = Cursor::new(Vec::<u8>::new());
-        Storage::init(Box::new(mem_backend), self)
+        self.in_backend(StorageBackend::new_in_memory())
+    }
+
+    /// Creates a write-only storage over the given writer, see StorageBackend::from_writer.
+    /// Seek is required to know the offsets of the stripes and of the footer.
+    pub fn into_writer<W>(&self, writer: W) -> StorageResult<Storage>
+        where W: Write + Seek + Send + Sync + 'static
+    {
+        self.in_backend(StorageBackend::from_writer(writer))
+    }
+
+    /// Creates the storage over an arbitrary backend
+    pub fn in_backend(&self, backend: StorageBackend) -> StorageResult<Storage> {
+        Storage::init(backend, self)
     }
 }
 
 
 // ----------------------------------------------------------------------------
-#[derive(Debug, Clone)]
+/// With the serde feature, FixedLength and VariableLength values are serialized as raw byte arrays
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum ColumnValue {
     Null,
-    Byte(i8), Int32(i32), Int64(i64),
+    Byte(i8), Int16(i16), Int32(i32), Int64(i64),
     Float(f32),
-    FixedLength(Vec<u8>), VariableLength(Vec<u8>)
+    FixedLength(Vec<u8>), VariableLength(Vec<u8>),
+    Timestamp(i64)
+}
+
+impl ColumnValue {
+    /// The value of integer and timestamp values, as stored in the chunk statistics
+    fn to_i64(&self) -> Option<i64> {
+        match *self {
+            ColumnValue::Byte(v) => Some(v as i64),
+            ColumnValue::Int16(v) => Some(v as i64),
+            ColumnValue::Int32(v) => Some(v as i64),
+            ColumnValue::Int64(v) | ColumnValue::Timestamp(v) => Some(v),
+            _ => None
+        }
+    }
+
+    /// Whether the value can be stored in a column of the given type. NULL can be stored in any.
+    fn has_datatype(&self, datatype: &ColumnDatatype) -> bool {
+        match (self, *datatype) {
+            (&ColumnValue::Null, _) => true,
+            (&ColumnValue::Byte(_), ColumnDatatype::Byte) => true,
+            (&ColumnValue::Int16(_), ColumnDatatype::Int16) => true,
+            (&ColumnValue::Int32(_), ColumnDatatype::Int32) => true,
+            (&ColumnValue::Int64(_), ColumnDatatype::Int64) => true,
+            (&ColumnValue::Float(_), ColumnDatatype::Float) => true,
+            (&ColumnValue::Timestamp(_), ColumnDatatype::Timestamp) => true,
+            (&ColumnValue::FixedLength(ref v), ColumnDatatype::FixedLength(size)) => v.len() == size as usize,
+            (&ColumnValue::VariableLength(_), ColumnDatatype::VariableLength) => true,
+            _ => false
+        }
+    }
+
+    /// The bytes of the value as they are in a raw chunk (numbers in little-endian), None
+    /// for NULL. Used to store the default values of appended columns.
+    fn to_bytes(&self) -> Option<Vec<u8>> {
+        Some(match *self {
+            ColumnValue::Null => return None,
+            ColumnValue::Byte(v) => get_slice_bytes(&[v]),
+            ColumnValue::Int16(v) => get_slice_bytes(&[v]),
+            ColumnValue::Int32(v) => get_slice_bytes(&[v]),
+            ColumnValue::Int64(v) => get_slice_bytes(&[v]),
+            ColumnValue::Float(v) => get_slice_bytes(&[v]),
+            ColumnValue::Timestamp(v) => get_slice_bytes(&[Timestamp(v)]),
+            ColumnValue::FixedLength(ref v) | ColumnValue::VariableLength(ref v) => v.clone()
+        })
+    }
+
+    /// The counterpart of to_bytes, for a non-NULL value of the given type
+    fn from_bytes(datatype: &ColumnDatatype, bytes: &[u8]) -> StorageResult<ColumnValue> {
+        fn numeric<N: NumericValue + Copy>(bytes: &[u8]) -> StorageResult<ColumnValue> {
+            let values = try!(get_slice_values::<N>(bytes));
+            match values.first() {
+                Some(&v) if values.len() == 1 => Ok(v.into_column_value()),
+                _ => Err(StorageError::InvalidFormat(format!("Invalid {} value", N::datatype())))
+            }
+        }
+
+        match *datatype {
+            ColumnDatatype::Byte => numeric::<i8>(bytes),
+            ColumnDatatype::Int16 => numeric::<i16>(bytes),
+            ColumnDatatype::Int32 => numeric::<i32>(bytes),
+            ColumnDatatype::Int64 => numeric::<i64>(bytes),
+            ColumnDatatype::Float => numeric::<f32>(bytes),
+            ColumnDatatype::Timestamp => numeric::<Timestamp>(bytes),
+            ColumnDatatype::FixedLength(size) if bytes.len() == size as usize => Ok(ColumnValue::FixedLength(bytes.to_vec())),
+            ColumnDatatype::FixedLength(_) => Err(StorageError::InvalidFormat(format!("Invalid {} value", datatype))),
+            ColumnDatatype::VariableLength => Ok(ColumnValue::VariableLength(bytes.to_vec()))
+        }
+    }
+
+    /// Compares two numeric values of the same type. Returns None if either of them is
+    /// NULL, NaN or not numeric, or if their types differ.
+    ///
+    /// This is the NaN policy of the storage: NaN is not ordered, so it is left out of the
+    /// chunk statistics, like NULL. It is still a regular value otherwise: it is stored and
+    /// read back, displayed as Float(NaN), and as with f32, Float(NaN) != Float(NaN).
+    pub fn numeric_cmp(&self, other: &ColumnValue) -> Option<cmp::Ordering> {
+        match (self, other) {
+            (&ColumnValue::Byte(a), &ColumnValue::Byte(b)) => Some(a.cmp(&b)),
+            (&ColumnValue::Int16(a), &ColumnValue::Int16(b)) => Some(a.cmp(&b)),
+            (&ColumnValue::Int32(a), &ColumnValue::Int32(b)) => Some(a.cmp(&b)),
+            (&ColumnValue::Int64(a), &ColumnValue::Int64(b)) => Some(a.cmp(&b)),
+            (&ColumnValue::Timestamp(a), &ColumnValue::Timestamp(b)) => Some(a.cmp(&b)),
+            // partial_cmp is None when either of them is NaN
+            (&ColumnValue::Float(a), &ColumnValue::Float(b)) => a.partial_cmp(&b),
+            _ => None
+        }
+    }
+
+    /// A fixed-length value holding a UTF-8 string, right-padded with zeros up to width
+    pub fn fixed_string(s: &str, width: usize) -> StorageResult<ColumnValue> {
+        if s.len() > width {
+            return Err(StorageError::InvalidLength(s.len(), width));
+        }
+
+        let mut bytes = s.as_bytes().to_vec();
+        bytes.resize(width, 0);
+        Ok(ColumnValue::FixedLength(bytes))
+    }
+
+    /// The string held by a fixed-length value created with fixed_string, without its padding.
+    /// None if this is not a fixed-length value or if it is not valid UTF-8.
+    pub fn as_fixed_string(&self) -> Option<&str> {
+        match *self {
+            ColumnValue::FixedLength(ref v) => {
+                let length = v.iter().rposition(|&b| b != 0).map_or(0, |p| p + 1);
+                str::from_utf8(&v[..length]).ok()
+            },
+            _ => None
+        }
+    }
 }
 
+/// How many bytes of fixed length and binary values Display shows, see ColumnValue::preview
+pub const PREVIEW_BYTES: usize = 5;
+
 impl fmt::Display for ColumnValue {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_preview(f, PREVIEW_BYTES)
+    }
+}
+
+/// Displays a value showing up to max_bytes of its bytes, see ColumnValue::preview
+pub struct ValuePreview<'a> {
+    value: &'a ColumnValue,
+    max_bytes: usize
+}
+
+impl<'a> fmt::Display for ValuePreview<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.value.fmt_preview(f, self.max_bytes)
+    }
+}
+
+impl ColumnValue {
+    /// Like Display, but showing up to max_bytes of fixed length and binary values, e.g.
+    /// format!("{}", value.preview(10))
+    pub fn preview(&self, max_bytes: usize) -> ValuePreview<'_> {
+        ValuePreview { value: self, max_bytes: max_bytes }
+    }
+
+    /// Writes the value like Display, but with up to max_bytes of fixed length and binary
+    /// values before the "..."
+    pub fn fmt_preview(&self, f: &mut fmt::Formatter, max_bytes: usize) -> fmt::Result {
         fn write_bytes<'a>(f: &mut fmt::Formatter, iter: &mut Iterator<Item=&'a u8>) -> fmt::Result {
             while let Some(b) = iter.next() {
                 try!(write!(f, "{:X} ", b));
@@ -242,13 +1233,15 @@ impl fmt::Display for ColumnValue {
         match *self {
             ColumnValue::Null => { write!(f, "(NULL)") },
             ColumnValue::Byte(v) => { write!(f, "Byte({})", v) },
+            ColumnValue::Int16(v) => { write!(f, "Int16({})", v) },
             ColumnValue::Int32(v) => { write!(f, "Int32({})", v) },
             ColumnValue::Int64(v) => { write!(f, "Int64({})", v) },
             ColumnValue::Float(v) => { write!(f, "Float({})", v) },
+            ColumnValue::Timestamp(v) => { write!(f, "Timestamp({})", v) },
             ColumnValue::FixedLength(ref v) => {
                 write!(f, "FixedLength(")
-                    .and(write_bytes(f, &mut v.iter().take(5)))
-                    .and(if v.len() > 5 {write!(f, "...")} else {Ok(())})
+                    .and(write_bytes(f, &mut v.iter().take(max_bytes)))
+                    .and(if v.len() > max_bytes {write!(f, "...")} else {Ok(())})
                     .and(write!(f, ")"))
             },
             ColumnValue::VariableLength(ref v) => {
@@ -256,8 +1249,8 @@ impl fmt::Display for ColumnValue {
                 write!(f, "VariableLength(")
                     .and(match str::from_utf8(v) {
                         Ok(s) => write!(f, "\"{}\"", s),
-                        Err(_) => write_bytes(f, &mut v.iter().take(5))
-                            .and(if v.len() > 5 {write!(f, "...")} else {Ok(())})
+                        Err(_) => write_bytes(f, &mut v.iter().take(max_bytes))
+                            .and(if v.len() > max_bytes {write!(f, "...")} else {Ok(())})
                     })
                     .and(write!(f, ")"))
             }
@@ -272,8 +1265,18 @@ pub trait NumericValue: Sized {
     fn extract_value_exact(value: &ColumnValue) -> Option<Self>;
     /// The column datatype used for storing a value of this type
     fn datatype() -> ColumnDatatype;
-    /// The null value associated to this type
+    /// Placeholder stored in place of NULL values. NULLs are tracked by the nulls bitmap of
+    /// each chunk, so this can be a valid value too (e.g. -inf for f32).
     fn null_value() -> Self;
+    /// Wraps the value in the ColumnValue variant of this type
+    fn into_column_value(self) -> ColumnValue;
+    /// The value as an i64, for the integer types. Used for the chunk statistics.
+    fn to_i64(self) -> Option<i64> { None }
+    /// Appends the little-endian bytes of the value to out. Values are stored in
+    /// little-endian byte order, whatever the host.
+    fn write_le(self, out: &mut Vec<u8>);
+    /// The value of exactly size_of::<Self>() little-endian bytes
+    fn read_le(bytes: &[u8]) -> Self;
 
     /// Extract a value of this type or the NULL value. Returns an error
     /// if the value is not NULL or it is not of this type.
@@ -300,7 +1303,31 @@ impl NumericValue for i8 {
     }
 
     fn datatype() -> ColumnDatatype { ColumnDatatype::Byte }
+    fn into_column_value(self) -> ColumnValue { ColumnValue::Byte(self) }
+    fn to_i64(self) -> Option<i64> { Some(self as i64) }
     fn null_value() -> Self { i8::MIN }
+    fn write_le(self, out: &mut Vec<u8>) { out.extend_from_slice(&self.to_le_bytes()); }
+    fn read_le(bytes: &[u8]) -> Self { i8::from_le_bytes([bytes[0]]) }
+}
+
+impl NumericValue for i16 {
+    fn extract_value_exact(value: &ColumnValue) -> Option<Self> {
+        match *value {
+            ColumnValue::Int16(v) => Some(v),
+            _ => None
+        }
+    }
+
+    fn datatype() -> ColumnDatatype { ColumnDatatype::Int16 }
+    fn into_column_value(self) -> ColumnValue { ColumnValue::Int16(self) }
+    fn to_i64(self) -> Option<i64> { Some(self as i64) }
+    fn null_value() -> Self { i16::MIN }
+    fn write_le(self, out: &mut Vec<u8>) { out.extend_from_slice(&self.to_le_bytes()); }
+    fn read_le(bytes: &[u8]) -> Self {
+        let mut buf = [0; 2];
+        buf.copy_from_slice(bytes);
+        i16::from_le_bytes(buf)
+    }
 }
 
 impl NumericValue for i32 {
@@ -312,7 +1339,15 @@ impl NumericValue for i32 {
     }
 
     fn datatype() -> ColumnDatatype { ColumnDatatype::Int32 }
+    fn into_column_value(self) -> ColumnValue { ColumnValue::Int32(self) }
+    fn to_i64(self) -> Option<i64> { Some(self as i64) }
     fn null_value() -> Self { i32::MIN }
+    fn write_le(self, out: &mut Vec<u8>) { out.extend_from_slice(&self.to_le_bytes()); }
+    fn read_le(bytes: &[u8]) -> Self {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(bytes);
+        i32::from_le_bytes(buf)
+    }
 }
 
 impl NumericValue for i64 {
@@ -324,7 +1359,15 @@ impl NumericValue for i64 {
     }
 
     fn datatype() -> ColumnDatatype { ColumnDatatype::Int64 }
+    fn into_column_value(self) -> ColumnValue { ColumnValue::Int64(self) }
+    fn to_i64(self) -> Option<i64> { Some(self) }
     fn null_value() -> Self { i64::MIN }
+    fn write_le(self, out: &mut Vec<u8>) { out.extend_from_slice(&self.to_le_bytes()); }
+    fn read_le(bytes: &[u8]) -> Self {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(bytes);
+        i64::from_le_bytes(buf)
+    }
 }
 
 impl NumericValue for f32 {
@@ -336,27 +1379,58 @@ impl NumericValue for f32 {
     }
 
     fn datatype() -> ColumnDatatype { ColumnDatatype::Float }
+    fn into_column_value(self) -> ColumnValue { ColumnValue::Float(self) }
     fn null_value() -> Self { f32::NEG_INFINITY }
+    fn write_le(self, out: &mut Vec<u8>) { out.extend_from_slice(&self.to_bits().to_le_bytes()); }
+    fn read_le(bytes: &[u8]) -> Self {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(bytes);
+        f32::from_bits(u32::from_le_bytes(buf))
+    }
+}
+
+/// The value of a Timestamp column, in microseconds since the epoch
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
+pub struct Timestamp(pub i64);
+
+impl NumericValue for Timestamp {
+    fn extract_value_exact(value: &ColumnValue) -> Option<Self> {
+        match *value {
+            ColumnValue::Timestamp(v) => Some(Timestamp(v)),
+            _ => None
+        }
+    }
+
+    fn datatype() -> ColumnDatatype { ColumnDatatype::Timestamp }
+    fn into_column_value(self) -> ColumnValue { ColumnValue::Timestamp(self.0) }
+    fn to_i64(self) -> Option<i64> { Some(self.0) }
+    fn null_value() -> Self { Timestamp(i64::MIN) }
+    fn write_le(self, out: &mut Vec<u8>) { self.0.write_le(out) }
+    fn read_le(bytes: &[u8]) -> Self { Timestamp(i64::read_le(bytes)) }
 }
 
 // ----------------------------------------------------------------------------
 #[cfg(test)]
 mod test {
+    use std::fs;
     use std::fs::File;
     use std::io::{Read, Seek, SeekFrom};
 
-    use ::test::{TestPath};
-    use ::storage::{Storage, StorageBuilder, ColumnDatatype};
+    use ::test::{TestPath, TestStorage};
+    use ::storage::{Storage, StorageBuilder, ColumnDatatype, ColumnValue, FORMAT_VERSION};
+    use ::error::StorageError;
 
     #[test]
     fn storage_can_be_initialized() {
         let test_path = TestPath::new();
         let filename = test_path.file_name("test.storage");
 
-        StorageBuilder::new()
+        let storage = StorageBuilder::new()
             .column("id", ColumnDatatype::Int32)
             .at(&filename)
             .unwrap();
+        storage.begin_inserting().finish_inserting().unwrap();
 
         // Check that the file has the right header and footer
         let mut file = File::open(&filename).unwrap();
@@ -367,11 +1441,188 @@ mod test {
         file.read_exact(&mut buf).unwrap();
         assert_eq!(&buf[..], expected_signature);
 
+        let mut version = [0u8; 2];
+        file.read_exact(&mut version).unwrap();
+        assert_eq!(u16::from_le_bytes(version), FORMAT_VERSION);
+
         // Check the footer
         file.seek(SeekFrom::End(-(expected_signature.len() as i64))).unwrap();
         file.read_exact(&mut buf).unwrap();
         assert_eq!(&buf[..], expected_signature);
     }
 
+    #[test]
+    #[should_panic(expected="Unsupported format version")]
+    fn unknown_versions_are_rejected() {
+        let test_path = TestPath::new();
+        let filename = test_path.file_name("test.storage");
+        StorageBuilder::new()
+            .column("id", ColumnDatatype::Int32)
+            .at(&filename)
+            .unwrap();
+
+        let mut bytes = fs::read(&filename).unwrap();
+        bytes[Storage::signature().len()] = 0xFF;
+        fs::write(&filename, &bytes).unwrap();
+
+        Storage::open(&filename).unwrap();
+    }
+
+    #[test]
+    fn corrupted_footers_are_detected() {
+        let test_path = TestPath::new();
+        let filename = test_path.file_name("test.storage");
+        TestStorage::insert_rows(TestStorage::new(&filename), 100, 50);
+        let bytes = fs::read(&filename).unwrap();
+
+        // The footer ends with its checksum, offset and the signature
+        let trailer_size = 4 + 8 + Storage::signature().len();
+        let mut corrupted = bytes.clone();
+        let footer_byte = corrupted.len() - trailer_size - 10;
+        corrupted[footer_byte] ^= 0x01;
+        fs::write(&filename, &corrupted).unwrap();
+        match Storage::open(&filename) {
+            Err(StorageError::InvalidFormat(ref msg)) => assert!(msg.contains("footer is corrupted"), "{}", msg),
+            Err(e) => panic!("Expected InvalidFormat, got {:?}", e),
+            Ok(_) => panic!("Expected InvalidFormat")
+        }
+
+        // An offset past the checksum
+        let mut corrupted = bytes.clone();
+        let offset_byte = corrupted.len() - 8 - Storage::signature().len() + 3;
+        corrupted[offset_byte] = 0x7F;
+        fs::write(&filename, &corrupted).unwrap();
+        assert!(Storage::open(&filename).is_err());
+
+        fs::write(&filename, &bytes).unwrap();
+        assert_eq!(Storage::open(&filename).unwrap().num_rows(), 100);
+    }
+
+    #[test]
+    fn schema_hashes_identify_layouts() {
+        let test_path = TestPath::new();
+        let filename = test_path.file_name("test.storage");
+
+        let build = |names: &[&str]| {
+            let mut builder = StorageBuilder::new();
+            for name in names.iter() {
+                let datatype = if *name == "id" { ColumnDatatype::Int32 } else { ColumnDatatype::VariableLength };
+                builder.column(name, datatype);
+            }
+            builder.in_memory().unwrap()
+        };
+
+        let storage = build(&["id", "name"]);
+        assert_eq!(storage.schema_hash(), build(&["id", "name"]).schema_hash());
+        assert!(storage.schema_hash() != build(&["name", "id"]).schema_hash());
+        assert!(storage.schema_hash() != build(&["id", "nam"]).schema_hash());
+        assert!(storage.schema_hash() != build(&["id"]).schema_hash());
+
+        let other_type = StorageBuilder::new()
+            .column("id", ColumnDatatype::Int64)
+            .column("name", ColumnDatatype::VariableLength)
+            .in_memory().unwrap();
+        assert!(storage.schema_hash() != other_type.schema_hash());
+        let other_size = StorageBuilder::new()
+            .column("a", ColumnDatatype::FixedLength(4))
+            .in_memory().unwrap();
+        let other_size2 = StorageBuilder::new()
+            .column("a", ColumnDatatype::FixedLength(8))
+            .in_memory().unwrap();
+        assert!(other_size.schema_hash() != other_size2.schema_hash());
+
+        // Nullability is not part of the layout
+        let not_null = StorageBuilder::new()
+            .column_not_null("id", ColumnDatatype::Int32)
+            .column("name", ColumnDatatype::VariableLength)
+            .in_memory().unwrap();
+        assert_eq!(storage.schema_hash(), not_null.schema_hash());
+
+        // The hash survives reopening the file
+        let hash = TestStorage::insert_rows(TestStorage::new(&filename), 10, 10).schema_hash();
+        assert_eq!(Storage::open(&filename).unwrap().schema_hash(), hash);
+    }
+
+    #[test]
+    fn byte_values_can_be_previewed() {
+        let value = ColumnValue::FixedLength((0..20).collect());
+        assert_eq!(format!("{}", value.preview(10)), "FixedLength(0 1 2 3 4 5 6 7 8 9 ...)");
+        assert_eq!(format!("{}", value), "FixedLength(0 1 2 3 4 ...)");
+        assert_eq!(format!("{}", value.preview(20)), format!("FixedLength({})", (0..20).map(|b| format!("{:X} ", b)).collect::<String>()));
+
+        let binary = ColumnValue::VariableLength(vec!(0xFF; 8));
+        assert_eq!(format!("{}", binary.preview(2)), "VariableLength(FF FF ...)");
+        assert_eq!(format!("{}", ColumnValue::Int32(7).preview(0)), "Int32(7)");
+    }
+
+    #[test]
+    fn fixed_strings() {
+        let exact = ColumnValue::fixed_string("abcde", 5).unwrap();
+        assert_eq!(exact, ColumnValue::FixedLength(b"abcde".to_vec()));
+        assert_eq!(exact.as_fixed_string(), Some("abcde"));
+
+        let short = ColumnValue::fixed_string("ab", 5).unwrap();
+        assert_eq!(short, ColumnValue::FixedLength(vec!(b'a', b'b', 0, 0, 0)));
+        assert_eq!(short.as_fixed_string(), Some("ab"));
+
+        match ColumnValue::fixed_string("abcdef", 5) {
+            Err(StorageError::InvalidLength(6, 5)) => {},
+            _ => panic!("Expected an InvalidLength error")
+        }
+
+        assert_eq!(ColumnValue::Int32(1).as_fixed_string(), None);
+    }
+
+    #[test]
+    fn rows_can_be_read_by_index() {
+        let test_path = TestPath::new();
+        let storage = TestStorage::new(&test_path.file_name("test.storage"));
+        let storage = TestStorage::insert_rows(storage, 10000, 2500);
+        assert_eq!(storage.stripes().len(), 4);
+
+        let rows = storage.read_rows(&[5, 5000, 9999]).unwrap();
+        assert_eq!(rows, vec!(TestStorage::row(5), TestStorage::row(5000), TestStorage::row(9999)));
 
+        // Unsorted and repeated indices
+        let rows = storage.read_rows(&[9999, 5, 2501, 5]).unwrap();
+        assert_eq!(rows, vec!(TestStorage::row(9999), TestStorage::row(5), TestStorage::row(2501), TestStorage::row(5)));
+
+        // Each stripe is decoded at most once
+        let groups = storage.group_rows_by_stripe(&[9999, 5, 2501, 5, 6]).unwrap();
+        let stripes: Vec<usize> = groups.iter().map(|&(s, _)| s).collect();
+        assert_eq!(stripes, vec!(0, 1, 3));
+        assert_eq!(groups[0].1.len(), 3);
+
+        assert!(storage.read_rows(&[10000]).is_err());
+    }
+
+    #[test]
+    fn datatype_info_by_column_name() {
+        let test_path = TestPath::new();
+        let storage = TestStorage::new(&test_path.file_name("test.storage"));
+
+        assert!(storage.datatype_info("int32col").unwrap().is_numeric);
+        assert_eq!(storage.datatype_info("int32col").unwrap().value_size, Some(4));
+
+        let info = storage.datatype_info("variablelengthcol").unwrap();
+        assert!(!info.is_numeric);
+        assert!(!info.is_fixed_size);
+        assert!(storage.datatype_info("nonexistent").is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn values_can_be_serialized() {
+        use ::serde_json;
+        use ::storage::ColumnValue;
+
+        let row = vec!(ColumnValue::Null, ColumnValue::Byte(-1), ColumnValue::Int32(3), ColumnValue::Int64(1 << 40),
+            ColumnValue::Float(1.5), ColumnValue::FixedLength(vec!(1, 2, 3)), ColumnValue::VariableLength(b"abc".to_vec()));
+        let json = serde_json::to_string(&row).unwrap();
+        assert_eq!(serde_json::from_str::<Vec<ColumnValue>>(&json).unwrap(), row);
+
+        let datatypes = vec!(ColumnDatatype::Int64, ColumnDatatype::FixedLength(5), ColumnDatatype::VariableLength);
+        let json = serde_json::to_string(&datatypes).unwrap();
+        assert_eq!(serde_json::from_str::<Vec<ColumnDatatype>>(&json).unwrap(), datatypes);
+    }
 }
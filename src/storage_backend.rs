@@ -1,13 +1,21 @@
+use std::io;
 use std::io::{Read, Write, Seek, Cursor};
 use std::path::{Path, PathBuf};
 use std::fs::OpenOptions;
 
 use ::error::StorageResult;
+#[cfg(unix)]
+use ::os::MappedFile;
 
 // ----------------------------------------------------------------------------
 pub enum StorageBackend {
     Memory(Cursor<Vec<u8>>),
-    File(PathBuf)
+    File(PathBuf),
+    /// Read-only, the file is mapped in memory. Only on unix hosts.
+    #[cfg(unix)]
+    Mmap(MappedFile),
+    /// Write-only, over any writer (e.g. a socket or a compressor)
+    Writer(Box<BackendWriter + Send + Sync>)
 }
 
 pub trait BackendReader : Read + Seek {}
@@ -29,6 +37,49 @@ impl StorageBackend {
         StorageBackend::File(path.as_ref().to_path_buf())
     }
 
+    /// Writes the storage into the given writer. It must be seekable, because the offsets of
+    /// the stripes and of the footer are taken from the writer's position.
+    pub fn from_writer<W>(writer: W) -> StorageBackend
+        where W: Write + Seek + Send + Sync + 'static
+    {
+        StorageBackend::Writer(Box::new(writer))
+    }
+
+    /// Maps an existing file in memory. Chunks are then read without any system calls,
+    /// but the storage cannot be written. The file must not be truncated while it is
+    /// mapped: reading past its new end crashes the process with SIGBUS.
+    #[cfg(unix)]
+    pub fn mmap<P: AsRef<Path>>(path: P) -> StorageResult<StorageBackend> {
+        Ok(StorageBackend::Mmap(try!(MappedFile::open(path))))
+    }
+
+    /// The contents of an in-memory backend, None for the other backends
+    pub fn into_bytes(self) -> Option<Vec<u8>> {
+        match self {
+            StorageBackend::Memory(c) => Some(c.into_inner()),
+            _ => None
+        }
+    }
+
+    /// Cuts the storage at the given length, dropping everything after it
+    pub fn truncate(&mut self, len: u64) -> StorageResult<()> {
+        match *self {
+            StorageBackend::Memory(ref mut c) => c.get_mut().truncate(len as usize),
+            StorageBackend::File(ref file_path) => {
+                let file = try!(OpenOptions::new().write(true).open(file_path));
+                try!(file.set_len(len));
+            }
+            #[cfg(unix)]
+            StorageBackend::Mmap(_) => {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Memory-mapped storages are read-only").into());
+            }
+            StorageBackend::Writer(_) => {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Writer storages cannot be truncated").into());
+            }
+        }
+        Ok(())
+    }
+
     pub fn reader<'a>(&'a self) -> StorageResult<Box<BackendReader+'a>> {
         match *self {
             StorageBackend::Memory(ref c) => {
@@ -46,6 +97,11 @@ impl StorageBackend {
                 );
                 Ok(Box::new(file))
             }
+            #[cfg(unix)]
+            StorageBackend::Mmap(ref m) => Ok(Box::new(Cursor::new(m.as_slice()))),
+            StorageBackend::Writer(_) => {
+                Err(io::Error::new(io::ErrorKind::PermissionDenied, "Writer storages are write-only").into())
+            }
         }
     }
 
@@ -62,6 +118,11 @@ impl StorageBackend {
                 );
                 Ok(Box::new(file))
             }
+            #[cfg(unix)]
+            StorageBackend::Mmap(_) => {
+                Err(io::Error::new(io::ErrorKind::PermissionDenied, "Memory-mapped storages are read-only").into())
+            }
+            StorageBackend::Writer(ref mut w) => Ok(Box::new(w))
         }
     }
 }
@@ -73,6 +134,8 @@ mod test {
     use std::io::{Write, Read};
 
     use ::storage_backend::*;
+    use ::storage::Storage;
+    use ::storage_reader::StorageStripeIterator;
     use ::test;
 
     #[test]
@@ -147,4 +210,21 @@ mod test {
         file.read(&mut buffer).unwrap();
         assert_eq!(buffer, orig_buffer);
     }
+
+    #[test]
+    #[cfg(unix)]
+    fn read_stripe_from_mapped_file() {
+        let test_path = test::TestPath::new();
+        let file_name = test_path.file_name("test.storage");
+        let storage = test::TestStorage::new(&file_name);
+        test::TestStorage::insert_rows(storage, 100, 50);
+
+        let mut storage = Storage::load(StorageBackend::mmap(&file_name).unwrap()).unwrap();
+        assert_eq!(storage.num_rows(), 100);
+
+        let columns = StorageStripeIterator::new(&storage).nth(1).unwrap().read_columns().unwrap();
+        assert_eq!(columns[2][0], test::TestStorage::row(50)[2]);
+
+        assert!(storage.backend.writer().is_err());
+    }
 }
@@ -1,55 +1,182 @@
 use std::mem;
-use std::slice;
-use std::sync::{Arc, RwLock};
+use std::borrow::Cow;
+use std::cmp;
+use std::sync::{Arc, Mutex, RwLock};
+use std::collections::HashSet;
 use std::io;
-use std::io::{Write};
+use std::io::{Write, Seek, BufWriter};
 
 use capnp::message::{Builder as ProtoBuilder};
 
-use ::encoding::Encoding;
-use ::compression::Compression;
-use ::storage::{ColumnDatatype, Storage, ColumnValue, NumericValue};
+use ::encoding;
+use ::encoding::{Encoding, Encoder, DictionaryMode, GlobalDictionary};
+use ::compression::{Compression, Compressor};
+use ::checksum::Crc32;
+use ::storage::{ColumnDatatype, Column, Storage, ColumnValue, NumericValue, Timestamp};
 use ::error::{StorageError, StorageResult};
 use ::proto_structs;
 use ::proto_structs::ProtocolBuildable;
+use ::nulls_bitmap::{NullsBitmap, NullsEncoding};
+use ::hyperloglog::HyperLogLog;
 
 // ----------------------------------------------------------------------------
-pub struct EncodedChunk<'a>(pub Encoding, pub &'a [u8]);
-pub struct CompressedChunk<'a>(pub Compression, pub Encoding, pub &'a [u8]);
+pub struct EncodedChunk<'a>(pub Encoding, pub NullsEncoding, pub &'a [u8]);
+pub struct CompressedChunk<'a>(pub Compression, pub Encoding, pub Cow<'a, [u8]>);
 
 // ----------------------------------------------------------------------------
-/// Helper function
-fn get_slice_bytes<'a, T>(s: &'a [T]) -> &'a [u8]
-    where T: Sized
+/// Appends the values to out one by one, each as its little-endian bytes, which is how
+/// they are stored regardless of the host
+pub fn write_le_values<T>(values: &[T], out: &mut Vec<u8>)
+    where T: NumericValue + Copy
 {
-    let ptr = s.as_ptr() as *const u8;
-    let size = mem::size_of::<T>() * s.len();
-    unsafe { slice::from_raw_parts(ptr, size) }
+    out.reserve(mem::size_of::<T>() * values.len());
+    for &v in values.iter() {
+        v.write_le(out);
+    }
+}
+
+/// The little-endian bytes of the values, see write_le_values
+pub fn get_slice_bytes<T>(values: &[T]) -> Vec<u8>
+    where T: NumericValue + Copy
+{
+    let mut bytes = Vec::new();
+    write_le_values(values, &mut bytes);
+    bytes
 }
 
-trait ChunkGenerator {
+/// Statistics of a chunk, stored in its header
+struct ChunkStats {
+    /// Minimum and maximum of the non-null values, only for integer types
+    min_max: Option<(i64, i64)>,
+    /// The same for floats, without NaN values
+    float_min_max: Option<(f32, f32)>,
+    null_count: usize,
+    distinct_count: Option<usize>
+}
+
+fn numeric_chunk_stats<N>(nulls: &NullsBitmap, values: &[N], distinct: &HyperLogLog) -> ChunkStats
+    where N: NumericValue + Copy
+{
+    let mut stats = ChunkStats { min_max: None, float_min_max: None, null_count: nulls.count_nulls(), distinct_count: Some(distinct.estimate()) };
+
+    // Compared as ColumnValues, so that NaN values are skipped like NULLs
+    let mut min_max: Option<(ColumnValue, ColumnValue)> = None;
+    for (has_value, &value) in nulls.iter().zip(values.iter()) {
+        let v = value.into_column_value();
+        if !has_value || v.numeric_cmp(&v).is_none() {
+            continue;
+        }
+        min_max = match min_max {
+            None => Some((v.clone(), v)),
+            Some((min, max)) => Some((
+                if v.numeric_cmp(&min) == Some(cmp::Ordering::Less) { v.clone() } else { min },
+                if v.numeric_cmp(&max) == Some(cmp::Ordering::Greater) { v } else { max }
+            ))
+        };
+    }
+
+    match min_max {
+        Some((ColumnValue::Float(min), ColumnValue::Float(max))) => stats.float_min_max = Some((min, max)),
+        Some((min, max)) => {
+            let to_i64 = |v: &ColumnValue| N::extract_value_exact(v).and_then(N::to_i64);
+            if let (Some(min), Some(max)) = (to_i64(&min), to_i64(&max)) {
+                stats.min_max = Some((min, max));
+            }
+        },
+        None => {}
+    }
+
+    stats
+}
+
+/// Writes the NULLs of a numeric chunk: the bitmap, or the runs of present and NULL values
+/// if they are smaller (i.e., the NULLs are clustered)
+fn write_numeric_nulls(nulls: &NullsBitmap, out: &mut Vec<u8>) -> NullsEncoding {
+    let mut runs = Vec::new();
+    nulls.write_runs(&mut runs);
+    if runs.len() < nulls.get_raw_bits().len() {
+        out.extend_from_slice(&runs);
+        NullsEncoding::RunLength
+    } else {
+        out.extend_from_slice(nulls.get_raw_bits());
+        NullsEncoding::Plain
+    }
+}
+
+/// Send, so that each inserter can be moved to its own thread
+trait ChunkGenerator: Send {
     fn validate_value(&self, value: &ColumnValue) -> StorageResult<()>;
     fn get_encoded_chunk<'a>(&'a mut self) -> EncodedChunk<'a>;
     fn reset(&mut self);
+    /// Makes room for at least num_values more values
+    fn reserve(&mut self, num_values: usize);
+
+    fn stats(&self) -> ChunkStats;
 
     /// Precondition: self.validate_value(value).is_ok()
     fn append_values<'a>(&mut self, values: &mut Iterator<Item=&'a ColumnValue>);
 }
 
+/// Numeric chunks hold a nulls bitmap followed by the values. NULL values still take
+/// their place in the values, so that they can be accessed by position.
 struct NumericChunkGenerator<N> {
-    values: Vec<N>
+    nulls: NullsBitmap,
+    values: Vec<N>,
+    distinct: HyperLogLog,
+    encoder: Encoder,
+    encoded_chunk_buffer: Vec<u8>
 }
 
 impl<N> NumericChunkGenerator<N> {
-    fn new(num_values: usize) -> NumericChunkGenerator<N> {
+    fn new(num_values: usize, encoder: Encoder) -> NumericChunkGenerator<N> {
         NumericChunkGenerator {
-            values: Vec::with_capacity(num_values)
+            nulls: NullsBitmap::with_capacity(num_values),
+            values: Vec::with_capacity(num_values),
+            distinct: HyperLogLog::new(),
+            encoder: encoder,
+            encoded_chunk_buffer: Vec::new()
         }
     }
 }
 
+/// Encodes the values with each encoding that applies to them and appends the smallest
+/// result to out. Raw is kept unless another encoding is strictly smaller.
+fn encode_smallest<N>(values: &[N], out: &mut Vec<u8>) -> Encoding
+    where N: NumericValue + Copy
+{
+    let mut raw = Vec::new();
+    write_le_values(values, &mut raw);
+    let mut best = (Encoding::Raw, raw.clone());
+
+    let mut rle = Vec::new();
+    encoding::encode_rle(&raw, mem::size_of::<N>(), &mut rle);
+    if rle.len() < best.1.len() {
+        best = (Encoding::RLE, rle);
+    }
+
+    let integers: Option<Vec<i64>> = values.iter().map(|v| v.to_i64()).collect();
+    if let Some(integers) = integers {
+        if encoding::delta_encodable(&integers) {
+            let mut delta = Vec::new();
+            encoding::encode_delta(&integers, &mut delta);
+            if delta.len() < best.1.len() {
+                best = (Encoding::Delta, delta);
+            }
+        }
+
+        let mut frame = Vec::new();
+        let base = encoding::encode_frame_of_reference(&integers, &mut frame);
+        if frame.len() < best.1.len() {
+            best = (Encoding::FrameOfReference(base), frame);
+        }
+    }
+
+    out.extend_from_slice(&best.1);
+    best.0
+}
+
 impl<N> ChunkGenerator for NumericChunkGenerator<N>
-    where N: NumericValue
+    where N: NumericValue + PartialEq + Copy + Send
 {
     fn validate_value(&self, value: &ColumnValue) -> StorageResult<()> {
         try!(N::extract_value_or_null(value));
@@ -57,39 +184,143 @@ impl<N> ChunkGenerator for NumericChunkGenerator<N>
     }
 
     fn append_values<'a>(&mut self, values: &mut Iterator<Item=&'a ColumnValue>) {
+        let mut bytes = Vec::with_capacity(mem::size_of::<N>());
         while let Some(ref value) = values.next() {
-            let v = match N::extract_value_or_null(value).unwrap() {
-                Some(v) => v,
-                None => N::null_value()
-            };
-            self.values.push(v);
+            let v = N::extract_value_or_null(value).unwrap();
+            if let Some(v) = v {
+                bytes.clear();
+                v.write_le(&mut bytes);
+                self.distinct.insert(&bytes);
+            }
+            self.nulls.push(v.is_some());
+            self.values.push(v.unwrap_or_else(N::null_value));
         }
     }
 
     fn get_encoded_chunk<'a>(&'a mut self) -> EncodedChunk<'a> {
-        let result = get_slice_bytes(&self.values);
-        EncodedChunk(Encoding::Raw, result)
+        self.encoded_chunk_buffer.clear();
+        let nulls_encoding = write_numeric_nulls(&self.nulls, &mut self.encoded_chunk_buffer);
+        let encoding = match self.encoder {
+            Encoder::Raw => {
+                write_le_values(&self.values, &mut self.encoded_chunk_buffer);
+                Encoding::Raw
+            },
+            Encoder::Auto => {
+                // NULLs repeat the previous value, so that they don't break runs or deltas
+                let mut previous = None;
+                let values: Vec<N> = self.nulls.iter().zip(self.values.iter())
+                    .map(|(has_value, &v)| {
+                        let v = if has_value { v } else { previous.unwrap_or(v) };
+                        previous = Some(v);
+                        v
+                    })
+                    .collect();
+                encode_smallest(&values, &mut self.encoded_chunk_buffer)
+            }
+        };
+
+        EncodedChunk(encoding, nulls_encoding, &self.encoded_chunk_buffer)
     }
 
+    fn stats(&self) -> ChunkStats { numeric_chunk_stats(&self.nulls, &self.values, &self.distinct) }
+
     fn reset(&mut self) {
+        self.nulls.clear();
         self.values.clear();
+        self.distinct.clear();
+    }
+
+    fn reserve(&mut self, num_values: usize) {
+        self.nulls.reserve(num_values);
+        self.values.reserve(num_values);
+    }
+}
+
+// ----------------------------------------------------------------------------
+/// Timestamps are usually near-monotonic, so they are stored with delta-of-delta encoding.
+/// The nulls bitmap comes first, as in the other numeric chunks.
+struct TimestampChunkGenerator {
+    nulls: NullsBitmap,
+    values: Vec<Timestamp>,
+    distinct: HyperLogLog,
+    encoded_chunk_buffer: Vec<u8>
+}
+
+impl TimestampChunkGenerator {
+    fn new(num_values: usize) -> TimestampChunkGenerator {
+        TimestampChunkGenerator {
+            nulls: NullsBitmap::with_capacity(num_values),
+            values: Vec::with_capacity(num_values),
+            distinct: HyperLogLog::new(),
+            encoded_chunk_buffer: Vec::new()
+        }
+    }
+}
+
+impl ChunkGenerator for TimestampChunkGenerator {
+    fn validate_value(&self, value: &ColumnValue) -> StorageResult<()> {
+        try!(Timestamp::extract_value_or_null(value));
+        Ok(())
+    }
+
+    fn append_values<'a>(&mut self, values: &mut Iterator<Item=&'a ColumnValue>) {
+        let mut bytes = Vec::with_capacity(mem::size_of::<Timestamp>());
+        while let Some(ref value) = values.next() {
+            let v = Timestamp::extract_value_or_null(value).unwrap();
+            if let Some(v) = v {
+                bytes.clear();
+                v.write_le(&mut bytes);
+                self.distinct.insert(&bytes);
+            }
+            self.nulls.push(v.is_some());
+
+            // Repeating the previous value keeps the deltas of NULLs at zero
+            let previous = self.values.last().cloned().unwrap_or(Timestamp(0));
+            self.values.push(v.unwrap_or(previous));
+        }
+    }
+
+    fn get_encoded_chunk<'a>(&'a mut self) -> EncodedChunk<'a> {
+        let values: Vec<i64> = self.values.iter().map(|t| t.0).collect();
+        self.encoded_chunk_buffer.clear();
+        let nulls_encoding = write_numeric_nulls(&self.nulls, &mut self.encoded_chunk_buffer);
+        encoding::encode_delta_of_delta(&values, &mut self.encoded_chunk_buffer);
+
+        EncodedChunk(Encoding::DeltaOfDelta, nulls_encoding, &self.encoded_chunk_buffer)
+    }
+
+    fn stats(&self) -> ChunkStats { numeric_chunk_stats(&self.nulls, &self.values, &self.distinct) }
+
+    fn reset(&mut self) {
+        self.nulls.clear();
+        self.values.clear();
+        self.distinct.clear();
+    }
+
+    fn reserve(&mut self, num_values: usize) {
+        self.nulls.reserve(num_values);
+        self.values.reserve(num_values);
     }
 }
 
 // ----------------------------------------------------------------------------
 struct FixedLengthChunkGenerator {
     value_size: usize,
-    nulls: Vec<bool>,
+    padding: Option<u8>,
+    nulls: NullsBitmap,
     values: Vec<u8>,
+    distinct: HyperLogLog,
     encoded_chunk_buffer: Vec<u8>
 }
 
 impl FixedLengthChunkGenerator {
-    fn new(value_size: i32, num_values: usize) -> FixedLengthChunkGenerator {
+    fn new(value_size: i32, padding: Option<u8>, num_values: usize) -> FixedLengthChunkGenerator {
         FixedLengthChunkGenerator {
             value_size: value_size as usize,
-            nulls: Vec::with_capacity(num_values),
+            padding: padding,
+            nulls: NullsBitmap::with_capacity(num_values),
             values: Vec::with_capacity(num_values*value_size as usize),
+            distinct: HyperLogLog::new(),
             encoded_chunk_buffer: Vec::new()
         }
     }
@@ -100,7 +331,7 @@ impl ChunkGenerator for FixedLengthChunkGenerator {
         match *value {
             ColumnValue::Null => Ok(()),
             ColumnValue::FixedLength(ref v) => {
-                if v.len() == self.value_size {
+                if v.len() == self.value_size || (v.len() < self.value_size && self.padding.is_some()) {
                     Ok(())
                 } else {
                     Err(StorageError::InvalidLength(v.len(), self.value_size))
@@ -113,10 +344,17 @@ impl ChunkGenerator for FixedLengthChunkGenerator {
     fn append_values<'a>(&mut self, values: &mut Iterator<Item=&'a ColumnValue>) {
         while let Some(ref value) = values.next() {
             match **value {
-                ColumnValue::Null => self.nulls.push(true),
+                ColumnValue::Null => self.nulls.push(false),
                 ColumnValue::FixedLength(ref v) => {
-                    self.nulls.push(false);
+                    self.nulls.push(true);
+                    let begin = self.values.len();
                     self.values.write(&v[..]).unwrap();
+                    if let Some(fill) = self.padding {
+                        for _ in v.len()..self.value_size {
+                            self.values.push(fill);
+                        }
+                    }
+                    self.distinct.insert(&self.values[begin..]);
                 },
                 // Should never get to this point
                 _ => panic!("Internal error: Received an invalid value size")
@@ -125,18 +363,29 @@ impl ChunkGenerator for FixedLengthChunkGenerator {
     }
 
     fn get_encoded_chunk<'a>(&'a mut self) -> EncodedChunk<'a> {
-        let nulls: Vec<u8> = self.nulls.iter().map(|n| if *n { 1 } else { 0 }).collect();
+        // One byte per value, set for NULLs
+        let nulls: Vec<u8> = self.nulls.iter().map(|has_value| if has_value { 0 } else { 1 }).collect();
 
         self.encoded_chunk_buffer.clear();
         self.encoded_chunk_buffer.write(&nulls).unwrap();
         self.encoded_chunk_buffer.write(&self.values).unwrap();
 
-        EncodedChunk(Encoding::Raw, &self.encoded_chunk_buffer)
+        EncodedChunk(Encoding::Raw, NullsEncoding::Plain, &self.encoded_chunk_buffer)
+    }
+
+    fn stats(&self) -> ChunkStats {
+        ChunkStats { min_max: None, float_min_max: None, null_count: self.nulls.count_nulls(), distinct_count: Some(self.distinct.estimate()) }
     }
 
     fn reset(&mut self) {
         self.nulls.clear();
         self.values.clear();
+        self.distinct.clear();
+    }
+
+    fn reserve(&mut self, num_values: usize) {
+        self.nulls.reserve(num_values);
+        self.values.reserve(num_values*self.value_size);
     }
 }
 
@@ -144,24 +393,67 @@ impl ChunkGenerator for FixedLengthChunkGenerator {
 struct VariableLengthChunkGenerator {
     sizes: Vec<i32>,
     values: Vec<u8>,
+    distinct: HyperLogLog,
+    dictionary_mode: DictionaryMode,
+    dictionary: Option<Arc<Mutex<GlobalDictionary>>>,
     encoded_chunk_buffer: Vec<u8>
 }
 
 impl VariableLengthChunkGenerator {
-    fn new(num_values: usize) -> VariableLengthChunkGenerator {
+    fn new(num_values: usize, dictionary_mode: DictionaryMode, dictionary: Option<Arc<Mutex<GlobalDictionary>>>) -> VariableLengthChunkGenerator {
         VariableLengthChunkGenerator {
             sizes: Vec::with_capacity(num_values),
             values: Vec::new(),
+            distinct: HyperLogLog::new(),
+            dictionary_mode: dictionary_mode,
+            dictionary: dictionary,
             encoded_chunk_buffer: Vec::new()
         }
     }
+
+    /// The values of the chunk, None for NULLs
+    fn chunk_values(&self) -> Vec<Option<&[u8]>> {
+        let mut offset = 0;
+        self.sizes.iter()
+            .map(|&size| {
+                if size < 0 {
+                    return None;
+                }
+                let value = &self.values[offset..offset + size as usize];
+                offset += size as usize;
+                Some(value)
+            })
+            .collect()
+    }
+
+    /// Whether the codes take less than the values: the codes replace the sizes, so the
+    /// values the chunk adds to the dictionary (and their sizes) must take less than all
+    /// of its values
+    fn dictionary_is_smaller(&self, dictionary: &GlobalDictionary, values: &[Option<&[u8]>]) -> bool {
+        let mut new_values = HashSet::new();
+        let mut new_size = 0;
+        for value in values.iter().filter_map(|v| *v) {
+            if dictionary.code(value).is_none() && new_values.insert(value) {
+                new_size += mem::size_of::<i32>() + value.len();
+            }
+        }
+        new_size < self.values.len()
+    }
+
+    /// Sizes are stored as i32, with -1 for NULLs
+    fn check_value_size(size: usize) -> StorageResult<()> {
+        if size > i32::MAX as usize {
+            return Err(StorageError::InvalidLength(size, i32::MAX as usize));
+        }
+        Ok(())
+    }
 }
 
 impl ChunkGenerator for VariableLengthChunkGenerator {
     fn validate_value(&self, value: &ColumnValue) -> StorageResult<()> {
         match *value {
             ColumnValue::Null => Ok(()),
-            ColumnValue::VariableLength(_) => Ok(()),
+            ColumnValue::VariableLength(ref v) => Self::check_value_size(v.len()),
             _ => Err(StorageError::TypeError)
         }
     }
@@ -173,6 +465,7 @@ impl ChunkGenerator for VariableLengthChunkGenerator {
                 ColumnValue::VariableLength(ref v) => {
                     self.sizes.push(v.len() as i32);
                     self.values.write(v).unwrap();
+                    self.distinct.insert(v);
                 },
                 // Should never get to this point
                 _ => unreachable!()
@@ -182,15 +475,43 @@ impl ChunkGenerator for VariableLengthChunkGenerator {
 
     fn get_encoded_chunk<'a>(&'a mut self) -> EncodedChunk<'a> {
         self.encoded_chunk_buffer.clear();
-        self.encoded_chunk_buffer.write(get_slice_bytes(&self.sizes)).unwrap();
+
+        let codes = match self.dictionary {
+            Some(ref dictionary) => {
+                let mut dictionary = dictionary.lock().unwrap();
+                let values = self.chunk_values();
+                if self.dictionary_mode == DictionaryMode::Always || self.dictionary_is_smaller(&dictionary, &values) {
+                    Some(values.iter().map(|v| v.map_or(-1, |v| dictionary.insert(v))).collect::<Vec<i32>>())
+                } else {
+                    None
+                }
+            },
+            None => None
+        };
+        if let Some(codes) = codes {
+            write_le_values(&codes, &mut self.encoded_chunk_buffer);
+            return EncodedChunk(Encoding::Dictionary, NullsEncoding::Plain, &self.encoded_chunk_buffer);
+        }
+
+        write_le_values(&self.sizes, &mut self.encoded_chunk_buffer);
         self.encoded_chunk_buffer.write(&self.values).unwrap();
 
-        EncodedChunk(Encoding::Raw, &self.encoded_chunk_buffer)
+        EncodedChunk(Encoding::Raw, NullsEncoding::Plain, &self.encoded_chunk_buffer)
+    }
+
+    fn stats(&self) -> ChunkStats {
+        ChunkStats { min_max: None, float_min_max: None, null_count: self.sizes.iter().filter(|&&s| s < 0).count(), distinct_count: Some(self.distinct.estimate()) }
     }
 
     fn reset(&mut self) {
         self.sizes.clear();
         self.values.clear();
+        self.distinct.clear();
+    }
+
+    /// Only the sizes, the length of the values is not known in advance
+    fn reserve(&mut self, num_values: usize) {
+        self.sizes.reserve(num_values);
     }
 }
 
@@ -212,14 +533,27 @@ impl InsertionManager {
         StorageInserter::new(self.storage_lock.clone())
     }
 
+    /// Returns the storage once all the inserters have been dropped. Fails with
+    /// PendingInserters if there is still an inserter alive.
     pub fn finish_inserting(self) -> StorageResult<Storage> {
-        let mut storage = Arc::try_unwrap(self.storage_lock)
-            .ok().expect("Tried to finish inserting rows while there are pending insertions")
-            .into_inner().unwrap();
+        let mut storage = match Arc::try_unwrap(self.storage_lock) {
+            Ok(lock) => lock.into_inner().unwrap(),
+            Err(_) => return Err(StorageError::PendingInserters)
+        };
 
         try!(storage.write_footer());
         Ok(storage)
     }
+
+    /// Like finish_inserting, but returns the bytes of the whole storage. Only for in-memory
+    /// storages.
+    pub fn finish_to_bytes(self) -> StorageResult<Vec<u8>> {
+        let storage = try!(self.finish_inserting());
+        match storage.backend.into_bytes() {
+            Some(bytes) => Ok(bytes),
+            None => Err(StorageError::InvalidFormat("Only in-memory storages can be turned into bytes".to_string()))
+        }
+    }
 }
 
 // ----------------------------------------------------------------------------
@@ -240,7 +574,7 @@ impl StorageInserter
 
             let max_rows_in_stripe = Self::num_rows_in_stripe_hint(&storage);
             let chunk_generators: Vec<Box<ChunkGenerator>> = storage.columns().iter()
-                .map(|c| Self::get_chunk_generator_for_datatype(&c.datatype, max_rows_in_stripe))
+                .map(|c| Self::get_chunk_generator(c, storage.encoder(), max_rows_in_stripe))
                 .collect();
 
             (max_rows_in_stripe, chunk_generators)
@@ -254,11 +588,11 @@ impl StorageInserter
         }
     }
 
-    /// A hint for how many rows should fit in a storage stripe
+    /// A hint for how many rows should fit in a storage stripe, unless set for the storage
     fn num_rows_in_stripe_hint(storage: &Storage) -> usize {
-        let disk_block_size: usize = 4096;
-        // How many blocks in a stripe
-        let blocks_in_stripe: usize = 64;
+        if let Some(num_rows) = storage.rows_per_stripe() {
+            return num_rows;
+        }
 
         // Find, for all the numeric columns, the one with the biggest size.
         let max_size = storage.columns.iter()
@@ -266,44 +600,134 @@ impl StorageInserter
             .map(|c| c.datatype_info.value_size.unwrap())
             .max().unwrap_or(1);    // If there are no numeric colums, assume size 1
 
-        (blocks_in_stripe*disk_block_size) / max_size
+        Self::num_rows_for_value_size(max_size)
+    }
+
+    /// How many values of the given size fit in a stripe. Always at least one row.
+    fn num_rows_for_value_size(value_size: usize) -> usize {
+        let disk_block_size: usize = 4096;
+        // How many blocks in a stripe
+        let blocks_in_stripe: usize = 64;
+
+        cmp::max((blocks_in_stripe*disk_block_size) / cmp::max(value_size, 1), 1)
     }
 
 
-    fn get_chunk_generator_for_datatype(datatype: &ColumnDatatype, size: usize) -> Box<ChunkGenerator> {
-        match *datatype {
-            ColumnDatatype::Byte => Box::new(NumericChunkGenerator::<i8>::new(size)),
-            ColumnDatatype::Int32 => Box::new(NumericChunkGenerator::<i32>::new(size)),
-            ColumnDatatype::Int64 => Box::new(NumericChunkGenerator::<i64>::new(size)),
-            ColumnDatatype::Float => Box::new(NumericChunkGenerator::<f32>::new(size)),
-            ColumnDatatype::FixedLength(length) => Box::new(FixedLengthChunkGenerator::new(length, size)),
-            ColumnDatatype::VariableLength => Box::new(VariableLengthChunkGenerator::new(size)),
+    fn get_chunk_generator(column: &Column, encoder: Encoder, size: usize) -> Box<ChunkGenerator> {
+        match column.datatype {
+            ColumnDatatype::Byte => Box::new(NumericChunkGenerator::<i8>::new(size, encoder)),
+            ColumnDatatype::Int16 => Box::new(NumericChunkGenerator::<i16>::new(size, encoder)),
+            ColumnDatatype::Int32 => Box::new(NumericChunkGenerator::<i32>::new(size, encoder)),
+            ColumnDatatype::Int64 => Box::new(NumericChunkGenerator::<i64>::new(size, encoder)),
+            ColumnDatatype::Float => Box::new(NumericChunkGenerator::<f32>::new(size, encoder)),
+            ColumnDatatype::FixedLength(length) => Box::new(FixedLengthChunkGenerator::new(length, column.padding(), size)),
+            ColumnDatatype::VariableLength => Box::new(VariableLengthChunkGenerator::new(size, column.dictionary_mode(), column.shared_dictionary())),
+            ColumnDatatype::Timestamp => Box::new(TimestampChunkGenerator::new(size)),
         }
     }
 
-    pub fn enqueue_row(&mut self, row: &Vec<ColumnValue>) -> StorageResult<()> {
+    /// Makes room for the given number of rows, to avoid reallocations while enqueueing
+    /// them. Rows are written every stripe, so there is never room for more than a stripe.
+    pub fn reserve(&mut self, num_rows: usize) {
+        let num_rows = cmp::min(num_rows, self.max_rows_in_stripe);
+        let additional = num_rows.saturating_sub(self.enqueued_rows.len());
+        self.enqueued_rows.reserve(additional);
+        for chunk_generator in self.chunk_generators.iter_mut() {
+            chunk_generator.reserve(num_rows);
+        }
+    }
+
+    /// Runs the same checks as enqueue_row (number of columns, types, sizes and column checks)
+    /// without enqueueing the row
+    pub fn validate_row(&self, row: &Vec<ColumnValue>) -> StorageResult<()> {
+        let storage = self.storage.read().unwrap();
+
         // Validate number of columns
-        let expected = self.storage.read().unwrap().num_columns();
+        let expected = storage.num_columns();
         let got = row.len();
         if got != expected {
             return Err(StorageError::InvalidNumberOfColumns(got, expected))
         }
 
         // Make sure that all the values have the right types
-        for (chunk_generator, value) in self.chunk_generators.iter().zip(row.iter()) {
-            try!(chunk_generator.validate_value(value));
+        for (i, (chunk_generator, value)) in self.chunk_generators.iter().zip(row.iter()).enumerate() {
+            match chunk_generator.validate_value(value) {
+                Ok(()) => {},
+                Err(StorageError::TypeError) => {
+                    return Err(StorageError::TypeMismatch { column: i, expected: storage.column(i).datatype })
+                },
+                Err(e) => return Err(e)
+            }
+        }
+
+        // ...that NOT NULL columns have a value...
+        for (column, value) in storage.columns().iter().zip(row.iter()) {
+            if *value == ColumnValue::Null && !column.is_nullable() {
+                return Err(StorageError::NullNotAllowed(column.name.clone()))
+            }
+        }
+
+        // ...and that they are not too large
+        if let Some(limit) = storage.max_value_bytes() {
+            for (column, value) in storage.columns().iter().zip(row.iter()) {
+                match *value {
+                    ColumnValue::FixedLength(ref v) | ColumnValue::VariableLength(ref v) if v.len() > limit => {
+                        return Err(StorageError::ValueTooLarge { column: column.name.clone(), size: v.len(), limit: limit })
+                    },
+                    _ => {}
+                }
+            }
+        }
+
+        // Run the column checks
+        for (column, value) in storage.columns().iter().zip(row.iter()) {
+            if !column.check_value(value) {
+                return Err(StorageError::CheckFailed { column: column.name.clone() })
+            }
         }
 
+        Ok(())
+    }
+
+    pub fn enqueue_row(&mut self, row: &Vec<ColumnValue>) -> StorageResult<()> {
+        try!(self.validate_row(row));
+
         self.enqueued_rows.push(row.clone());
 
         if self.enqueued_rows.len() == self.max_rows_in_stripe {
-            self.flush()
+            self.flush(false)
         } else {
             Ok(())
         }
     }
 
-    fn flush(&mut self) -> StorageResult<()> {
+    /// Flushes the pending rows and returns the result of doing so. Dropping the inserter
+    /// also flushes, but any error is lost.
+    pub fn finish(mut self) -> StorageResult<()> {
+        let result = self.flush(false);
+        // Whatever happened, there is nothing left to flush when the inserter is dropped
+        self.enqueued_rows.clear();
+        result
+    }
+
+    /// Writes the enqueued rows as a (possibly partial) stripe right away, followed by a
+    /// footer, so that they are on disk and the storage can be opened by readers before
+    /// finish_inserting. The previous footer is left as unused bytes (see Storage::vacuum).
+    /// Does nothing if there are no enqueued rows.
+    pub fn flush_now(&mut self) -> StorageResult<()> {
+        self.flush(true)
+    }
+
+    /// Ends the current stripe at a logical boundary (e.g. a new day), so that no stripe mixes
+    /// rows of different groups and readers can skip whole groups using the chunk statistics.
+    /// It is flush_now, so each finished group can also be read before finish_inserting, at the
+    /// cost of a footer per group. It doesn't write empty stripes either.
+    pub fn end_stripe(&mut self) -> StorageResult<()> {
+        self.flush_now()
+    }
+
+    /// Writes the enqueued rows as a stripe, and then the footer if write_footer is set
+    fn flush(&mut self, write_footer: bool) -> StorageResult<()> {
         if self.enqueued_rows.len() == 0 {
             return Ok(())
         }
@@ -315,60 +739,100 @@ impl StorageInserter
         }
 
         // Write the chunks!
-        {
+        let result = {
             // Acquire write lock for storage
             let mut storage = self.storage.write().unwrap();
 
             {
+                let stats: Vec<ChunkStats> = self.chunk_generators.iter()
+                    .map(|gen| gen.stats())
+                    .collect();
                 let encoded_stripe: Vec<EncodedChunk> = self.chunk_generators.iter_mut()
                     .map(|gen| gen.get_encoded_chunk())
                     .collect();
 
-                try!(Self::append_stripe(&mut storage, self.enqueued_rows.len(), &encoded_stripe));
+                try!(Self::append_stripe(&mut storage, self.enqueued_rows.len(), &encoded_stripe, &stats));
             }
 
             for chunk_generator in self.chunk_generators.iter_mut() {
                 chunk_generator.reset();
             }
-        }
+
+            // The rows are in the stripe already, so they are not enqueued again if this fails
+            if write_footer { storage.write_footer() } else { Ok(()) }
+        };
 
         self.enqueued_rows.clear();
-        Ok(())
+        result
+    }
+
+    fn compress_chunk<'a>(compressor: Compressor, chunk: &EncodedChunk<'a>) -> CompressedChunk<'a> {
+        let EncodedChunk(encoding, _, bytes) = *chunk;
+        CompressedChunk(compressor.compression(), encoding, compressor.compress(bytes))
+    }
+
+    /// Compresses the chunks of a stripe, keeping the order of the columns
+    #[cfg(not(feature = "rayon"))]
+    fn compress_chunks<'a>(compressor: Compressor, stripe: &[EncodedChunk<'a>]) -> Vec<CompressedChunk<'a>> {
+        Self::compress_chunks_sequentially(compressor, stripe)
+    }
+
+    /// The chunks one after the other, which the parallel compression must match
+    #[cfg(any(test, not(feature = "rayon")))]
+    fn compress_chunks_sequentially<'a>(compressor: Compressor, stripe: &[EncodedChunk<'a>]) -> Vec<CompressedChunk<'a>> {
+        stripe.iter().map(|chunk| Self::compress_chunk(compressor, chunk)).collect()
+    }
+
+    /// Compresses the chunks of a stripe in parallel, keeping the order of the columns
+    #[cfg(feature = "rayon")]
+    fn compress_chunks<'a>(compressor: Compressor, stripe: &[EncodedChunk<'a>]) -> Vec<CompressedChunk<'a>> {
+        use rayon::prelude::*;
+        stripe.par_iter().map(|chunk| Self::compress_chunk(compressor, chunk)).collect()
     }
 
-    fn append_stripe(storage: &mut Storage, num_rows: usize, stripe: &Vec<EncodedChunk>) -> StorageResult<()> {
+    fn append_stripe(storage: &mut Storage, num_rows: usize, stripe: &Vec<EncodedChunk>, stats: &[ChunkStats]) -> StorageResult<()> {
         // No columns to insert? Weird...
         if stripe.len() == 0 { return Ok(()); }
 
         // Compress the chunks
-        //TODO
-        let compressed_chunks: Vec<CompressedChunk> = stripe.iter()
-            .map(|&EncodedChunk(encoding, chunk)| CompressedChunk(Compression::None, encoding, chunk))
-            .collect();
+        let compressed_chunks = Self::compress_chunks(storage.compressor(), stripe);
 
         // Calculate the size of the stripe. It is the sum of the sizes of the compressed chunks.
         // We cannot do this because of issue #27739 :(
-        //let stripe_size: usize = compressed_chunks.iter().map(|&CompressedChunk(_, _, c)| c.len()).sum();
-        let stripe_size: usize = compressed_chunks.iter().map(|&CompressedChunk(_, _, c)| c.len()).fold(0, |a, b| a + b);
+        //let stripe_size: usize = compressed_chunks.iter().map(|&CompressedChunk(_, _, ref c)| c.len()).sum();
+        let stripe_size: usize = compressed_chunks.iter().map(|&CompressedChunk(_, _, ref c)| c.len()).fold(0, |a, b| a + b);
 
-        // Get the current offset in the storage's backend
-        let stripe_header_absolute_offset = storage.backend.seek(io::SeekFrom::Current(0)).unwrap() as usize;
+        let mut crc = Crc32::new();
+        for &CompressedChunk(_, _, ref chunk) in compressed_chunks.iter() {
+            crc.update(chunk);
+        }
+
+        // Stripes are always appended at the end of the storage's backend. The writes are
+        // buffered so that small chunks don't reach the OS one by one.
+        let mut writer = BufWriter::new(try!(storage.backend.writer()));
+        let stripe_header_absolute_offset = try!(writer.seek(io::SeekFrom::End(0))) as usize;
 
         // Build the stripe header
         let mut stripe_header = proto_structs::StripeHeader {
             num_rows: num_rows,
             column_chunks: Vec::new(),
-            stripe_size: stripe_size
+            stripe_size: stripe_size,
+            crc32: crc.finish()
         };
 
         let mut relative_column_begin: usize = 0;
-        for (&CompressedChunk(compression, encoding, compressed_chunk), &EncodedChunk(_, encoded_chunk)) in compressed_chunks.iter().zip(stripe.iter()) {
+        for ((&CompressedChunk(compression, encoding, ref compressed_chunk), &EncodedChunk(_, nulls_encoding, encoded_chunk)), chunk_stats) in compressed_chunks.iter().zip(stripe.iter()).zip(stats.iter()) {
             stripe_header.column_chunks.push(proto_structs::ColumnChunkHeader {
                 relative_offset: relative_column_begin,
                 compressed_size: compressed_chunk.len(),
                 uncompressed_size: encoded_chunk.len(),
                 encoding: encoding,
+                nulls_encoding: nulls_encoding,
                 compression: compression,
+                min_max: chunk_stats.min_max,
+                float_min_max: chunk_stats.float_min_max,
+                null_count: chunk_stats.null_count,
+                distinct_count: chunk_stats.distinct_count
             });
 
             relative_column_begin += compressed_chunk.len();
@@ -381,17 +845,22 @@ impl StorageInserter
                 let mut header_builder = builder.init_root::<<proto_structs::StripeHeader as proto_structs::ProtocolBuildable>::Builder>();
                 stripe_header.build_message(&mut header_builder);
             }
-            try!(::capnp::serialize::write_message(&mut storage.backend, &builder));
+            try!(::capnp::serialize::write_message(&mut writer, &builder));
         }
 
         // Now write all the compressed columns
-        for &CompressedChunk(_, _, chunk) in compressed_chunks.iter() {
-            try!(storage.backend.write(chunk));
+        for &CompressedChunk(_, _, ref chunk) in compressed_chunks.iter() {
+            try!(writer.write_all(chunk));
         }
+        // Seeking flushes the buffer first
+        let stripe_end = try!(writer.seek(io::SeekFrom::Current(0))) as usize;
+        try!(writer.flush());
+        drop(writer);
 
         storage.append_stripe(&proto_structs::Stripe {
             absolute_offset: stripe_header_absolute_offset,
-            num_rows: num_rows
+            num_rows: num_rows,
+            size: stripe_end - stripe_header_absolute_offset
         });
 
         Ok(())
@@ -402,6 +871,150 @@ impl StorageInserter
 impl Drop for StorageInserter
 {
     fn drop(&mut self) {
-        self.flush().unwrap();
+        // Best effort only: use finish() to know whether the rows were actually written
+        self.flush(false).ok();
+    }
+}
+
+// ----------------------------------------------------------------------------
+#[cfg(test)]
+mod test {
+    use ::storage_inserter::StorageInserter;
+    use ::encoding::DictionaryMode;
+
+    #[test]
+    fn stripe_hint_is_at_least_one_row() {
+        assert_eq!(StorageInserter::num_rows_for_value_size(0), 64*4096);
+        assert_eq!(StorageInserter::num_rows_for_value_size(8), 64*4096/8);
+        assert_eq!(StorageInserter::num_rows_for_value_size(1 << 30), 1);
+    }
+
+    #[test]
+    fn nulls_round_trip_through_every_chunk_type() {
+        use ::storage::{ColumnDatatype, ColumnValue};
+        use ::storage_inserter::{ChunkGenerator, FixedLengthChunkGenerator, VariableLengthChunkGenerator, NumericChunkGenerator, EncodedChunk};
+        use ::storage_reader::{decode_chunk_nulls, decode_column_chunk};
+        use ::encoding::Encoder;
+
+        fn round_trip(datatype: ColumnDatatype, mut generator: Box<ChunkGenerator>, values: &[ColumnValue]) {
+            generator.append_values(&mut values.iter());
+            assert_eq!(generator.stats().null_count, values.iter().filter(|v| **v == ColumnValue::Null).count());
+
+            let EncodedChunk(_, _, chunk) = generator.get_encoded_chunk();
+            let nulls = decode_chunk_nulls(&datatype, values.len(), chunk).unwrap();
+            assert_eq!(nulls.iter().collect::<Vec<bool>>(), values.iter().map(|v| *v != ColumnValue::Null).collect::<Vec<bool>>());
+            assert_eq!(decode_column_chunk(&datatype, values.len(), chunk).unwrap(), values.to_vec());
+            assert!(decode_chunk_nulls(&datatype, values.len() + 1000, chunk).is_err());
+        }
+
+        let fixed = |b: u8| ColumnValue::FixedLength(vec!(b; 3));
+        round_trip(ColumnDatatype::FixedLength(3), Box::new(FixedLengthChunkGenerator::new(3, None, 8)),
+            &[ColumnValue::Null, fixed(1), fixed(2), ColumnValue::Null, ColumnValue::Null, fixed(3)]);
+
+        let variable = |s: &str| ColumnValue::VariableLength(s.into());
+        round_trip(ColumnDatatype::VariableLength, Box::new(VariableLengthChunkGenerator::new(8, DictionaryMode::None, None)),
+            &[variable(""), ColumnValue::Null, variable("abc"), ColumnValue::Null]);
+
+        round_trip(ColumnDatatype::Int32, Box::new(NumericChunkGenerator::<i32>::new(8, Encoder::Raw)),
+            &[ColumnValue::Int32(1), ColumnValue::Null, ColumnValue::Int32(-1)]);
+    }
+
+    #[test]
+    fn numeric_values_are_written_as_little_endian() {
+        use ::storage::ColumnValue;
+        use ::storage_inserter::{ChunkGenerator, NumericChunkGenerator, EncodedChunk};
+        use ::encoding::{Encoder, Encoding};
+
+        let mut generator = NumericChunkGenerator::<i32>::new(2, Encoder::Raw);
+        generator.append_values(&mut [ColumnValue::Int32(1), ColumnValue::Int32(0x01020304)].iter());
+        let EncodedChunk(encoding, _, chunk) = generator.get_encoded_chunk();
+        assert_eq!(encoding, Encoding::Raw);
+        // The values come after the nulls
+        assert_eq!(&chunk[chunk.len() - 8..], &[0x01, 0x00, 0x00, 0x00, 0x04, 0x03, 0x02, 0x01]);
+
+        let mut generator = NumericChunkGenerator::<f32>::new(1, Encoder::Raw);
+        generator.append_values(&mut [ColumnValue::Float(1.0)].iter());
+        let EncodedChunk(_, _, chunk) = generator.get_encoded_chunk();
+        assert_eq!(&chunk[chunk.len() - 4..], &[0x00, 0x00, 0x80, 0x3F]);
+    }
+
+    #[test]
+    fn variable_length_sizes_cannot_overflow() {
+        use std::i32;
+        use ::storage::{ColumnDatatype, ColumnValue};
+        use ::storage_inserter::{ChunkGenerator, VariableLengthChunkGenerator, EncodedChunk};
+        use ::storage_reader::decode_column_chunk;
+        use ::error::StorageError;
+
+        assert!(VariableLengthChunkGenerator::check_value_size(i32::MAX as usize).is_ok());
+        match VariableLengthChunkGenerator::check_value_size(i32::MAX as usize + 1) {
+            Err(StorageError::InvalidLength(size, limit)) => {
+                assert_eq!(size, i32::MAX as usize + 1);
+                assert_eq!(limit, i32::MAX as usize);
+            },
+            r => panic!("Expected InvalidLength, got {:?}", r)
+        }
+
+        let generator = VariableLengthChunkGenerator::new(1, DictionaryMode::None, None);
+        assert!(generator.validate_value(&ColumnValue::VariableLength(vec!(0u8; 1000))).is_ok());
+
+        // NULLs and empty values are different
+        let mut generator = VariableLengthChunkGenerator::new(2, DictionaryMode::None, None);
+        let values = [ColumnValue::Null, ColumnValue::VariableLength(Vec::new())];
+        generator.append_values(&mut values.iter());
+        let EncodedChunk(_, _, chunk) = generator.get_encoded_chunk();
+        assert_eq!(decode_column_chunk(&ColumnDatatype::VariableLength, 2, chunk).unwrap(), values.to_vec());
+    }
+
+    #[test]
+    fn capacity_can_be_reserved() {
+        use ::storage::{StorageBuilder, ColumnDatatype, ColumnValue};
+
+        let storage = StorageBuilder::new()
+            .column("id", ColumnDatatype::Int32)
+            .column("name", ColumnDatatype::VariableLength)
+            .rows_per_stripe(1000)
+            .in_memory().unwrap();
+
+        let mut insertion_manager = storage.begin_inserting();
+        {
+            let mut inserter = insertion_manager.create_inserter();
+            inserter.reserve(500);
+            let capacity = inserter.enqueued_rows.capacity();
+            assert!(capacity >= 500);
+
+            for i in 0..500 {
+                inserter.enqueue_row(&vec!(ColumnValue::Int32(i), ColumnValue::Null)).unwrap();
+            }
+            assert_eq!(inserter.enqueued_rows.capacity(), capacity);
+
+            // Never more than a stripe
+            inserter.reserve(1000000);
+            assert!(inserter.enqueued_rows.capacity() < 1000000);
+        }
+        let storage = insertion_manager.finish_inserting().unwrap();
+        assert_eq!(storage.num_rows(), 500);
+    }
+
+    #[test]
+    #[cfg(all(feature = "rayon", feature = "gzip"))]
+    fn parallel_compression_keeps_the_column_order() {
+        use ::encoding::Encoding;
+        use ::nulls_bitmap::NullsEncoding;
+        use ::compression::{self, Compressor};
+        use ::storage_inserter::EncodedChunk;
+
+        // Every chunk is different, so that chunks in the wrong column would be noticed
+        let chunks: Vec<Vec<u8>> = (0..20).map(|i| (0..100 + i * 37).map(|j| (j * (i + 1) % 251) as u8).collect()).collect();
+        let stripe: Vec<EncodedChunk> = chunks.iter().map(|c| EncodedChunk(Encoding::Raw, NullsEncoding::Plain, c)).collect();
+
+        let parallel = StorageInserter::compress_chunks(Compressor::Gzip(6), &stripe);
+        let sequential = StorageInserter::compress_chunks_sequentially(Compressor::Gzip(6), &stripe);
+        assert_eq!(parallel.len(), chunks.len());
+        for ((p, s), chunk) in parallel.iter().zip(sequential.iter()).zip(chunks.iter()) {
+            assert_eq!(p.2, s.2);
+            assert!(&p.2[..] != &chunk[..]);
+            assert_eq!(&compression::decompress(p.0, p.2.to_vec(), chunk.len()).unwrap(), chunk);
+        }
     }
 }
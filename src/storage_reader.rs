@@ -1,35 +1,892 @@
-/*
+use std::collections::hash_map::HashMap;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Cursor};
+use std::fs::File;
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::thread;
 use std::iter::Iterator;
+use std::mem;
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::vec;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use ::storage::Storage;
+use capnp::message::ReaderOptions;
+
+use ::storage_backend::{StorageBackend, BackendReader};
+use ::storage::{Storage, NumericValue, ColumnDatatype, DatatypeInfo, ColumnValue, Timestamp};
+use ::compression;
+use ::compression::Compression;
+use ::encoding;
+use ::encoding::{Encoding, GlobalDictionary};
+use ::checksum::Crc32;
+use ::error::{StorageError, StorageResult};
 use ::proto_structs;
+use ::proto_structs::ProtocolReadable;
+use ::storage_inserter::get_slice_bytes;
+use ::row::Row;
+use ::nulls_bitmap::{NullsBitmap, NullsEncoding};
 
-pub struct StorageStripeIterator<'a> {
-    current_stripe: usize,
-    storage: &'a Storage
+// ----------------------------------------------------------------------------
+/// Helper function, the inverse of storage_inserter's get_slice_bytes. The bytes are
+/// little-endian values, read one by one.
+pub fn get_slice_values<T>(bytes: &[u8]) -> StorageResult<Vec<T>>
+    where T: NumericValue + Copy
+{
+    let size = mem::size_of::<T>();
+    if bytes.len() % size != 0 {
+        return Err(StorageError::InvalidFormat(format!("Chunk size {} is not a multiple of the value size {}", bytes.len(), size)));
+    }
+
+    Ok(bytes.chunks(size).map(T::read_le).collect())
 }
 
-pub struct StripeReference<'a> {
+// ----------------------------------------------------------------------------
+/// Decodes a column chunk with the given number of values, the inverse of the
+/// storage_inserter's chunk generators
+pub fn decode_column_chunk(datatype: &ColumnDatatype, num_values: usize, chunk: &[u8]) -> StorageResult<Vec<ColumnValue>> {
+    match *datatype {
+        ColumnDatatype::Byte => decode_numeric_chunk::<i8>(num_values, chunk),
+        ColumnDatatype::Int16 => decode_numeric_chunk::<i16>(num_values, chunk),
+        ColumnDatatype::Int32 => decode_numeric_chunk::<i32>(num_values, chunk),
+        ColumnDatatype::Int64 => decode_numeric_chunk::<i64>(num_values, chunk),
+        ColumnDatatype::Float => decode_numeric_chunk::<f32>(num_values, chunk),
+        ColumnDatatype::FixedLength(size) => decode_fixed_length_chunk(size as usize, num_values, chunk),
+        ColumnDatatype::VariableLength => decode_variable_length_chunk(num_values, chunk),
+        ColumnDatatype::Timestamp => decode_numeric_chunk::<Timestamp>(num_values, chunk),
+    }
+}
+
+/// Which values of a decoded chunk are present, whatever the convention of its type: a
+/// bitmap for numeric chunks, a flag byte per value for fixed length chunks, and a size of -1
+/// for variable length chunks.
+pub fn decode_chunk_nulls(datatype: &ColumnDatatype, num_values: usize, chunk: &[u8]) -> StorageResult<NullsBitmap> {
+    let mut nulls = NullsBitmap::with_capacity(num_values);
+    match *datatype {
+        ColumnDatatype::FixedLength(_) => {
+            if chunk.len() < num_values {
+                return Err(invalid_chunk());
+            }
+            for &is_null in chunk[..num_values].iter() {
+                nulls.push(is_null == 0);
+            }
+        },
+        ColumnDatatype::VariableLength => {
+            let sizes_length = num_values*mem::size_of::<i32>();
+            if chunk.len() < sizes_length {
+                return Err(invalid_chunk());
+            }
+            for size in try!(get_slice_values::<i32>(&chunk[..sizes_length])) {
+                nulls.push(size >= 0);
+            }
+        },
+        _ => {
+            let bitmap_size = num_values.div_ceil(8);
+            if chunk.len() < bitmap_size {
+                return Err(invalid_chunk());
+            }
+            nulls = NullsBitmap::from_raw_bits(&chunk[..bitmap_size], num_values);
+        }
+    }
+
+    Ok(nulls)
+}
+
+/// Replaces the NULL runs at the beginning of a numeric chunk by the equivalent bitmap
+pub fn expand_null_runs(num_values: usize, chunk: &[u8]) -> StorageResult<Vec<u8>> {
+    let mut values = chunk;
+    let nulls = try!(NullsBitmap::read_runs(&mut values, num_values));
+
+    let mut expanded = Vec::with_capacity(nulls.get_raw_bits().len() + values.len());
+    expanded.extend_from_slice(nulls.get_raw_bits());
+    expanded.extend_from_slice(values);
+    Ok(expanded)
+}
+
+/// Undoes the encoding of a chunk, so that it has the raw layout expected by decode_column_chunk.
+/// Only the values of numeric chunks are encoded, not the nulls bitmap before them.
+pub fn decode_chunk_encoding(encoding: Encoding, datatype: &ColumnDatatype, num_values: usize, mut chunk: Vec<u8>) -> StorageResult<Vec<u8>> {
+    if let Encoding::Raw = encoding {
+        return Ok(chunk);
+    }
+
+    let info = DatatypeInfo::new(datatype);
+    let value_size = match info.value_size {
+        Some(size) if info.is_numeric => size,
+        _ => return Err(StorageError::InvalidFormat(format!("Unsupported encoding for a {:?} chunk", datatype)))
+    };
+
+    let bitmap_size = num_values.div_ceil(8);
+    if chunk.len() < bitmap_size {
+        return Err(invalid_chunk());
+    }
+
+    let values = match encoding {
+        Encoding::DeltaOfDelta if value_size == 8 => {
+            let values = try!(encoding::decode_delta_of_delta(&chunk[bitmap_size..]));
+            get_slice_bytes(&values)
+        },
+        Encoding::RLE => try!(encoding::decode_rle(&chunk[bitmap_size..], value_size, num_values)),
+        Encoding::Delta => {
+            let values = try!(encoding::decode_delta(&chunk[bitmap_size..]));
+            if values.len() != num_values {
+                return Err(invalid_chunk());
+            }
+            try!(integers_to_bytes(datatype, &values))
+        },
+        Encoding::FrameOfReference(base) => {
+            let values = try!(encoding::decode_frame_of_reference(&chunk[bitmap_size..], base, num_values));
+            try!(integers_to_bytes(datatype, &values))
+        },
+        _ => return Err(StorageError::InvalidFormat("Unsupported chunk encoding".to_string()))
+    };
+
+    chunk.truncate(bitmap_size);
+    chunk.extend_from_slice(&values);
+    Ok(chunk)
+}
+
+/// Replaces the codes of a variable length chunk by the values of the dictionary, giving
+/// the layout of raw chunks
+pub fn decode_dictionary_codes(dictionary: &GlobalDictionary, num_values: usize, chunk: &[u8]) -> StorageResult<Vec<u8>> {
+    let codes = try!(get_slice_values::<i32>(chunk));
+    if codes.len() != num_values {
+        return Err(invalid_chunk());
+    }
+
+    let mut values = Vec::new();
+    let mut sizes = Vec::with_capacity(num_values);
+    for &code in codes.iter() {
+        if code < 0 {
+            sizes.push(-1);
+            continue;
+        }
+        match dictionary.value(code) {
+            Some(value) => {
+                sizes.push(value.len() as i32);
+                values.extend_from_slice(value);
+            },
+            None => return Err(StorageError::InvalidFormat(format!("Code {} is not in the dictionary", code)))
+        }
+    }
+
+    let mut decoded = get_slice_bytes(&sizes);
+    decoded.extend_from_slice(&values);
+    Ok(decoded)
+}
+
+/// The raw bytes of decoded integers, narrowed to the type of the column
+fn integers_to_bytes(datatype: &ColumnDatatype, values: &[i64]) -> StorageResult<Vec<u8>> {
+    Ok(match *datatype {
+        ColumnDatatype::Byte => get_slice_bytes(&values.iter().map(|&v| v as i8).collect::<Vec<i8>>()),
+        ColumnDatatype::Int16 => get_slice_bytes(&values.iter().map(|&v| v as i16).collect::<Vec<i16>>()),
+        ColumnDatatype::Int32 => get_slice_bytes(&values.iter().map(|&v| v as i32).collect::<Vec<i32>>()),
+        ColumnDatatype::Int64 | ColumnDatatype::Timestamp => get_slice_bytes(values),
+        _ => return Err(StorageError::InvalidFormat(format!("Unsupported encoding for a {:?} chunk", datatype)))
+    })
+}
+
+fn invalid_chunk() -> StorageError {
+    StorageError::InvalidFormat("Chunk does not match its number of values".to_string())
+}
+
+/// Decodes a numeric chunk: a nulls bitmap followed by the values. None for NULL values.
+pub fn decode_numeric_values<N>(num_values: usize, chunk: &[u8]) -> StorageResult<Vec<Option<N>>>
+    where N: NumericValue + Copy
+{
+    let bitmap_size = num_values.div_ceil(8);
+    if chunk.len() < bitmap_size {
+        return Err(invalid_chunk());
+    }
+
+    let (bits, values) = chunk.split_at(bitmap_size);
+    let values = try!(get_slice_values::<N>(values));
+    if values.len() != num_values {
+        return Err(invalid_chunk());
+    }
+
+    let nulls = NullsBitmap::from_raw_bits(bits, num_values);
+    Ok(nulls.iter().zip(values.into_iter())
+        .map(|(has_value, v)| if has_value { Some(v) } else { None })
+        .collect())
+}
+
+fn decode_numeric_chunk<N>(num_values: usize, chunk: &[u8]) -> StorageResult<Vec<ColumnValue>>
+    where N: NumericValue + Copy
+{
+    let values = try!(decode_numeric_values::<N>(num_values, chunk));
+    Ok(values.into_iter()
+        .map(|v| v.map_or(ColumnValue::Null, N::into_column_value))
+        .collect())
+}
+
+fn decode_fixed_length_chunk(value_size: usize, num_values: usize, chunk: &[u8]) -> StorageResult<Vec<ColumnValue>> {
+    let values = try!(fixed_length_slices(value_size, num_values, chunk));
+    Ok(values.into_iter()
+        .map(|v| v.map_or(ColumnValue::Null, |v| ColumnValue::FixedLength(v.to_vec())))
+        .collect())
+}
+
+fn decode_variable_length_chunk(num_values: usize, chunk: &[u8]) -> StorageResult<Vec<ColumnValue>> {
+    let values = try!(variable_length_slices(num_values, chunk));
+    Ok(values.into_iter()
+        .map(|v| v.map_or(ColumnValue::Null, |v| ColumnValue::VariableLength(v.to_vec())))
+        .collect())
+}
+
+/// A null flag (one byte) for each value, followed by the non-null values
+fn fixed_length_slices(value_size: usize, num_values: usize, chunk: &[u8]) -> StorageResult<Vec<Option<&[u8]>>> {
+    if chunk.len() < num_values {
+        return Err(invalid_chunk());
+    }
+
+    let (nulls, mut data) = chunk.split_at(num_values);
+    let mut values = Vec::with_capacity(num_values);
+    for &is_null in nulls.iter() {
+        if is_null != 0 {
+            values.push(None);
+        } else {
+            if data.len() < value_size {
+                return Err(invalid_chunk());
+            }
+            values.push(Some(&data[..value_size]));
+            data = &data[value_size..];
+        }
+    }
+
+    Ok(values)
+}
+
+/// The size of each value (-1 for NULL), followed by the concatenated values
+fn variable_length_slices(num_values: usize, chunk: &[u8]) -> StorageResult<Vec<Option<&[u8]>>> {
+    let sizes_length = num_values*mem::size_of::<i32>();
+    if chunk.len() < sizes_length {
+        return Err(invalid_chunk());
+    }
+
+    let sizes = try!(get_slice_values::<i32>(&chunk[..sizes_length]));
+    let mut data = &chunk[sizes_length..];
+    let mut values = Vec::with_capacity(num_values);
+    for size in sizes {
+        if size < 0 {
+            values.push(None);
+        } else {
+            let size = size as usize;
+            if data.len() < size {
+                return Err(invalid_chunk());
+            }
+            values.push(Some(&data[..size]));
+            data = &data[size..];
+        }
+    }
+
+    Ok(values)
+}
+
+// ----------------------------------------------------------------------------
+/// A single column chunk, read, decompressed and decoded from its header. The stripe readers
+/// are built on it, and it can be used to write custom scanners: the chunk headers come from
+/// StripeReference::get_header, and the chunks start at StripeReference::data_offset.
+pub struct ChunkReader {
+    datatype: ColumnDatatype,
+    num_values: usize,
+    chunk: Vec<u8>
+}
+
+impl ChunkReader {
+    /// Reads the chunk with the given header and number of values, from the chunks of a
+    /// stripe that begin at data_offset. Chunks encoded with a dictionary need the one of
+    /// their column (see Column::dictionary).
+    pub fn read(reader: &mut BackendReader, data_offset: u64, chunk_header: &proto_structs::ColumnChunkHeader,
+                datatype: &ColumnDatatype, dictionary: Option<&GlobalDictionary>, num_values: usize) -> StorageResult<ChunkReader> {
+        let chunk = try!(read_chunk_bytes(reader, data_offset, chunk_header, datatype, dictionary, num_values, &BufferPool::new()));
+        Ok(ChunkReader { datatype: *datatype, num_values: num_values, chunk: chunk })
+    }
+
+    pub fn datatype(&self) -> &ColumnDatatype { &self.datatype }
+    pub fn num_values(&self) -> usize { self.num_values }
+
+    /// The decoded bytes of the chunk, laid out like a raw chunk
+    pub fn bytes(&self) -> &[u8] { &self.chunk }
+
+    /// The values of a numeric chunk, None for NULL values. Fails if T is not the type of
+    /// the chunk.
+    pub fn numeric_values<T>(&self) -> StorageResult<vec::IntoIter<Option<T>>>
+        where T: NumericValue + Copy
+    {
+        if self.datatype != T::datatype() {
+            return Err(StorageError::TypeError);
+        }
+        Ok(try!(decode_numeric_values::<T>(self.num_values, &self.chunk)).into_iter())
+    }
+
+    /// The values of a fixed or variable length chunk, None for NULL values, borrowed from
+    /// the chunk
+    pub fn byte_values(&self) -> StorageResult<vec::IntoIter<Option<&[u8]>>> {
+        let values = match self.datatype {
+            ColumnDatatype::FixedLength(size) => try!(fixed_length_slices(size as usize, self.num_values, &self.chunk)),
+            ColumnDatatype::VariableLength => try!(variable_length_slices(self.num_values, &self.chunk)),
+            _ => return Err(StorageError::TypeError)
+        };
+        Ok(values.into_iter())
+    }
+
+    /// The values of the chunk, whatever its type
+    pub fn values(&self) -> StorageResult<Vec<ColumnValue>> {
+        decode_column_chunk(&self.datatype, self.num_values, &self.chunk)
+    }
+}
+
+/// Reads, decompresses and decodes a chunk, into buffers taken from the pool
+fn read_chunk_bytes(reader: &mut BackendReader, data_offset: u64, chunk_header: &proto_structs::ColumnChunkHeader,
+                    datatype: &ColumnDatatype, dictionary: Option<&GlobalDictionary>, num_values: usize,
+                    buffers: &BufferPool) -> StorageResult<Vec<u8>> {
+    let mut stored = buffers.take();
+    stored.resize(chunk_header.compressed_size, 0);
+
+    try!(reader.seek(SeekFrom::Start(data_offset + chunk_header.relative_offset as u64)));
+    try!(reader.read_exact(&mut stored));
+
+    let mut chunk = if chunk_header.compression == Compression::None {
+        try!(compression::decompress(chunk_header.compression, stored, chunk_header.uncompressed_size))
+    } else {
+        let mut decompressed = buffers.take();
+        let result = compression::decompress_into(chunk_header.compression, &stored, chunk_header.uncompressed_size, &mut decompressed);
+        buffers.give_back(stored);
+        try!(result);
+        decompressed
+    };
+
+    if chunk_header.nulls_encoding == NullsEncoding::RunLength {
+        if !DatatypeInfo::new(datatype).is_numeric {
+            return Err(StorageError::InvalidFormat(format!("Unsupported NULL runs in a {:?} chunk", datatype)));
+        }
+        let expanded = try!(expand_null_runs(num_values, &chunk));
+        buffers.give_back(mem::replace(&mut chunk, expanded));
+    }
+
+    if chunk_header.encoding == Encoding::Dictionary {
+        let dictionary = match dictionary {
+            Some(d) if *datatype == ColumnDatatype::VariableLength => d,
+            _ => return Err(StorageError::InvalidFormat(format!("Chunk is encoded with a dictionary, but its {:?} column has none", datatype)))
+        };
+        let decoded = try!(decode_dictionary_codes(dictionary, num_values, &chunk));
+        buffers.give_back(chunk);
+        return Ok(decoded);
+    }
+
+    decode_chunk_encoding(chunk_header.encoding, datatype, num_values, chunk)
+}
+
+// ----------------------------------------------------------------------------
+/// SplitMix64: a small, seedable pseudo-random generator, good enough for sampling
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number in [0, bound), with a negligible bias for the bounds used here
+    fn below(&mut self, bound: usize) -> usize { (self.next_u64() % bound as u64) as usize }
+}
+
+// ----------------------------------------------------------------------------
+/// Reads back an existing storage
+pub struct StorageReader {
+    storage: Storage
+}
+
+impl StorageReader {
+    pub fn new(storage: Storage) -> StorageReader {
+        StorageReader { storage: storage }
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> StorageResult<StorageReader> {
+        Ok(StorageReader::new(try!(Storage::open(path))))
+    }
+
+    /// Reads a storage from its bytes, e.g. the ones returned by InsertionManager::finish_to_bytes
+    pub fn from_bytes(bytes: Vec<u8>) -> StorageResult<StorageReader> {
+        Ok(StorageReader::new(try!(Storage::load(StorageBackend::from_existing_memory(bytes)))))
+    }
+
+    pub fn storage(&self) -> &Storage { &self.storage }
+
+    /// Checks that the storage has exactly the expected columns, in the same order. The
+    /// error names the first column that doesn't match.
+    pub fn verify_schema(&self, expected: &[(&str, ColumnDatatype)]) -> StorageResult<()> {
+        for (i, &(name, datatype)) in expected.iter().enumerate() {
+            let column = match self.storage.columns().get(i) {
+                Some(c) => c,
+                None => return Err(StorageError::InvalidFormat(format!("Missing column {} '{}'", i, name)))
+            };
+
+            if column.name != name {
+                return Err(StorageError::InvalidFormat(format!("Column {} is '{}', expected '{}'", i, column.name, name)));
+            }
+            if column.datatype != datatype {
+                return Err(StorageError::InvalidFormat(format!("Column '{}' is {}, expected {}", name, column.datatype, datatype)));
+            }
+        }
+
+        if let Some(column) = self.storage.columns().get(expected.len()) {
+            return Err(StorageError::InvalidFormat(format!("Unexpected column {} '{}'", expected.len(), column.name)));
+        }
+
+        Ok(())
+    }
+
+    /// Reads every stripe and checks its checksum, instead of only the stripes that are
+    /// decoded. Fails with ChecksumMismatch for the first stripe that doesn't match.
+    pub fn verify_integrity(&self) -> StorageResult<()> {
+        for stripe in self.stripes_iter() {
+            let (header, data_offset) = try!(stripe.read_header());
+            try!(stripe.verify_checksum(&header, data_offset));
+        }
+        Ok(())
+    }
+
+    /// The number of rows according to the stripe index. No stripe is read.
+    pub fn row_count(&self) -> usize {
+        self.storage.stripes().iter().map(|s| s.num_rows).fold(0, |a, b| a + b)
+    }
+
+    pub fn stripe_count(&self) -> usize { self.storage.stripes().len() }
+    pub fn stripes_iter(&self) -> StorageStripeIterator<'_> { self.storage.stripes_iter() }
+
+    /// Decodes all the columns of a stripe
+    pub fn stripe(&self, index: usize) -> StorageResult<StripeColumns> {
+        let stripe = match StorageStripeIterator::new(&self.storage).nth(index) {
+            Some(s) => s,
+            None => return Err(StorageError::InvalidStripeIndex(index))
+        };
+
+        let columns = try!(stripe.read_columns()).into_iter()
+            .map(|values| {
+                let mut nulls = NullsBitmap::with_capacity(values.len());
+                for value in values.iter() {
+                    nulls.push(*value != ColumnValue::Null);
+                }
+                DecodedColumn { values: values, nulls: nulls }
+            })
+            .collect();
+
+        Ok(StripeColumns { num_rows: stripe.num_rows(), columns: columns })
+    }
+
+    /// Reads a single row, decoding only the stripe that holds it
+    pub fn get_row(&self, index: usize) -> StorageResult<Vec<ColumnValue>> {
+        let mut rows = try!(self.storage.read_rows(&[index]));
+        Ok(rows.pop().unwrap())
+    }
+
+    /// Scans the values of a single numeric column, stripe by stripe. None is returned for
+    /// NULL values. Fails if T is not the type of the column.
+    pub fn column_cursor<T>(&self, name: &str) -> StorageResult<ColumnCursor<'_, T>>
+        where T: NumericValue + PartialEq + Copy
+    {
+        let column = match self.storage.column_by_name(name) {
+            Some(c) => c,
+            None => return Err(StorageError::InvalidFormat(format!("Unknown column '{}'", name)))
+        };
+
+        if column.datatype != T::datatype() {
+            return Err(StorageError::TypeError);
+        }
+
+        Ok(ColumnCursor {
+            stripes: StorageStripeIterator::new(&self.storage),
+            num_column: column.num_column_in_storage(),
+            values: Vec::new().into_iter(),
+            failed: false
+        })
+    }
+
+    /// Iterates over all the rows, decoding one stripe at a time
+    pub fn rows(&self) -> RowIterator<'_> {
+        RowIterator {
+            stripes: StorageStripeIterator::new(&self.storage),
+            projection: None,
+            rows: Vec::new().into_iter(),
+            failed: false
+        }
+    }
+
+    /// Passes every row to f, decoding one stripe at a time and reusing the same row buffer,
+    /// so that the rows are never collected. f stops the scan by returning Break. Returns the
+    /// number of rows passed to f.
+    pub fn for_each_row<F>(&self, mut f: F) -> StorageResult<usize>
+        where F: FnMut(&[ColumnValue]) -> ControlFlow<()>
+    {
+        let mut row = Vec::with_capacity(self.storage.num_columns());
+        let mut num_rows = 0;
+        for stripe in StorageStripeIterator::new(&self.storage) {
+            let mut columns: Vec<vec::IntoIter<ColumnValue>> = try!(stripe.read_columns()).into_iter()
+                .map(|values| values.into_iter())
+                .collect();
+
+            for _ in 0..stripe.num_rows() {
+                row.clear();
+                for column in columns.iter_mut() {
+                    match column.next() {
+                        Some(value) => row.push(value),
+                        None => return Err(invalid_chunk())
+                    }
+                }
+
+                num_rows += 1;
+                if let ControlFlow::Break(()) = f(&row) {
+                    return Ok(num_rows);
+                }
+            }
+        }
+
+        Ok(num_rows)
+    }
+
+    /// Up to n rows chosen uniformly at random, with reservoir sampling during a single
+    /// scan, so only the sampled rows are kept in memory. The rows are in no particular
+    /// order. See sample_with_seed for reproducible samples.
+    pub fn sample(&self, n: usize) -> StorageResult<Vec<Vec<ColumnValue>>> {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+        self.sample_with_seed(n, seed)
+    }
+
+    /// Like sample, but the same seed always picks the same rows of the same storage
+    pub fn sample_with_seed(&self, n: usize, seed: u64) -> StorageResult<Vec<Vec<ColumnValue>>> {
+        let mut reservoir: Vec<Vec<ColumnValue>> = Vec::with_capacity(n);
+        if n == 0 {
+            return Ok(reservoir);
+        }
+
+        let mut random = SplitMix64(seed);
+        let mut num_seen = 0;
+        try!(self.for_each_row(|row| {
+            num_seen += 1;
+            if reservoir.len() < n {
+                reservoir.push(row.to_vec());
+            } else {
+                let i = random.below(num_seen);
+                if i < n {
+                    reservoir[i] = row.to_vec();
+                }
+            }
+            ControlFlow::Continue(())
+        }));
+
+        Ok(reservoir)
+    }
+
+    /// Like rows, but the next stripe is read in the background while the current one is
+    /// decoded. Only file storages are prefetched, the rows are the same.
+    pub fn rows_prefetching(&self) -> RowIterator<'_> {
+        RowIterator {
+            stripes: StorageStripeIterator::prefetching(&self.storage),
+            projection: None,
+            rows: Vec::new().into_iter(),
+            failed: false
+        }
+    }
+
+    /// Iterates over all the rows, as maps from column name to value
+    pub fn rows_as_map<'a>(&'a self) -> impl Iterator<Item=StorageResult<HashMap<String, ColumnValue>>> + 'a {
+        let names: Vec<String> = self.storage.columns().iter().map(|c| c.name.clone()).collect();
+        self.rows().map(move |row| row.map(|row| names.iter().cloned().zip(row.into_iter()).collect()))
+    }
+
+    /// Iterates over the rows as typed rows. Columns are matched to the fields by name, so
+    /// the storage can have other columns too.
+    pub fn typed_rows<'a, R: Row + 'a>(&'a self) -> StorageResult<impl Iterator<Item=StorageResult<R>> + 'a> {
+        let schema = R::schema();
+        let names: Vec<&str> = schema.iter().map(|&(name, _, _)| name).collect();
+        let rows = try!(self.project(&names));
+        Ok(rows.map(|row| row.and_then(|row| R::from_row(&row))))
+    }
+
+    /// Iterates over the rows, with only the given columns (in that order). The chunks of
+    /// the other columns are skipped, so their checksums are not verified.
+    pub fn project(&self, names: &[&str]) -> StorageResult<RowIterator<'_>> {
+        let mut projection = Vec::with_capacity(names.len());
+        for name in names.iter() {
+            match self.storage.column_index(name) {
+                Some(i) => projection.push(i),
+                None => return Err(StorageError::InvalidFormat(format!("Unknown column '{}'", name)))
+            }
+        }
+
+        Ok(RowIterator {
+            stripes: StorageStripeIterator::new(&self.storage),
+            projection: Some(projection),
+            rows: Vec::new().into_iter(),
+            failed: false
+        })
+    }
+}
+
+// ----------------------------------------------------------------------------
+/// Iterates over the rows of a storage. Errors reading a stripe are returned as Err items,
+/// after which the iteration stops.
+pub struct RowIterator<'a> {
+    stripes: StorageStripeIterator<'a>,
+    /// The columns to read, or None for all of them
+    projection: Option<Vec<usize>>,
+    rows: vec::IntoIter<Vec<ColumnValue>>,
+    failed: bool
+}
+
+/// Turns the decoded columns of a stripe into rows
+fn columns_to_rows(columns: Vec<Vec<ColumnValue>>) -> Vec<Vec<ColumnValue>> {
+    let num_rows = columns.first().map_or(0, |c| c.len());
+    let mut rows: Vec<Vec<ColumnValue>> = (0..num_rows).map(|_| Vec::with_capacity(columns.len())).collect();
+    for column in columns.into_iter() {
+        for (row, value) in rows.iter_mut().zip(column.into_iter()) {
+            row.push(value);
+        }
+    }
+
+    rows
+}
+
+impl<'a> Iterator for RowIterator<'a> {
+    type Item = StorageResult<Vec<ColumnValue>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(row) = self.rows.next() {
+                return Some(Ok(row));
+            }
+
+            if self.failed {
+                return None;
+            }
+
+            // Decode the next stripe
+            let stripe = match self.stripes.next() {
+                Some(s) => s,
+                None => return None
+            };
+
+            let columns = match self.projection {
+                Some(ref projection) => stripe.read_selected_columns(projection),
+                None => stripe.read_columns()
+            };
+
+            match columns {
+                Ok(columns) => self.rows = columns_to_rows(columns).into_iter(),
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+/// The values of a column in a stripe, and which of them are not NULL
+pub struct DecodedColumn {
+    pub values: Vec<ColumnValue>,
+    pub nulls: NullsBitmap
+}
+
+/// All the columns of a stripe, in the order of the schema
+pub struct StripeColumns {
+    pub num_rows: usize,
+    pub columns: Vec<DecodedColumn>
+}
+
+/// The values of a numeric chunk as stored, see StripeReference::read_raw_values. NULL
+/// values hold a placeholder, so they must be skipped using the bitmap.
+pub struct RawValues<T> {
+    pub values: Vec<T>,
+    pub nulls: NullsBitmap
+}
+
+// ----------------------------------------------------------------------------
+/// Iterates over the values of a numeric column. Errors reading a stripe are returned
+/// as Err items, after which the iteration stops.
+pub struct ColumnCursor<'a, T> {
+    stripes: StorageStripeIterator<'a>,
+    num_column: usize,
+    values: vec::IntoIter<Option<T>>,
+    failed: bool
+}
+
+impl<'a, T> Iterator for ColumnCursor<'a, T>
+    where T: NumericValue + PartialEq + Copy
+{
+    type Item = StorageResult<Option<T>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(value) = self.values.next() {
+                return Some(Ok(value));
+            }
+
+            if self.failed {
+                return None;
+            }
+
+            // Decode the next stripe
+            let stripe = match self.stripes.next() {
+                Some(s) => s,
+                None => return None
+            };
+
+            match stripe.read_numeric_values::<T>(self.num_column) {
+                Ok(values) => self.values = values.into_iter(),
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+/// At most this many buffers are kept for reuse. Chunks are read one at a time, so only a
+/// couple of them are in use at once.
+const MAX_POOLED_BUFFERS: usize = 4;
+
+/// Chunk buffers that are reused instead of allocating new ones for each chunk read. It is
+/// shared by a stripe iterator and the stripes it returns, so it lasts for a whole scan.
+struct BufferPool {
+    buffers: RefCell<Vec<Vec<u8>>>
+}
+
+impl BufferPool {
+    fn new() -> BufferPool {
+        BufferPool { buffers: RefCell::new(Vec::new()) }
+    }
+
+    /// An empty buffer, with the capacity of a previous chunk if there is one
+    fn take(&self) -> Vec<u8> {
+        self.buffers.borrow_mut().pop().unwrap_or_else(Vec::new)
+    }
+
+    fn give_back(&self, mut buffer: Vec<u8>) {
+        let mut buffers = self.buffers.borrow_mut();
+        if buffers.len() < MAX_POOLED_BUFFERS {
+            buffer.clear();
+            buffers.push(buffer);
+        }
+    }
+
+    #[cfg(test)]
+    fn capacities(&self) -> Vec<usize> {
+        self.buffers.borrow().iter().map(|b| b.capacity()).collect()
+    }
+}
+
+// ----------------------------------------------------------------------------
+/// At most this many stripes are read ahead by a prefetching stripe iterator
+const PREFETCHED_STRIPES: usize = 1;
+
+/// The bytes of each stripe (header and chunks) in order, read by a background thread. None
+/// for stripes of unknown size, or if reading them failed: those are read as usual.
+type PrefetchedStripes = Receiver<Option<Vec<u8>>>;
+
+/// Starts reading the stripes of a file storage in a background thread. Other backends are
+/// already in memory (or can't be read), so nothing is prefetched for them.
+fn prefetch_stripes(storage: &Storage) -> Option<PrefetchedStripes> {
+    let path = match storage.backend {
+        StorageBackend::File(ref path) => path.clone(),
+        _ => return None
+    };
+
+    let stripes: Vec<(u64, usize)> = storage.stripes().iter().map(|s| (s.absolute_offset as u64, s.size)).collect();
+    let (sender, receiver) = sync_channel(PREFETCHED_STRIPES);
+    thread::spawn(move || {
+        let mut file = File::open(&path).ok();
+        for (offset, size) in stripes {
+            let bytes = match file {
+                Some(ref mut file) if size > 0 => {
+                    let mut bytes = vec!(0; size);
+                    file.seek(SeekFrom::Start(offset)).and_then(|_| file.read_exact(&mut bytes)).ok().map(|_| bytes)
+                },
+                _ => None
+            };
+
+            // The iterator was dropped
+            if sender.send(bytes).is_err() {
+                return;
+            }
+        }
+    });
+
+    Some(receiver)
+}
+
+/// Reads from the prefetched bytes of a stripe as if they were the whole storage
+struct PrefetchedStripeReader<'a> {
+    bytes: Cursor<&'a [u8]>,
+    /// The offset of the stripe in the storage
+    offset: u64
+}
+
+impl<'a> Read for PrefetchedStripeReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.bytes.read(buf) }
+}
+
+impl<'a> Seek for PrefetchedStripeReader<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(p) if p >= self.offset => try!(self.bytes.seek(SeekFrom::Start(p - self.offset))),
+            SeekFrom::Current(delta) => try!(self.bytes.seek(SeekFrom::Current(delta))),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Seek outside of the prefetched stripe"))
+        };
+        Ok(position + self.offset)
+    }
+}
+
+// ----------------------------------------------------------------------------
+pub struct StorageStripeIterator<'a> {
+    current_stripe: usize,
     storage: &'a Storage,
-    stripe: proto_structs::Stripe
+    buffers: Rc<BufferPool>,
+    prefetched: Option<PrefetchedStripes>
 }
 
-/*
-impl<'a> StripeReference<'a> {
-    pub fn get_header(&mut self)
+impl<'a> StorageStripeIterator<'a> {
+    pub fn new(storage: &'a Storage) -> StorageStripeIterator<'a> {
+        StorageStripeIterator {
+            current_stripe: 0,
+            storage: storage,
+            buffers: Rc::new(BufferPool::new()),
+            prefetched: None
+        }
+    }
+
+    /// Like new, but a background thread reads the next stripe while the current one is
+    /// decoded, so that I/O and decoding overlap. Only file storages are prefetched.
+    pub fn prefetching(storage: &'a Storage) -> StorageStripeIterator<'a> {
+        let mut stripes = Self::new(storage);
+        stripes.prefetched = prefetch_stripes(storage);
+        stripes
+    }
 }
-*/
 
 impl<'a> Iterator for StorageStripeIterator<'a> {
     type Item = StripeReference<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let result = if self.current_stripe >= self.storage.stripes.len() {
+        let result = if self.current_stripe >= self.storage.stripes().len() {
             None
         } else {
             Some(StripeReference {
                 storage: self.storage,
-                stripe: self.storage.stripes[self.current_stripe].clone()
+                num_stripe: self.current_stripe,
+                stripe: self.storage.stripes()[self.current_stripe].clone(),
+                buffers: self.buffers.clone(),
+                prefetched: self.prefetched.as_ref().and_then(|p| p.recv().ok()).and_then(|bytes| bytes)
             })
         };
 
@@ -37,4 +894,493 @@ impl<'a> Iterator for StorageStripeIterator<'a> {
         result
     }
 }
-*/
+
+// ----------------------------------------------------------------------------
+/// The size of a column chunk in the storage and once read, see
+/// StripeReference::compression_summary
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkSizes {
+    pub column: String,
+    /// Bytes taken by the chunk in the storage, after encoding and compression
+    pub stored_size: usize,
+    /// Bytes taken by the chunk once decompressed and decoded
+    pub decoded_size: usize
+}
+
+impl ChunkSizes {
+    /// How many times smaller the chunk is in the storage than once decoded
+    pub fn compression_rate(&self) -> f64 {
+        if self.stored_size == 0 {
+            return 1.0;
+        }
+        self.decoded_size as f64 / self.stored_size as f64
+    }
+}
+
+// ----------------------------------------------------------------------------
+pub struct StripeReference<'a> {
+    storage: &'a Storage,
+    num_stripe: usize,
+    stripe: proto_structs::Stripe,
+    buffers: Rc<BufferPool>,
+    /// The bytes of the stripe, if they were read in advance
+    prefetched: Option<Vec<u8>>
+}
+
+impl<'a> StripeReference<'a> {
+    /// The number of rows in the stripe, according to the stripe index
+    pub fn num_rows(&self) -> usize { self.stripe.num_rows }
+
+    /// Reads the stripe header, verifying the checksum of the stripe
+    pub fn get_header(&self) -> StorageResult<proto_structs::StripeHeader> {
+        let (header, data_offset) = try!(self.read_header());
+        try!(self.verify_checksum(&header, data_offset));
+        Ok(header)
+    }
+
+    /// Reads the stripe header only. The chunks are not read, so the checksum is not verified.
+    pub fn get_header_unverified(&self) -> StorageResult<proto_structs::StripeHeader> {
+        let (header, _) = try!(self.read_header());
+        Ok(header)
+    }
+
+    /// The stored and decoded sizes of each column chunk, to find out which columns compress
+    /// well. Only the stripe header is read. Stripes written before a column was appended
+    /// have no chunk for it, so it is not in their summary.
+    pub fn compression_summary(&self) -> StorageResult<Vec<ChunkSizes>> {
+        let header = try!(self.get_header_unverified());
+        if header.column_chunks.len() > self.storage.num_columns() {
+            return Err(StorageError::InvalidFormat(format!("Stripe has {} chunks, expected {}", header.column_chunks.len(), self.storage.num_columns())));
+        }
+
+        let summary = self.storage.columns().iter().zip(header.column_chunks.iter())
+            .map(|(column, chunk_header)| {
+                // Chunks that are not encoded are laid out as they are once decoded, so their
+                // size is the uncompressed one. Encoded numeric chunks are decoded to a nulls
+                // bitmap and one value per row, NULLs included. Dictionary-encoded chunks
+                // count the size of their codes: the values are not in the header.
+                let num_values = header.num_rows;
+                let is_raw = chunk_header.encoding == Encoding::Raw && chunk_header.nulls_encoding == NullsEncoding::Plain;
+                let decoded_size = match column.datatype {
+                    ColumnDatatype::VariableLength | ColumnDatatype::FixedLength(_) => chunk_header.uncompressed_size,
+                    _ if is_raw => chunk_header.uncompressed_size,
+                    ref datatype => num_values.div_ceil(8) + num_values * DatatypeInfo::new(datatype).value_size.unwrap()
+                };
+
+                ChunkSizes {
+                    column: column.name.clone(),
+                    stored_size: chunk_header.compressed_size,
+                    decoded_size: decoded_size
+                }
+            })
+            .collect();
+
+        Ok(summary)
+    }
+
+    /// Reads the (uncompressed and decoded) bytes of the chunk for the given column. Only this chunk is
+    /// read, so the checksum of the stripe is not verified.
+    pub fn read_column_chunk(&self, num_column: usize) -> StorageResult<Vec<u8>> {
+        let (header, data_offset) = try!(self.read_header());
+        let chunk_header = match header.column_chunks.get(num_column) {
+            Some(h) => h,
+            None => return Err(StorageError::InvalidFormat(format!("Stripe has no chunk for column {}", num_column)))
+        };
+
+        self.read_chunk(chunk_header, num_column, header.num_rows, data_offset)
+    }
+
+    /// Reads the values of a numeric chunk without converting them to ColumnValues. Only
+    /// raw chunks can be read this way. Fails if T is not the type of the column. Only this
+    /// chunk is read, so the checksum of the stripe is not verified.
+    pub fn read_raw_values<T>(&self, num_column: usize) -> StorageResult<RawValues<T>>
+        where T: NumericValue + Copy
+    {
+        if num_column >= self.storage.num_columns() {
+            return Err(StorageError::InvalidFormat(format!("Stripe has no chunk for column {}", num_column)));
+        }
+        let datatype = &self.storage.column(num_column).datatype;
+        if *datatype != T::datatype() {
+            return Err(StorageError::TypeError);
+        }
+
+        let (header, data_offset) = try!(self.read_header());
+        let chunk_header = match header.column_chunks.get(num_column) {
+            Some(h) => h,
+            None => return Err(StorageError::InvalidFormat(format!("Stripe has no chunk for column {}", num_column)))
+        };
+        if chunk_header.encoding != Encoding::Raw {
+            return Err(StorageError::InvalidFormat(format!("Chunk of column {} is not raw ({:?})", num_column, chunk_header.encoding)));
+        }
+
+        let num_values = header.num_rows;
+        let chunk = try!(self.read_chunk(chunk_header, num_column, num_values, data_offset));
+        let bitmap_size = num_values.div_ceil(8);
+        if chunk.len() < bitmap_size {
+            return Err(invalid_chunk());
+        }
+
+        let values = try!(get_slice_values::<T>(&chunk[bitmap_size..]));
+        if values.len() != num_values {
+            return Err(invalid_chunk());
+        }
+
+        let nulls = NullsBitmap::from_raw_bits(&chunk[..bitmap_size], num_values);
+        self.buffers.give_back(chunk);
+        Ok(RawValues { values: values, nulls: nulls })
+    }
+
+    /// Reads and decodes all the columns of the stripe
+    pub fn read_columns(&self) -> StorageResult<Vec<Vec<ColumnValue>>> {
+        let (header, data_offset) = try!(self.read_header());
+        try!(self.verify_checksum(&header, data_offset));
+        if header.column_chunks.len() > self.storage.num_columns() {
+            return Err(StorageError::InvalidFormat(format!("Stripe has {} chunks, expected {}", header.column_chunks.len(), self.storage.num_columns())));
+        }
+
+        let mut columns = Vec::with_capacity(self.storage.num_columns());
+        for (num_column, column) in self.storage.columns().iter().enumerate() {
+            let chunk_header = match header.column_chunks.get(num_column) {
+                Some(h) => h,
+                None => {
+                    columns.push(try!(self.appended_column_values(num_column, &header)));
+                    continue;
+                }
+            };
+
+            let chunk = try!(self.read_chunk(chunk_header, num_column, header.num_rows, data_offset));
+            columns.push(try!(decode_column_chunk(&column.datatype, header.num_rows, &chunk)));
+            self.buffers.give_back(chunk);
+        }
+
+        Ok(columns)
+    }
+
+    /// Reads and decodes the values of a numeric column, None for NULL values. Only this
+    /// chunk is read, so the checksum of the stripe is not verified.
+    pub fn read_numeric_values<T>(&self, num_column: usize) -> StorageResult<Vec<Option<T>>>
+        where T: NumericValue + Copy
+    {
+        let (header, data_offset) = try!(self.read_header());
+        let chunk_header = match header.column_chunks.get(num_column) {
+            Some(h) => h,
+            None => {
+                let values = try!(self.appended_column_values(num_column, &header));
+                return values.iter().map(T::extract_value_or_null).collect();
+            }
+        };
+
+        let chunk = try!(self.read_chunk(chunk_header, num_column, header.num_rows, data_offset));
+        let values = decode_numeric_values::<T>(header.num_rows, &chunk);
+        self.buffers.give_back(chunk);
+        values
+    }
+
+    /// The values of a column that was appended after this stripe was written, which has
+    /// no chunk for it: the default value of the column for every row
+    fn appended_column_values(&self, num_column: usize, header: &proto_structs::StripeHeader) -> StorageResult<Vec<ColumnValue>> {
+        let default = self.storage.columns().get(num_column).and_then(|c| c.default_value());
+        match default {
+            Some(default) if num_column >= header.column_chunks.len() => Ok(vec!(default.clone(); header.num_rows)),
+            _ => Err(StorageError::InvalidFormat(format!("Stripe has no chunk for column {}", num_column)))
+        }
+    }
+
+    /// Reads the chunk of the given column with a ChunkReader. Only this chunk is read, so
+    /// the checksum of the stripe is not verified.
+    pub fn chunk_reader(&self, num_column: usize) -> StorageResult<ChunkReader> {
+        let chunk = try!(self.read_column_chunk(num_column));
+        Ok(ChunkReader { datatype: self.storage.column(num_column).datatype, num_values: self.stripe.num_rows, chunk: chunk })
+    }
+
+    /// The absolute offset where the chunks of the stripe begin, right after its header
+    pub fn data_offset(&self) -> StorageResult<u64> {
+        let (_, data_offset) = try!(self.read_header());
+        Ok(data_offset)
+    }
+
+    /// Reads, decompresses and decodes a chunk into a buffer from the pool. Callers that don't
+    /// keep the chunk give the buffer back.
+    fn read_chunk(&self, chunk_header: &proto_structs::ColumnChunkHeader, num_column: usize, num_values: usize, data_offset: u64) -> StorageResult<Vec<u8>> {
+        let column = self.storage.column(num_column);
+        let dictionary = column.dictionary();
+        let mut reader = try!(self.reader());
+        read_chunk_bytes(&mut *reader, data_offset, chunk_header, &column.datatype, dictionary.as_deref(), num_values, &self.buffers)
+    }
+
+    /// Reads and decodes only the given columns, skipping the chunks of the others. The
+    /// checksum of the stripe is not verified.
+    pub fn read_selected_columns(&self, num_columns: &[usize]) -> StorageResult<Vec<Vec<ColumnValue>>> {
+        let (header, data_offset) = try!(self.read_header());
+
+        let mut columns = Vec::with_capacity(num_columns.len());
+        for &num_column in num_columns.iter() {
+            let chunk_header = match header.column_chunks.get(num_column) {
+                Some(h) => h,
+                None => {
+                    columns.push(try!(self.appended_column_values(num_column, &header)));
+                    continue;
+                }
+            };
+
+            let datatype = &self.storage.column(num_column).datatype;
+            let chunk = try!(self.read_chunk(chunk_header, num_column, header.num_rows, data_offset));
+            columns.push(try!(decode_column_chunk(datatype, header.num_rows, &chunk)));
+            self.buffers.give_back(chunk);
+        }
+
+        Ok(columns)
+    }
+
+    fn verify_checksum(&self, header: &proto_structs::StripeHeader, data_offset: u64) -> StorageResult<()> {
+        if !try!(self.checksum_matches(header, data_offset)) {
+            return Err(StorageError::ChecksumMismatch { stripe: self.num_stripe });
+        }
+        Ok(())
+    }
+
+    fn checksum_matches(&self, header: &proto_structs::StripeHeader, data_offset: u64) -> StorageResult<bool> {
+        let mut data = vec![0u8; header.stripe_size];
+
+        let mut reader = try!(self.reader());
+        try!(reader.seek(SeekFrom::Start(data_offset)));
+        try!(reader.read_exact(&mut data));
+
+        let mut crc = Crc32::new();
+        crc.update(&data);
+        Ok(crc.finish() == header.crc32)
+    }
+
+    /// Reads the storage, or the bytes of the stripe if they were prefetched
+    fn reader<'b>(&'b self) -> StorageResult<Box<BackendReader + 'b>> {
+        match self.prefetched {
+            Some(ref bytes) => Ok(Box::new(PrefetchedStripeReader {
+                bytes: Cursor::new(&bytes[..]),
+                offset: self.stripe.absolute_offset as u64
+            })),
+            None => self.storage.backend.reader()
+        }
+    }
+
+    /// Returns the stripe header and the absolute offset where the stripe's chunks begin
+    fn read_header(&self) -> StorageResult<(proto_structs::StripeHeader, u64)> {
+        let mut reader = try!(self.reader());
+        try!(reader.seek(SeekFrom::Start(self.stripe.absolute_offset as u64)));
+
+        let message = try!(::capnp::serialize::read_message(&mut reader, ReaderOptions::new()));
+        let header_reader = try!(message.get_root::<<proto_structs::StripeHeader as ProtocolReadable>::Reader>());
+        let header = try!(proto_structs::StripeHeader::read_message(&header_reader));
+
+        // Rows are located with the index, and decoded with the header
+        if header.num_rows != self.stripe.num_rows {
+            return Err(StorageError::InvalidFormat(format!("Stripe {} has {} rows, but the index says {}", self.num_stripe, header.num_rows, self.stripe.num_rows)));
+        }
+
+        // The chunks are written right after the header
+        let data_offset = try!(reader.seek(SeekFrom::Current(0)));
+        Ok((header, data_offset))
+    }
+}
+
+// ----------------------------------------------------------------------------
+#[cfg(test)]
+mod test {
+    use std::{i32, i64, f32};
+    use ::storage_reader::*;
+    use ::storage_inserter::get_slice_bytes;
+
+    #[test]
+    fn rle_chunks_are_expanded() {
+        let values: Vec<Option<i32>> = vec!(Some(5), Some(5), Some(5), None, Some(-1), Some(-1), Some(5), Some(5), Some(5), Some(5));
+
+        // The nulls bitmap is not encoded. NULLs hold a placeholder value, like in raw chunks.
+        let mut nulls = NullsBitmap::new();
+        for v in values.iter() {
+            nulls.push(v.is_some());
+        }
+        let mut chunk = nulls.get_raw_bits().to_vec();
+
+        // Runs of (length, value). The last 4 values are written as two adjacent runs, like
+        // the encoder does with runs longer than u32::MAX.
+        let runs: [(u32, i32); 5] = [(3, 5), (1, i32::null_value()), (2, -1), (1, 5), (3, 5)];
+        for &(length, value) in runs.iter() {
+            chunk.extend_from_slice(&length.to_le_bytes());
+            chunk.extend_from_slice(&get_slice_bytes(&[value]));
+        }
+
+        let datatype = ColumnDatatype::Int32;
+        let chunk = decode_chunk_encoding(Encoding::RLE, &datatype, values.len(), chunk).unwrap();
+        assert_eq!(decode_numeric_values::<i32>(values.len(), &chunk).unwrap(), values);
+
+        let decoded = decode_column_chunk(&datatype, values.len(), &chunk).unwrap();
+        assert_eq!(decoded[3], ColumnValue::Null);
+        assert_eq!(decoded[9], ColumnValue::Int32(5));
+
+        // Runs that don't add up to the number of values
+        let mut encoded = nulls.get_raw_bits().to_vec();
+        encoding::encode_rle(&get_slice_bytes(&[5i32; 4]), 4, &mut encoded);
+        assert!(decode_chunk_encoding(Encoding::RLE, &datatype, values.len(), encoded).is_err());
+
+        // Only numeric chunks can be RLE encoded
+        assert!(decode_chunk_encoding(Encoding::RLE, &ColumnDatatype::VariableLength, 1, vec!(0)).is_err());
+    }
+
+    #[test]
+    fn frame_of_reference_chunks_are_decoded() {
+        let values: Vec<Option<i32>> = vec!(Some(-20), None, Some(-18), Some(-25), Some(-20));
+
+        let mut nulls = NullsBitmap::new();
+        for v in values.iter() {
+            nulls.push(v.is_some());
+        }
+        let mut chunk = nulls.get_raw_bits().to_vec();
+        // NULLs can take any value in the frame, the base is the simplest one
+        let raw: Vec<i64> = values.iter().map(|v| v.unwrap_or(-25) as i64).collect();
+        let base = encoding::encode_frame_of_reference(&raw, &mut chunk);
+
+        let datatype = ColumnDatatype::Int32;
+        let chunk = decode_chunk_encoding(Encoding::FrameOfReference(base), &datatype, values.len(), chunk).unwrap();
+        assert_eq!(decode_numeric_values::<i32>(values.len(), &chunk).unwrap(), values);
+
+        assert!(decode_chunk_encoding(Encoding::FrameOfReference(0), &ColumnDatatype::Float, 1, vec!(1, 0, 0)).is_err());
+    }
+
+    #[test]
+    fn values_are_stored_as_little_endian() {
+        assert_eq!(&get_slice_bytes(&[1i32, -2])[..], &[1, 0, 0, 0, 0xFE, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(&get_slice_bytes(&[1.0f32])[..], &[0, 0, 0x80, 0x3F]);
+        assert_eq!(get_slice_values::<i16>(&[0x34, 0x12]).unwrap(), vec!(0x1234));
+        assert_eq!(get_slice_values::<Timestamp>(&[1, 0, 0, 0, 0, 0, 0, 0x80]).unwrap(), vec!(Timestamp(i64::MIN + 1)));
+
+        let values = [7i64, -1, 1 << 40];
+        let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes().to_vec()).collect();
+        assert_eq!(get_slice_values::<i64>(&bytes).unwrap(), values.to_vec());
+        let floats = [1.5f32, f32::NEG_INFINITY];
+        let bytes: Vec<u8> = floats.iter().flat_map(|f| f.to_bits().to_le_bytes().to_vec()).collect();
+        assert_eq!(get_slice_values::<f32>(&bytes).unwrap(), floats.to_vec());
+        assert!(get_slice_values::<i32>(&[1, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn scans_reuse_chunk_buffers() {
+        use ::test::{TestPath, TestStorage};
+
+        let test_path = TestPath::new();
+        let storage = TestStorage::insert_rows(TestStorage::new(&test_path.file_name("test.storage")), 3000, 1000);
+
+        // The buffers of the first stripe are reused by the others, instead of allocating
+        // new ones for every chunk
+        let mut stripes = StorageStripeIterator::new(&storage);
+        let first = stripes.next().unwrap();
+        first.read_columns().unwrap();
+        let pooled = first.buffers.capacities();
+        assert!(!pooled.is_empty());
+        for stripe in stripes {
+            stripe.read_columns().unwrap();
+            assert!(stripe.buffers.capacities().len() <= MAX_POOLED_BUFFERS);
+            assert_eq!(stripe.buffers.capacities().iter().max(), pooled.iter().max());
+        }
+
+        let reader = StorageReader::new(storage);
+        let rows: Vec<Vec<ColumnValue>> = reader.rows().map(|r| r.unwrap()).collect();
+        assert_eq!(rows.len(), 3000);
+        for i in (0..3000).step_by(97) {
+            assert_eq!(rows[i], reader.get_row(i).unwrap());
+        }
+        assert_eq!(rows[2501][2], ColumnValue::Int32(2501));
+    }
+
+    /// Reads the only chunk of an in-memory storage with a single column and a single stripe
+    fn read_only_chunk(datatype: ColumnDatatype, values: &[ColumnValue]) -> ChunkReader {
+        use ::storage::StorageBuilder;
+        use ::encoding::Encoder;
+
+        let storage = StorageBuilder::new()
+            .column("value", datatype)
+            .encoder(Encoder::Auto)
+            .in_memory().unwrap();
+        let mut insertion_manager = storage.begin_inserting();
+        {
+            let mut inserter = insertion_manager.create_inserter();
+            for v in values.iter() {
+                inserter.enqueue_row(&vec!(v.clone())).unwrap();
+            }
+        }
+        let storage = insertion_manager.finish_inserting().unwrap();
+
+        let stripe = storage.stripes_iter().next().unwrap();
+        let header = stripe.get_header().unwrap();
+        let data_offset = stripe.data_offset().unwrap();
+        let mut reader = storage.backend.reader().unwrap();
+        let dictionary = storage.column(0).dictionary();
+        let chunk = ChunkReader::read(&mut *reader, data_offset, &header.column_chunks[0], &datatype, dictionary.as_deref(), header.num_rows).unwrap();
+
+        assert_eq!(chunk.num_values(), values.len());
+        assert_eq!(chunk.values().unwrap(), values.to_vec());
+        assert_eq!(chunk.bytes(), &stripe.chunk_reader(0).unwrap().bytes()[..]);
+        chunk
+    }
+
+    fn check_numeric_chunk<T>(values: Vec<Option<T>>)
+        where T: NumericValue + Copy + PartialEq + ::std::fmt::Debug
+    {
+        let column_values: Vec<ColumnValue> = values.iter().map(|v| v.map_or(ColumnValue::Null, T::into_column_value)).collect();
+        let chunk = read_only_chunk(T::datatype(), &column_values);
+        assert_eq!(chunk.numeric_values::<T>().unwrap().collect::<Vec<_>>(), values);
+        assert!(chunk.byte_values().is_err());
+    }
+
+    #[test]
+    fn chunks_of_integers_can_be_read() {
+        check_numeric_chunk::<i8>(vec!(Some(1), None, Some(-128), Some(127)));
+        check_numeric_chunk::<i16>(vec!(Some(300), Some(300), Some(300), None));
+        check_numeric_chunk::<i32>((0..100).map(|i| if i % 10 == 0 { None } else { Some(1000 + i) }).collect());
+        check_numeric_chunk::<i64>(vec!(Some(i64::MAX), None, Some(i64::MIN + 1)));
+
+        let chunk = read_only_chunk(ColumnDatatype::Int32, &[ColumnValue::Int32(1)]);
+        assert!(chunk.numeric_values::<i64>().is_err());
+    }
+
+    #[test]
+    fn chunks_of_floats_and_timestamps_can_be_read() {
+        check_numeric_chunk::<f32>(vec!(Some(1.5), None, Some(f32::NEG_INFINITY)));
+        check_numeric_chunk::<Timestamp>((0..50).map(|i| Some(Timestamp(1_500_000_000_000_000 + i * 1000))).collect());
+    }
+
+    #[test]
+    fn chunks_of_fixed_length_values_can_be_read() {
+        let values = vec!(ColumnValue::FixedLength(vec!(1, 2, 3)), ColumnValue::Null, ColumnValue::FixedLength(vec!(4, 5, 6)));
+        let chunk = read_only_chunk(ColumnDatatype::FixedLength(3), &values);
+        let expected: Vec<Option<&[u8]>> = vec!(Some(&[1, 2, 3]), None, Some(&[4, 5, 6]));
+        assert_eq!(chunk.byte_values().unwrap().collect::<Vec<_>>(), expected);
+        assert!(chunk.numeric_values::<i8>().is_err());
+    }
+
+    #[test]
+    fn chunks_of_variable_length_values_can_be_read() {
+        let values = vec!(ColumnValue::VariableLength(b"abc".to_vec()), ColumnValue::VariableLength(vec!()), ColumnValue::Null);
+        let chunk = read_only_chunk(ColumnDatatype::VariableLength, &values);
+        let expected: Vec<Option<&[u8]>> = vec!(Some(b"abc"), Some(b""), None);
+        assert_eq!(chunk.byte_values().unwrap().collect::<Vec<_>>(), expected);
+        assert!(chunk.numeric_values::<i32>().is_err());
+    }
+
+    #[test]
+    fn only_file_storages_are_prefetched() {
+        use ::test::{TestPath, TestStorage};
+        use ::storage::StorageBuilder;
+
+        let test_path = TestPath::new();
+        let storage = TestStorage::insert_rows(TestStorage::new(&test_path.file_name("test.storage")), 300, 100);
+        let stripes: Vec<_> = StorageStripeIterator::prefetching(&storage).collect();
+        assert_eq!(stripes.len(), 3);
+        for (stripe, index) in stripes.iter().zip(storage.stripes().iter()) {
+            assert_eq!(stripe.prefetched.as_ref().map(|b| b.len()), Some(index.size));
+            assert_eq!(stripe.read_columns().unwrap(), StorageStripeIterator::new(&storage).nth(stripe.num_stripe).unwrap().read_columns().unwrap());
+        }
+        assert!(StorageStripeIterator::new(&storage).all(|s| s.prefetched.is_none()));
+
+        let storage = StorageBuilder::new().column("id", ColumnDatatype::Int32).in_memory().unwrap();
+        assert!(StorageStripeIterator::prefetching(&storage).prefetched.is_none());
+    }
+}
@@ -1,7 +1,18 @@
 use std::path::{Path, PathBuf};
 use std::fs;
+use std::thread;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{Write, Seek, SeekFrom, Cursor};
+use std::sync::{Arc, Mutex};
+use std::{f32, i16, i32};
 
-use ::storage::{Storage, StorageBuilder, ColumnDatatype, ColumnValue};
+use ::storage::{Storage, StorageBuilder, ColumnDatatype, ColumnValue, ColumnStats, StripeStats};
+use ::storage_backend::StorageBackend;
+use ::storage_reader::{StorageReader, StorageStripeIterator};
+use ::error::StorageError;
+#[cfg(feature = "derive")]
+use ::Row;
 
 // ----------------------------------------------------------------------------
 use libc::{c_char, c_void, free};
@@ -13,10 +24,10 @@ extern {
 
 pub fn tempname(prefix: &str) -> PathBuf {
     let temp_dir = CString::new("/tmp").unwrap();
-    let prefix_ptr = CString::new(prefix).expect("tempname prefix contains non-UTF8 chars").as_ptr();
+    let prefix = CString::new(prefix).expect("tempname prefix contains non-UTF8 chars");
 
     unsafe {
-        let buffer = tempnam(temp_dir.as_ptr(), prefix_ptr);
+        let buffer = tempnam(temp_dir.as_ptr(), prefix.as_ptr());
         let path_name = CStr::from_ptr(buffer).to_str().unwrap();
 
         let mut full_path = PathBuf::from(temp_dir.to_str().unwrap());
@@ -75,6 +86,39 @@ impl TestStorage {
             .column("variablelengthcol", ColumnDatatype::VariableLength)
             .at(path).unwrap()
     }
+
+    /// The i-th row inserted by insert_rows. Every column has some NULLs.
+    pub fn row(i: usize) -> Vec<ColumnValue> {
+        let null_or = |v: ColumnValue| if i % 11 == 3 { ColumnValue::Null } else { v };
+
+        vec!(
+            ColumnValue::Null,
+            null_or(ColumnValue::Byte((i % 100) as i8)),
+            null_or(ColumnValue::Int32(i as i32)),
+            null_or(ColumnValue::Int64((i as i64)*1000)),
+            null_or(ColumnValue::Float(i as f32 / 2.0)),
+            null_or(ColumnValue::FixedLength(vec!((i % 256) as u8; 5))),
+            null_or(ColumnValue::VariableLength(format!("Row {}", i).into())),
+        )
+    }
+
+    /// Inserts num_rows rows, writing a stripe every rows_per_stripe rows
+    pub fn insert_rows(storage: Storage, num_rows: usize, rows_per_stripe: usize) -> Storage {
+        let mut insertion_manager = storage.begin_inserting();
+        let mut i = 0;
+        while i < num_rows {
+            let mut inserter = insertion_manager.create_inserter();
+            for _ in 0..rows_per_stripe {
+                if i == num_rows {
+                    break;
+                }
+                inserter.enqueue_row(&Self::row(i)).unwrap();
+                i += 1;
+            }
+        }
+
+        insertion_manager.finish_inserting().unwrap()
+    }
 }
 
 // ----------------------------------------------------------------------------
@@ -94,6 +138,19 @@ fn column_accessors() {
     assert_eq!(storage.column_by_name("col2").unwrap().num_column_in_storage(), 1);
 }
 
+// ----------------------------------------------------------------------------
+#[test]
+fn column_indices_by_name() {
+    let test_path = TestPath::new();
+    let storage = TestStorage::new(&test_path.file_name("test.storage"));
+
+    let names = ["nullcol", "bytecol", "int32col", "int64col", "floatcol", "fixedlengthcol", "variablelengthcol"];
+    for (i, name) in names.iter().enumerate() {
+        assert_eq!(storage.column_index(name), Some(i));
+    }
+    assert_eq!(storage.column_index("nonexistent"), None);
+}
+
 // ----------------------------------------------------------------------------
 #[test]
 fn storage_generates_right_columns() {
@@ -141,6 +198,42 @@ fn storage_with_duplicated_columns() {
         .unwrap();
 }
 
+// ----------------------------------------------------------------------------
+#[test]
+fn columns_need_a_name() {
+    for name in ["", " ", "\t\n"].iter() {
+        match StorageBuilder::new().column("id", ColumnDatatype::Int32).column(name, ColumnDatatype::Int32).in_memory() {
+            Err(StorageError::InvalidFormat(message)) => assert!(message.contains("empty")),
+            _ => panic!("Expected an InvalidFormat error for {:?}", name)
+        }
+    }
+
+    // Names are case-sensitive, and can have surrounding spaces
+    let mut storage = StorageBuilder::new()
+        .column("id", ColumnDatatype::Int32)
+        .column("ID", ColumnDatatype::Int32)
+        .column(" x ", ColumnDatatype::Int32)
+        .in_memory().unwrap();
+    assert_eq!(storage.column_index("ID"), Some(1));
+    assert_eq!(storage.column_index(" x "), Some(2));
+
+    assert!(storage.append_column(" ", ColumnDatatype::Int32, ColumnValue::Null).is_err());
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn storage_with_empty_fixed_length_columns() {
+    let result = StorageBuilder::new()
+        .column("empty", ColumnDatatype::FixedLength(0))
+        .in_memory();
+    assert!(result.is_err());
+
+    let result = StorageBuilder::new()
+        .column("negative", ColumnDatatype::FixedLength(-1))
+        .in_memory();
+    assert!(result.is_err());
+}
+
 // ----------------------------------------------------------------------------
 #[test]
 fn a_single_row_can_be_inserted() {
@@ -192,7 +285,1926 @@ fn invalid_values_cannot_be_inserted() {
             ColumnValue::VariableLength("Hello world".to_string().into()),
         );
 
-        let result = inserter.enqueue_row(&row);
-        assert!(result.is_err());
+        match inserter.enqueue_row(&row) {
+            Err(StorageError::TypeMismatch { column: 2, expected: ColumnDatatype::Int32 }) => {},
+            r => panic!("Expected a TypeMismatch for column 2, got {:?}", r)
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn numeric_column_can_be_visited() {
+    let test_path = TestPath::new();
+
+    let storage = StorageBuilder::new()
+        .column("id", ColumnDatatype::Int32)
+        .column("value", ColumnDatatype::Int64)
+        .at(test_path.file_name("test.storage")).unwrap();
+
+    // Enough rows to span several stripes, with a NULL every 7 rows
+    let values: Vec<Option<i64>> = (0..100000i64).map(|i| if i % 7 == 0 { None } else { Some(i) }).collect();
+
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        for (i, value) in values.iter().enumerate() {
+            let row = vec!(
+                ColumnValue::Int32(i as i32),
+                match *value { Some(v) => ColumnValue::Int64(v), None => ColumnValue::Null }
+            );
+            inserter.enqueue_row(&row).unwrap();
+        }
+    }
+    let storage = insertion_manager.finish_inserting().unwrap();
+    assert!(storage.stripes().len() > 1);
+
+    let mut sum = 0i64;
+    let mut null_count = 0;
+    storage.visit_column_i64(1, |v| match v {
+        Some(v) => sum += v,
+        None => null_count += 1
+    }).unwrap();
+
+    assert_eq!(sum, values.iter().filter_map(|v| *v).fold(0, |a, b| a + b));
+    assert_eq!(null_count, values.iter().filter(|v| v.is_none()).count());
+
+    // The column has to be visited with its own type
+    assert!(storage.visit_column_i32(1, |_| {}).is_err());
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn storage_can_be_built_over_any_backend() {
+    let test_path = TestPath::new();
+    let backends = vec!(
+        StorageBackend::new_in_memory(),
+        StorageBackend::in_path(test_path.file_name("test.storage"))
+    );
+
+    for backend in backends {
+        let storage = StorageBuilder::new()
+            .column("id", ColumnDatatype::Int32)
+            .in_backend(backend).unwrap();
+
+        let mut insertion_manager = storage.begin_inserting();
+        {
+            let mut inserter = insertion_manager.create_inserter();
+            for i in 0..10 {
+                inserter.enqueue_row(&vec!(ColumnValue::Int32(i))).unwrap();
+            }
+        }
+        let storage = insertion_manager.finish_inserting().unwrap();
+
+        let mut sum = 0;
+        storage.visit_column_i32(0, |v| sum += v.unwrap()).unwrap();
+        assert_eq!(sum, 45);
+    }
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn cannot_finish_inserting_with_pending_inserters() {
+    let storage = StorageBuilder::new()
+        .column("id", ColumnDatatype::Int32)
+        .in_memory().unwrap();
+
+    let mut insertion_manager = storage.begin_inserting();
+    let mut inserter = insertion_manager.create_inserter();
+    inserter.enqueue_row(&vec!(ColumnValue::Int32(1))).unwrap();
+
+    match insertion_manager.finish_inserting() {
+        Err(StorageError::PendingInserters) => {},
+        _ => panic!("Expected a PendingInserters error")
+    }
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn rows_can_be_counted_from_headers() {
+    let storage = StorageBuilder::new()
+        .column("id", ColumnDatatype::Int64)
+        .in_memory().unwrap();
+
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        for i in 0..100000 {
+            inserter.enqueue_row(&vec!(ColumnValue::Int64(i))).unwrap();
+        }
+    }
+    let mut storage = insertion_manager.finish_inserting().unwrap();
+    assert!(storage.stripes().len() > 1);
+    assert_eq!(storage.count_rows_from_headers().unwrap(), storage.num_rows());
+
+    // Tamper with the total
+    storage.num_rows = 12345;
+    assert!(storage.count_rows_from_headers().unwrap() != storage.num_rows());
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn inserter_does_not_panic_when_flushing_fails() {
+    let test_path = TestPath::new();
+    let test_file = test_path.file_name("test.storage");
+
+    let storage = TestStorage::new(test_file.as_path());
+    let mut insertion_manager = storage.begin_inserting();
+
+    let row = vec!(
+        ColumnValue::Null,
+        ColumnValue::Byte(2),
+        ColumnValue::Int32(300),
+        ColumnValue::Int64(400000000i64),
+        ColumnValue::Float(1.5),
+        ColumnValue::FixedLength(vec!(1,2,3,4,5)),
+        ColumnValue::VariableLength("Hello world".to_string().into()),
+    );
+
+    let mut inserter = insertion_manager.create_inserter();
+    inserter.enqueue_row(&row).unwrap();
+    let mut other_inserter = insertion_manager.create_inserter();
+    other_inserter.enqueue_row(&row).unwrap();
+
+    // Remove the directory that holds the storage, so writing to it fails
+    fs::remove_dir_all(test_file.parent().unwrap()).unwrap();
+
+    assert!(inserter.finish().is_err());
+    drop(other_inserter);
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn rows_failing_a_check_are_rejected() {
+    let storage = StorageBuilder::new()
+        .column("id", ColumnDatatype::Int32)
+        .column("count", ColumnDatatype::Int32)
+        .check("count", Box::new(|v| match *v {
+            ColumnValue::Int32(v) => v >= 0,
+            _ => true
+        }))
+        .in_memory().unwrap();
+
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        assert!(inserter.enqueue_row(&vec!(ColumnValue::Int32(1), ColumnValue::Int32(10))).is_ok());
+        assert!(inserter.enqueue_row(&vec!(ColumnValue::Int32(2), ColumnValue::Null)).is_ok());
+
+        match inserter.enqueue_row(&vec!(ColumnValue::Int32(3), ColumnValue::Int32(-1))) {
+            Err(StorageError::CheckFailed { column }) => assert_eq!(column, "count"),
+            _ => panic!("Expected a CheckFailed error")
+        }
+    }
+
+    let storage = insertion_manager.finish_inserting().unwrap();
+    assert_eq!(storage.num_rows(), 2);
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn checks_on_unknown_columns_are_rejected() {
+    let result = StorageBuilder::new()
+        .column("id", ColumnDatatype::Int32)
+        .check("count", Box::new(|_| true))
+        .in_memory();
+    assert!(result.is_err());
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn rows_can_be_counted_from_the_stripe_index() {
+    let test_path = TestPath::new();
+    let test_file = test_path.file_name("test.storage");
+
+    let storage = StorageBuilder::new()
+        .column("id", ColumnDatatype::Int32)
+        .at(&test_file).unwrap();
+
+    // Each inserter writes its own stripe when dropped
+    let mut insertion_manager = storage.begin_inserting();
+    for i in 0..5 {
+        let mut inserter = insertion_manager.create_inserter();
+        for j in 0..1000 {
+            inserter.enqueue_row(&vec!(ColumnValue::Int32(i*1000 + j))).unwrap();
+        }
+    }
+    let storage = insertion_manager.finish_inserting().unwrap();
+    let stripes = storage.stripes().to_vec();
+    drop(storage);
+
+    // Overwrite the stripe headers, so the only way of counting the rows is the footer
+    {
+        let mut file = OpenOptions::new().write(true).open(&test_file).unwrap();
+        for stripe in stripes.iter() {
+            file.seek(SeekFrom::Start(stripe.absolute_offset as u64)).unwrap();
+            file.write_all(&[0xFF; 16]).unwrap();
+        }
+    }
+
+    let reader = StorageReader::open(&test_file).unwrap();
+    assert_eq!(reader.stripe_count(), 5);
+    assert_eq!(reader.row_count(), 5000);
+    assert!(reader.storage().count_rows_from_headers().is_err());
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn numeric_column_can_be_scanned_with_a_cursor() {
+    let storage = StorageBuilder::new()
+        .column("id", ColumnDatatype::Int32)
+        .column("value", ColumnDatatype::Int64)
+        .in_memory().unwrap();
+
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        for i in 0..1000 {
+            let value = if i % 10 == 0 { ColumnValue::Null } else { ColumnValue::Int64(i) };
+            inserter.enqueue_row(&vec!(ColumnValue::Int32(i as i32), value)).unwrap();
+        }
+    }
+    let reader = StorageReader::new(insertion_manager.finish_inserting().unwrap());
+
+    let mut sum = 0i64;
+    let mut count = 0;
+    for value in reader.column_cursor::<i64>("value").unwrap() {
+        if let Some(v) = value.unwrap() {
+            sum += v;
+        }
+        count += 1;
+    }
+
+    assert_eq!(count, 1000);
+    assert_eq!(sum, (0..1000i64).filter(|i| i % 10 != 0).fold(0, |a, b| a + b));
+
+    assert!(reader.column_cursor::<i32>("value").is_err());
+    assert!(reader.column_cursor::<i64>("unknown").is_err());
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn values_larger_than_the_limit_are_rejected() {
+    let storage = StorageBuilder::new()
+        .column("data", ColumnDatatype::VariableLength)
+        .max_value_bytes(1024)
+        .in_memory().unwrap();
+
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        assert!(inserter.enqueue_row(&vec!(ColumnValue::VariableLength(vec!(0; 500)))).is_ok());
+
+        match inserter.enqueue_row(&vec!(ColumnValue::VariableLength(vec!(0; 2048)))) {
+            Err(StorageError::ValueTooLarge { column, size, limit }) => {
+                assert_eq!(column, "data");
+                assert_eq!(size, 2048);
+                assert_eq!(limit, 1024);
+            },
+            _ => panic!("Expected a ValueTooLarge error")
+        }
+    }
+
+    let storage = insertion_manager.finish_inserting().unwrap();
+    assert_eq!(storage.num_rows(), 1);
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn many_tiny_rows_are_written_identically_to_any_backend() {
+    fn insert_tiny_rows(storage: Storage) -> Storage {
+        let mut insertion_manager = storage.begin_inserting();
+        {
+            let mut inserter = insertion_manager.create_inserter();
+            for i in 0..100000 {
+                inserter.enqueue_row(&vec!(ColumnValue::Byte((i % 100) as i8), ColumnValue::Byte(1))).unwrap();
+            }
+        }
+        insertion_manager.finish_inserting().unwrap()
+    }
+
+    let mut builder = StorageBuilder::new();
+    builder.column("a", ColumnDatatype::Byte).column("b", ColumnDatatype::Byte);
+
+    let test_path = TestPath::new();
+    let file_name = test_path.file_name("test.storage");
+    let file_storage = insert_tiny_rows(builder.at(&file_name).unwrap());
+    let memory_storage = insert_tiny_rows(builder.in_memory().unwrap());
+    assert_eq!(file_storage.num_rows(), 100000);
+
+    let memory_bytes = match memory_storage.backend {
+        StorageBackend::Memory(ref c) => c.get_ref().clone(),
+        _ => unreachable!()
+    };
+    assert_eq!(fs::read(&file_name).unwrap(), memory_bytes);
+
+    let reader = StorageReader::open(&file_name).unwrap();
+    assert_eq!(reader.row_count(), 100000);
+    let values: Vec<Option<i8>> = reader.column_cursor::<i8>("a").unwrap().map(|v| v.unwrap()).collect();
+    assert_eq!(values.len(), 100000);
+    assert_eq!(values[99999], Some(99));
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn corrupted_stripes_are_rejected() {
+    let test_path = TestPath::new();
+    let file_name = test_path.file_name("test.storage");
+    let storage = TestStorage::insert_rows(TestStorage::new(&file_name), 100, 50);
+
+    // Flip the last byte of the first stripe's chunks
+    let corrupted_offset = storage.stripes()[1].absolute_offset - 1;
+    {
+        let mut bytes = fs::read(&file_name).unwrap();
+        bytes[corrupted_offset] ^= 0xFF;
+        fs::write(&file_name, &bytes).unwrap();
+    }
+
+    let storage = Storage::open(&file_name).unwrap();
+    assert!(storage.read_rows(&[50]).is_ok());
+    match storage.read_rows(&[0]) {
+        Err(StorageError::ChecksumMismatch { stripe: 0 }) => {},
+        r => panic!("Expected a checksum mismatch in stripe 0, got {:?}", r)
+    }
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn timestamps_are_stored_with_delta_of_delta() {
+    let storage = StorageBuilder::new()
+        .column("ts", ColumnDatatype::Timestamp)
+        .in_memory().unwrap();
+
+    // One value per second, with some jitter and a few NULLs
+    let series: Vec<Option<i64>> = (0..1000i64)
+        .map(|i| if i % 100 == 7 { None } else { Some(1_500_000_000_000_000 + i*1_000_000 + (i % 3)) })
+        .collect();
+
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        for v in series.iter() {
+            let value = match *v { Some(ts) => ColumnValue::Timestamp(ts), None => ColumnValue::Null };
+            inserter.enqueue_row(&vec!(value)).unwrap();
+        }
+    }
+    let storage = insertion_manager.finish_inserting().unwrap();
+
+    let mut values = Vec::new();
+    storage.visit_column_timestamp(0, |v| values.push(v.map(|ts| ts.0))).unwrap();
+    assert_eq!(values, series);
+
+    let header = StorageStripeIterator::new(&storage).next().unwrap().get_header().unwrap();
+    let chunk_header = &header.column_chunks[0];
+    assert!(chunk_header.compressed_size < series.len() * 8);
+    assert_eq!(chunk_header.min_max, Some((1_500_000_000_000_000, 1_500_000_000_000_000 + 999*1_000_000)));
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn storage_can_be_described() {
+    let test_path = TestPath::new();
+    let storage = TestStorage::insert_rows(TestStorage::new(&test_path.file_name("test.storage")), 10, 10);
+
+    let description = storage.describe();
+    for (i, column) in storage.columns().iter().enumerate() {
+        assert!(description.contains(&format!("{}: {} {}", i, column.name, column.datatype)));
+    }
+    assert!(description.contains("fixedlengthcol FixedLength(5)"));
+    assert!(description.contains("variablelengthcol VariableLength"));
+    assert_eq!(description.lines().count(), 8);
+    assert!(description.ends_with("10 rows\n"));
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn column_stats_are_aggregated_from_the_chunk_headers() {
+    let storage = StorageBuilder::new()
+        .column("value", ColumnDatatype::Int32)
+        .column("empty", ColumnDatatype::Int64)
+        .column("name", ColumnDatatype::VariableLength)
+        .in_memory().unwrap();
+
+    let mut insertion_manager = storage.begin_inserting();
+    for stripe in [vec!(5, -3, 10), vec!(42, 7)].iter() {
+        let mut inserter = insertion_manager.create_inserter();
+        for &v in stripe.iter() {
+            inserter.enqueue_row(&vec!(ColumnValue::Int32(v), ColumnValue::Null, ColumnValue::VariableLength(vec!(1)))).unwrap();
+        }
+        inserter.enqueue_row(&vec!(ColumnValue::Null, ColumnValue::Null, ColumnValue::Null)).unwrap();
+    }
+    let storage = insertion_manager.finish_inserting().unwrap();
+    assert_eq!(storage.stripes().len(), 2);
+
+    let stats = storage.column_stats("value").unwrap().unwrap();
+    assert_eq!(stats, ColumnStats { min: Some(-3), max: Some(42), null_count: 2, num_rows: 7 });
+
+    // All NULL
+    let stats = storage.column_stats("empty").unwrap().unwrap();
+    assert_eq!(stats, ColumnStats { min: None, max: None, null_count: 7, num_rows: 7 });
+
+    // No min/max for non-integer types
+    let stats = storage.column_stats("name").unwrap().unwrap();
+    assert_eq!(stats, ColumnStats { min: None, max: None, null_count: 2, num_rows: 7 });
+
+    assert!(storage.column_stats("unknown").unwrap().is_none());
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn row_group_stats_are_exported_per_stripe() {
+    let storage = StorageBuilder::new()
+        .column("value", ColumnDatatype::Int64)
+        .column("name", ColumnDatatype::VariableLength)
+        .in_memory().unwrap();
+
+    let mut insertion_manager = storage.begin_inserting();
+    for stripe in [vec!(Some(5), None, Some(-8)), vec!(Some(100), Some(3))].iter() {
+        let mut inserter = insertion_manager.create_inserter();
+        for v in stripe.iter() {
+            let value = v.map_or(ColumnValue::Null, ColumnValue::Int64);
+            inserter.enqueue_row(&vec!(value, ColumnValue::VariableLength(vec!(1)))).unwrap();
+        }
+    }
+    let storage = insertion_manager.finish_inserting().unwrap();
+
+    let stats = storage.row_group_stats().unwrap();
+    assert_eq!(stats, vec!(
+        StripeStats {
+            offset: storage.stripes()[0].absolute_offset,
+            num_rows: 3,
+            columns: vec!(
+                ColumnStats { min: Some(-8), max: Some(5), null_count: 1, num_rows: 3 },
+                ColumnStats { min: None, max: None, null_count: 0, num_rows: 3 })
+        },
+        StripeStats {
+            offset: storage.stripes()[1].absolute_offset,
+            num_rows: 2,
+            columns: vec!(
+                ColumnStats { min: Some(3), max: Some(100), null_count: 0, num_rows: 2 },
+                ColumnStats { min: None, max: None, null_count: 0, num_rows: 2 })
+        }));
+    assert!(stats[0].offset < stats[1].offset);
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn distinct_values_are_estimated_per_chunk() {
+    let storage = StorageBuilder::new()
+        .column("id", ColumnDatatype::Int64)
+        .column("name", ColumnDatatype::VariableLength)
+        .column("empty", ColumnDatatype::Int32)
+        .rows_per_stripe(10000)
+        .in_memory().unwrap();
+
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        for i in 0..10000 {
+            inserter.enqueue_row(&vec!(
+                ColumnValue::Int64(i % 100),
+                ColumnValue::VariableLength(format!("Name {}", i % 1000).into()),
+                ColumnValue::Null
+            )).unwrap();
+        }
+    }
+    let storage = insertion_manager.finish_inserting().unwrap();
+
+    let header = storage.stripes_iter().next().unwrap().get_header().unwrap();
+    let within = |estimate: Option<usize>, expected: usize| {
+        let estimate = estimate.unwrap() as f64;
+        (estimate - expected as f64).abs() <= expected as f64 * 0.02
+    };
+    assert!(within(header.column_chunks[0].distinct_count, 100), "{:?}", header.column_chunks[0].distinct_count);
+    assert!(within(header.column_chunks[1].distinct_count, 1000), "{:?}", header.column_chunks[1].distinct_count);
+    assert_eq!(header.column_chunks[2].distinct_count, Some(0));
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn rows_can_be_appended_to_an_existing_storage() {
+    let test_path = TestPath::new();
+    let file_name = test_path.file_name("test.storage");
+
+    let storage = TestStorage::new(&file_name);
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        for i in 0..3 {
+            inserter.enqueue_row(&TestStorage::row(i)).unwrap();
+        }
+    }
+    insertion_manager.finish_inserting().unwrap();
+
+    let storage = Storage::open_for_append(&file_name).unwrap();
+    assert_eq!(storage.num_rows(), 3);
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        for i in 3..5 {
+            inserter.enqueue_row(&TestStorage::row(i)).unwrap();
+        }
+    }
+    insertion_manager.finish_inserting().unwrap();
+
+    let storage = Storage::open(&file_name).unwrap();
+    assert_eq!(storage.num_rows(), 5);
+    assert_eq!(storage.stripes().len(), 2);
+    let rows = storage.read_rows(&[0, 1, 2, 3, 4]).unwrap();
+    assert_eq!(rows, (0..5).map(TestStorage::row).collect::<Vec<_>>());
+}
+
+#[test]
+fn appended_rows_keep_the_settings_of_the_storage() {
+    use ::{Encoder, Encoding};
+
+    let test_path = TestPath::new();
+    let file_name = test_path.file_name("test.storage");
+    let row = |i: i32| vec!(ColumnValue::Int32(i), ColumnValue::FixedLength(b"ab".to_vec()), ColumnValue::VariableLength(b"x".to_vec()));
+
+    let storage = StorageBuilder::new()
+        .column("id", ColumnDatatype::Int32)
+        .column_padded("code", 4, b' ')
+        .column("name", ColumnDatatype::VariableLength)
+        .max_value_bytes(8)
+        .encoder(Encoder::Auto)
+        .check("id", Box::new(|v| *v != ColumnValue::Int32(-1)))
+        .at(&file_name).unwrap();
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        for i in 0..100 {
+            inserter.enqueue_row(&row(i)).unwrap();
+        }
+    }
+    insertion_manager.finish_inserting().unwrap();
+
+    let mut storage = Storage::open_for_append(&file_name).unwrap();
+    assert_eq!(storage.encoder(), Encoder::Auto);
+    assert_eq!(storage.max_value_bytes(), Some(8));
+    assert_eq!(storage.column(1).padding(), Some(b' '));
+
+    // Checks are not stored, so they are attached again
+    storage.add_check("id", Box::new(|v| *v != ColumnValue::Int32(-1))).unwrap();
+    assert!(storage.add_check("unknown", Box::new(|_| true)).is_err());
+
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        for i in 100..200 {
+            inserter.enqueue_row(&row(i)).unwrap();
+        }
+
+        let mut too_large = row(0);
+        too_large[2] = ColumnValue::VariableLength(vec!(b'x'; 9));
+        assert!(inserter.enqueue_row(&too_large).is_err());
+        assert!(inserter.enqueue_row(&row(-1)).is_err());
+    }
+    insertion_manager.finish_inserting().unwrap();
+
+    let storage = Storage::open(&file_name).unwrap();
+    assert_eq!(storage.num_rows(), 200);
+    let header = storage.stripes_iter().nth(1).unwrap().get_header().unwrap();
+    assert!(header.column_chunks[0].encoding != Encoding::Raw);
+    assert_eq!(storage.read_rows(&[150]).unwrap()[0][1], ColumnValue::FixedLength(b"ab  ".to_vec()));
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn storages_can_be_truncated_to_a_stripe_boundary() {
+    let test_path = TestPath::new();
+    let file_name = test_path.file_name("test.storage");
+    TestStorage::insert_rows(TestStorage::new(&file_name), 3000, 1000);
+    let full_size = fs::metadata(&file_name).unwrap().len();
+
+    let mut storage = Storage::open(&file_name).unwrap();
+    match storage.truncate_to_rows(1500) {
+        Err(StorageError::InvalidFormat(_)) => {},
+        _ => panic!("Expected an InvalidFormat error")
+    }
+    match storage.truncate_to_rows(3001) {
+        Err(StorageError::InvalidRowIndex(3001)) => {},
+        _ => panic!("Expected an InvalidRowIndex error")
+    }
+
+    storage.truncate_to_rows(2000).unwrap();
+    assert_eq!(storage.num_rows(), 2000);
+    assert!(fs::metadata(&file_name).unwrap().len() < full_size);
+
+    let reader = StorageReader::open(&file_name).unwrap();
+    assert_eq!(reader.row_count(), 2000);
+    assert_eq!(reader.stripe_count(), 2);
+    let rows: Vec<_> = reader.rows().map(|r| r.unwrap()).collect();
+    assert_eq!(rows, (0..2000).map(TestStorage::row).collect::<Vec<_>>());
+
+    // In-memory storages can be truncated too, down to no rows at all
+    let storage = StorageBuilder::new().column("id", ColumnDatatype::Int32).rows_per_stripe(10).in_memory().unwrap();
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        for i in 0..30 {
+            inserter.enqueue_row(&vec!(ColumnValue::Int32(i))).unwrap();
+        }
+    }
+    let mut storage = insertion_manager.finish_inserting().unwrap();
+    storage.truncate_to_rows(0).unwrap();
+
+    let reader = StorageReader::from_bytes(storage.backend.into_bytes().unwrap()).unwrap();
+    assert_eq!(reader.row_count(), 0);
+    assert_eq!(reader.rows().count(), 0);
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn compression_rates_are_reported_per_column() {
+    let storage = StorageBuilder::new()
+        .column("steady", ColumnDatatype::Timestamp)
+        .column("random", ColumnDatatype::Timestamp)
+        .column("name", ColumnDatatype::VariableLength)
+        .in_memory().unwrap();
+
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        let mut random: u64 = 12345;
+        for i in 0..1000 {
+            random = random.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            inserter.enqueue_row(&vec!(
+                ColumnValue::Timestamp(1500000000 + i * 60),
+                ColumnValue::Timestamp((random >> 1) as i64),
+                ColumnValue::VariableLength(format!("Row {}", i).into())
+            )).unwrap();
+        }
+    }
+    let storage = insertion_manager.finish_inserting().unwrap();
+
+    let summary = StorageStripeIterator::new(&storage).next().unwrap().compression_summary().unwrap();
+    let names: Vec<&str> = summary.iter().map(|s| &s.column[..]).collect();
+    assert_eq!(names, vec!("steady", "random", "name"));
+
+    assert_eq!(summary[0].decoded_size, 125 + 8000);
+    assert!(summary[0].compression_rate() > 4.0);
+    assert!(summary[0].compression_rate() > summary[1].compression_rate());
+    assert!(summary[1].compression_rate() < 1.5);
+    assert_eq!(summary[2].compression_rate(), 1.0);
+}
+
+#[test]
+fn nulls_are_not_counted_in_the_decoded_size() {
+    let storage = StorageBuilder::new()
+        .column("code", ColumnDatatype::FixedLength(4))
+        .column("id", ColumnDatatype::Int32)
+        .in_memory().unwrap();
+
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        for i in 0..10 {
+            let code = if i % 3 == 0 { ColumnValue::Null } else { ColumnValue::FixedLength(b"abcd".to_vec()) };
+            inserter.enqueue_row(&vec!(code, ColumnValue::Int32(i))).unwrap();
+        }
+    }
+    let storage = insertion_manager.finish_inserting().unwrap();
+
+    // One byte per row for the NULLs, and only the 6 values that are not NULL
+    let summary = StorageStripeIterator::new(&storage).next().unwrap().compression_summary().unwrap();
+    assert_eq!(summary[0].decoded_size, 10 + 6 * 4);
+    assert_eq!(summary[0].compression_rate(), 1.0);
+    assert_eq!(summary[1].decoded_size, 2 + 10 * 4);
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn storages_can_be_merged() {
+    let test_path = TestPath::new();
+    let first = test_path.file_name("first.storage");
+    let second = test_path.file_name("second.storage");
+    let merged = test_path.file_name("merged.storage");
+
+    TestStorage::insert_rows(TestStorage::new(&first), 3, 2);
+    let mut insertion_manager = TestStorage::new(&second).begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        for i in 3..6 {
+            inserter.enqueue_row(&TestStorage::row(i)).unwrap();
+        }
+    }
+    insertion_manager.finish_inserting().unwrap();
+
+    let storage = Storage::merge(&merged, &[&first, &second]).unwrap();
+    assert_eq!(storage.num_rows(), 6);
+    assert_eq!(storage.stripes().len(), 3);
+
+    let reader = StorageReader::open(&merged).unwrap();
+    assert_eq!(reader.storage().columns().len(), 7);
+    let rows: Vec<_> = reader.rows().map(|r| r.unwrap()).collect();
+    assert_eq!(rows, (0..6).map(TestStorage::row).collect::<Vec<_>>());
+
+    // Nothing is written if the schemas don't match
+    let other = test_path.file_name("other.storage");
+    let not_merged = test_path.file_name("not_merged.storage");
+    StorageBuilder::new().column("id", ColumnDatatype::Int32).at(&other).unwrap().begin_inserting().finish_inserting().unwrap();
+    match Storage::merge(&not_merged, &[&first, &other]) {
+        Err(StorageError::InvalidFormat(_)) => {},
+        _ => panic!("Expected an InvalidFormat error")
+    }
+    assert!(!not_merged.exists());
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn stripes_can_be_walked() {
+    let test_path = TestPath::new();
+    let file_name = test_path.file_name("test.storage");
+    TestStorage::insert_rows(TestStorage::new(&file_name), 2500, 1000);
+
+    let reader = StorageReader::open(&file_name).unwrap();
+    let num_rows: Vec<usize> = reader.stripes_iter().map(|s| s.get_header().unwrap().num_rows).collect();
+    assert_eq!(num_rows, vec!(1000, 1000, 500));
+    assert_eq!(reader.storage().stripes_iter().count(), reader.stripe_count());
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn vacuumed_storages_drop_unused_bytes() {
+    use ::Encoder;
+
+    let test_path = TestPath::new();
+    let vacuumed = test_path.file_name("vacuumed.storage");
+
+    // Leave some garbage before each stripe, like a failed insert would
+    let mut storage = StorageBuilder::new()
+        .column("id", ColumnDatatype::Int32)
+        .column("name", ColumnDatatype::VariableLength)
+        .max_value_bytes(8)
+        .encoder(Encoder::Auto)
+        .in_memory().unwrap();
+    for round in 0..3 {
+        {
+            let mut writer = storage.backend.writer().unwrap();
+            writer.seek(SeekFrom::End(0)).unwrap();
+            writer.write_all(&[0xAB; 1000]).unwrap();
+        }
+
+        let mut insertion_manager = storage.begin_inserting();
+        {
+            let mut inserter = insertion_manager.create_inserter();
+            for i in 0..10 {
+                inserter.enqueue_row(&vec!(ColumnValue::Int32(round*10 + i), ColumnValue::VariableLength(format!("Row {}", i).into()))).unwrap();
+            }
+        }
+        storage = insertion_manager.finish_inserting().unwrap();
+    }
+    storage.truncate_to_rows(20).unwrap();
+    let indices: Vec<usize> = (0..20).collect();
+    let rows = storage.read_rows(&indices).unwrap();
+    let size = storage.backend.reader().unwrap().seek(SeekFrom::End(0)).unwrap();
+
+    let vacuumed_storage = storage.vacuum(&vacuumed).unwrap();
+    assert_eq!(vacuumed_storage.num_rows(), 20);
+    // The garbage and the footers written before each round are gone
+    let vacuumed_size = fs::metadata(&vacuumed).unwrap().len();
+    assert_eq!(vacuumed_size, vacuumed_storage.estimated_size_on_disk() as u64);
+    assert!(vacuumed_size + 3000 <= size);
+
+    let reader = StorageReader::open(&vacuumed).unwrap();
+    assert_eq!(reader.stripe_count(), 2);
+    assert_eq!(reader.rows().map(|r| r.unwrap()).collect::<Vec<_>>(), rows);
+    // The settings are kept too, so rows appended to the copy are written the same way
+    assert_eq!(reader.storage().encoder(), Encoder::Auto);
+    assert_eq!(reader.storage().max_value_bytes(), Some(8));
+    assert_eq!(reader.storage().compressor(), storage.compressor());
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn rows_can_be_iterated() {
+    let test_path = TestPath::new();
+    let file_name = test_path.file_name("test.storage");
+    let storage = TestStorage::insert_rows(TestStorage::new(&file_name), 5000, 1000);
+
+    let reader = StorageReader::new(storage);
+    let mut num_rows = 0;
+    for (i, row) in reader.rows().enumerate() {
+        assert_eq!(row.unwrap(), TestStorage::row(i));
+        num_rows += 1;
+    }
+    assert_eq!(num_rows, 5000);
+
+    // Corrupt the third stripe: the rows of the first two are still returned
+    let corrupted_offset = reader.storage().stripes()[3].absolute_offset - 1;
+    {
+        let mut bytes = fs::read(&file_name).unwrap();
+        bytes[corrupted_offset] ^= 0xFF;
+        fs::write(&file_name, &bytes).unwrap();
+    }
+
+    let reader = StorageReader::open(&file_name).unwrap();
+    let results: Vec<_> = reader.rows().collect();
+    assert_eq!(results.len(), 2001);
+    assert!(results[..2000].iter().all(|r| r.is_ok()));
+    assert!(results[2000].is_err());
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn projected_rows_skip_the_other_columns() {
+    let test_path = TestPath::new();
+    let file_name = test_path.file_name("test.storage");
+    let storage = TestStorage::insert_rows(TestStorage::new(&file_name), 200, 100);
+
+    // Corrupt the sizes of the first variable length chunk, so that it cannot be decoded
+    let header = StorageStripeIterator::new(&storage).next().unwrap().get_header().unwrap();
+    let chunk_offset = storage.stripes()[1].absolute_offset - header.column_chunks[6].compressed_size;
+    {
+        let mut bytes = fs::read(&file_name).unwrap();
+        for b in bytes[chunk_offset..chunk_offset + 4].iter_mut() {
+            *b = 0x7F;
+        }
+        fs::write(&file_name, &bytes).unwrap();
+    }
+
+    let reader = StorageReader::open(&file_name).unwrap();
+    assert!(reader.rows().next().unwrap().is_err());
+
+    let rows: Vec<Vec<ColumnValue>> = reader.project(&["int64col", "bytecol"]).unwrap().map(|r| r.unwrap()).collect();
+    assert_eq!(rows.len(), 200);
+    for (i, row) in rows.iter().enumerate() {
+        let expected = TestStorage::row(i);
+        assert_eq!(*row, vec!(expected[3].clone(), expected[1].clone()));
+    }
+
+    assert!(reader.project(&["int64col", "unknown"]).is_err());
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn single_rows_can_be_fetched() {
+    let test_path = TestPath::new();
+    let storage = TestStorage::insert_rows(TestStorage::new(&test_path.file_name("test.storage")), 3000, 1000);
+    let reader = StorageReader::new(storage);
+    assert_eq!(reader.stripe_count(), 3);
+
+    for &i in [0, 1500, 2999].iter() {
+        assert_eq!(reader.get_row(i).unwrap(), TestStorage::row(i));
+    }
+
+    match reader.get_row(3000) {
+        Err(StorageError::InvalidRowIndex(3000)) => {},
+        _ => panic!("Expected an InvalidRowIndex error")
+    }
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn rows_can_be_read_as_maps() {
+    let test_path = TestPath::new();
+    let storage = TestStorage::insert_rows(TestStorage::new(&test_path.file_name("test.storage")), 1500, 1000);
+    let reader = StorageReader::new(storage);
+
+    let rows: Vec<_> = reader.rows_as_map().map(|r| r.unwrap()).collect();
+    assert_eq!(rows.len(), 1500);
+
+    let row = &rows[1200];
+    assert_eq!(row.len(), 7);
+    assert_eq!(row["int32col"], ColumnValue::Int32(1200));
+    assert_eq!(row["variablelengthcol"], ColumnValue::VariableLength("Row 1200".into()));
+    assert_eq!(row["nullcol"], ColumnValue::Null);
+    assert!(row.get("unknown").is_none());
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn negative_infinity_is_not_null() {
+    let storage = StorageBuilder::new()
+        .column("float", ColumnDatatype::Float)
+        .column("int", ColumnDatatype::Int32)
+        .in_memory().unwrap();
+
+    let rows = vec!(
+        vec!(ColumnValue::Float(f32::NEG_INFINITY), ColumnValue::Int32(i32::MIN)),
+        vec!(ColumnValue::Null, ColumnValue::Null),
+        vec!(ColumnValue::Float(1.5), ColumnValue::Int32(3)),
+    );
+
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        for row in rows.iter() {
+            inserter.enqueue_row(row).unwrap();
+        }
+    }
+    let storage = insertion_manager.finish_inserting().unwrap();
+
+    assert_eq!(storage.read_rows(&[0, 1, 2]).unwrap(), rows);
+
+    let mut values = Vec::new();
+    storage.visit_column_f32(0, |v| values.push(v)).unwrap();
+    assert_eq!(values, vec!(Some(f32::NEG_INFINITY), None, Some(1.5)));
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn rows_per_stripe_can_be_set() {
+    let test_path = TestPath::new();
+    let file_name = test_path.file_name("test.storage");
+    let storage = StorageBuilder::new()
+        .column("id", ColumnDatatype::Int32)
+        .rows_per_stripe(100)
+        .at(&file_name).unwrap();
+
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        for i in 0..250 {
+            inserter.enqueue_row(&vec!(ColumnValue::Int32(i))).unwrap();
+        }
+    }
+    let storage = insertion_manager.finish_inserting().unwrap();
+    let num_rows: Vec<usize> = storage.stripes().iter().map(|s| s.num_rows).collect();
+    assert_eq!(num_rows, vec!(100, 100, 50));
+
+    let storage = Storage::open(&file_name).unwrap();
+    assert_eq!(storage.rows_per_stripe(), Some(100));
+
+    assert!(StorageBuilder::new().column("id", ColumnDatatype::Int32).rows_per_stripe(0).in_memory().is_err());
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn pending_rows_can_be_flushed() {
+    let storage = StorageBuilder::new()
+        .column("id", ColumnDatatype::Int32)
+        .in_memory().unwrap();
+
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        for i in 0..3 {
+            inserter.enqueue_row(&vec!(ColumnValue::Int32(i))).unwrap();
+        }
+        inserter.flush_now().unwrap();
+        // Nothing is enqueued, so this doesn't write an empty stripe
+        inserter.flush_now().unwrap();
+
+        for i in 3..5 {
+            inserter.enqueue_row(&vec!(ColumnValue::Int32(i))).unwrap();
+        }
+    }
+    let storage = insertion_manager.finish_inserting().unwrap();
+    let num_rows: Vec<usize> = storage.stripes().iter().map(|s| s.num_rows).collect();
+    assert_eq!(num_rows, vec!(3, 2));
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn flushed_rows_can_be_read_before_finishing() {
+    let test_path = TestPath::new();
+    let file_name = test_path.file_name("test.storage");
+    let storage = StorageBuilder::new()
+        .column("id", ColumnDatatype::Int32)
+        .at(&file_name).unwrap();
+
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        for i in 0..3 {
+            inserter.enqueue_row(&vec!(ColumnValue::Int32(i))).unwrap();
+        }
+        inserter.flush_now().unwrap();
+
+        assert_eq!(Storage::open(&file_name).unwrap().num_rows(), 3);
+        let reader = StorageReader::open(&file_name).unwrap();
+        let rows: Vec<Vec<ColumnValue>> = reader.rows().map(|r| r.unwrap()).collect();
+        assert_eq!(rows, (0..3).map(|i| vec!(ColumnValue::Int32(i))).collect::<Vec<_>>());
+
+        for i in 3..5 {
+            inserter.enqueue_row(&vec!(ColumnValue::Int32(i))).unwrap();
+        }
+    }
+    insertion_manager.finish_inserting().unwrap();
+
+    // The footer written by flush_now is left behind, the new one indexes all the rows
+    let reader = StorageReader::open(&file_name).unwrap();
+    let rows: Vec<Vec<ColumnValue>> = reader.rows().map(|r| r.unwrap()).collect();
+    assert_eq!(rows, (0..5).map(|i| vec!(ColumnValue::Int32(i))).collect::<Vec<_>>());
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn stripes_can_end_at_group_boundaries() {
+    let storage = StorageBuilder::new()
+        .column("day", ColumnDatatype::Int32)
+        .column("value", ColumnDatatype::Int64)
+        .rows_per_stripe(1000)
+        .in_memory().unwrap();
+
+    let group_sizes = [5, 1, 12, 3];
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        for (day, &size) in group_sizes.iter().enumerate() {
+            for i in 0..size {
+                inserter.enqueue_row(&vec!(ColumnValue::Int32(day as i32), ColumnValue::Int64(i))).unwrap();
+            }
+            inserter.end_stripe().unwrap();
+        }
+        // The last group was already written
+        inserter.end_stripe().unwrap();
+    }
+    let storage = insertion_manager.finish_inserting().unwrap();
+
+    assert_eq!(storage.stripes().len(), group_sizes.len());
+    for (day, stripe) in StorageStripeIterator::new(&storage).enumerate() {
+        let header = stripe.get_header().unwrap();
+        assert_eq!(header.num_rows, group_sizes[day] as usize);
+        assert_eq!(header.column_chunks[0].min_max, Some((day as i64, day as i64)));
+    }
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn in_memory_storages_can_be_read_from_their_bytes() {
+    let storage = StorageBuilder::new()
+        .column("id", ColumnDatatype::Int32)
+        .column("name", ColumnDatatype::VariableLength)
+        .rows_per_stripe(10)
+        .in_memory().unwrap();
+
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        for i in 0..25 {
+            inserter.enqueue_row(&vec!(ColumnValue::Int32(i), ColumnValue::VariableLength(format!("Row {}", i).into()))).unwrap();
+        }
+    }
+    let bytes = insertion_manager.finish_to_bytes().unwrap();
+
+    let reader = StorageReader::from_bytes(bytes).unwrap();
+    assert_eq!(reader.row_count(), 25);
+    assert_eq!(reader.stripe_count(), 3);
+    let rows: Vec<Vec<ColumnValue>> = reader.rows().map(|r| r.unwrap()).collect();
+    assert_eq!(rows[24], vec!(ColumnValue::Int32(24), ColumnValue::VariableLength("Row 24".to_string().into())));
+
+    // Storages in files have no bytes to return
+    let test_path = TestPath::new();
+    let storage = TestStorage::new(&test_path.file_name("test.storage"));
+    assert!(storage.begin_inserting().finish_to_bytes().is_err());
+    assert!(StorageReader::from_bytes(vec!(1, 2, 3)).is_err());
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn int16_values_round_trip() {
+    let storage = StorageBuilder::new()
+        .column("small", ColumnDatatype::Int16)
+        .in_memory().unwrap();
+    assert_eq!(storage.datatype_info("small").unwrap().value_size, Some(2));
+
+    let values = vec!(ColumnValue::Int16(i16::MIN), ColumnValue::Int16(-1), ColumnValue::Null, ColumnValue::Int16(i16::MAX));
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        for value in values.iter() {
+            inserter.enqueue_row(&vec!(value.clone())).unwrap();
+        }
+        assert!(inserter.enqueue_row(&vec!(ColumnValue::Int32(1))).is_err());
+    }
+
+    let reader = StorageReader::from_bytes(insertion_manager.finish_to_bytes().unwrap()).unwrap();
+    let read: Vec<ColumnValue> = reader.rows().map(|r| r.unwrap()[0].clone()).collect();
+    assert_eq!(read, values);
+
+    let mut visited = Vec::new();
+    reader.storage().visit_column_i16(0, |v| visited.push(v)).unwrap();
+    assert_eq!(visited, vec!(Some(i16::MIN), Some(-1), None, Some(i16::MAX)));
+
+    let stats = reader.storage().column_stats("small").unwrap().unwrap();
+    assert_eq!((stats.min, stats.max), (Some(i16::MIN as i64), Some(i16::MAX as i64)));
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn rows_can_be_validated_without_inserting_them() {
+    let test_path = TestPath::new();
+    let storage = TestStorage::new(&test_path.file_name("test.storage"));
+
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let inserter = insertion_manager.create_inserter();
+        inserter.validate_row(&TestStorage::row(0)).unwrap();
+
+        let mut wrong_type = TestStorage::row(0);
+        wrong_type[2] = ColumnValue::Float(1.0);
+        match inserter.validate_row(&wrong_type) {
+            Err(StorageError::TypeMismatch { column: 2, expected: ColumnDatatype::Int32 }) => {},
+            _ => panic!("Expected a TypeMismatch for column 2")
+        }
+
+        match inserter.validate_row(&vec!(ColumnValue::Null)) {
+            Err(StorageError::InvalidNumberOfColumns(1, 7)) => {},
+            _ => panic!("Expected an InvalidNumberOfColumns error")
+        }
+
+        // Nothing is enqueued, so dropping the inserter doesn't write anything
+    }
+    let storage = insertion_manager.finish_inserting().unwrap();
+    assert_eq!(storage.num_rows(), 0);
+    assert!(storage.stripes().is_empty());
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn rows_can_be_inserted_concurrently() {
+    let num_threads = 8;
+    let rows_per_thread = 5000;
+
+    let test_path = TestPath::new();
+    let file_name = test_path.file_name("test.storage");
+    let storage = StorageBuilder::new()
+        .column("thread", ColumnDatatype::Int32)
+        .column("row", ColumnDatatype::Int32)
+        .column("name", ColumnDatatype::VariableLength)
+        .rows_per_stripe(300)
+        .at(&file_name).unwrap();
+
+    let mut insertion_manager = storage.begin_inserting();
+    let threads: Vec<_> = (0..num_threads).map(|t| {
+        let mut inserter = insertion_manager.create_inserter();
+        thread::spawn(move || {
+            for i in 0..rows_per_thread {
+                let row = vec!(ColumnValue::Int32(t), ColumnValue::Int32(i), ColumnValue::VariableLength(format!("{}-{}", t, i).into()));
+                inserter.enqueue_row(&row).unwrap();
+            }
+            inserter.finish().unwrap();
+        })
+    }).collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    insertion_manager.finish_inserting().unwrap();
+
+    let reader = StorageReader::open(&file_name).unwrap();
+    assert_eq!(reader.row_count(), (num_threads * rows_per_thread) as usize);
+
+    // The stripes don't overlap: they are at different offsets, and none of them was
+    // corrupted by another one (the checksums are verified)
+    let mut offsets: Vec<usize> = reader.storage().stripes().iter().map(|s| s.absolute_offset).collect();
+    offsets.sort();
+    offsets.dedup();
+    assert_eq!(offsets.len(), reader.stripe_count());
+    for stripe in StorageStripeIterator::new(reader.storage()) {
+        stripe.get_header().unwrap();
+    }
+
+    // Each thread inserted all its rows exactly once, and in order within its stripes
+    let mut rows_of_thread = vec!(Vec::new(); num_threads as usize);
+    for row in reader.rows() {
+        let row = row.unwrap();
+        match (&row[0], &row[1], &row[2]) {
+            (&ColumnValue::Int32(t), &ColumnValue::Int32(i), &ColumnValue::VariableLength(ref name)) => {
+                assert_eq!(*name, format!("{}-{}", t, i).into_bytes());
+                rows_of_thread[t as usize].push(i);
+            },
+            _ => panic!("Unexpected row {:?}", row)
+        }
+    }
+    for rows in rows_of_thread {
+        assert_eq!(rows, (0..rows_per_thread).collect::<Vec<i32>>());
+    }
+}
+
+// ----------------------------------------------------------------------------
+/// A cursor that is still reachable after being handed to a storage
+#[derive(Clone)]
+struct SharedCursor(Arc<Mutex<Cursor<Vec<u8>>>>);
+
+impl Write for SharedCursor {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.0.lock().unwrap().write(buf) }
+    fn flush(&mut self) -> io::Result<()> { self.0.lock().unwrap().flush() }
+}
+
+impl Seek for SharedCursor {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> { self.0.lock().unwrap().seek(pos) }
+}
+
+#[test]
+fn storages_can_be_written_into_any_writer() {
+    let cursor = SharedCursor(Arc::new(Mutex::new(Cursor::new(Vec::new()))));
+    let storage = StorageBuilder::new()
+        .column("id", ColumnDatatype::Int32)
+        .rows_per_stripe(10)
+        .into_writer(cursor.clone()).unwrap();
+
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        for i in 0..25 {
+            inserter.enqueue_row(&vec!(ColumnValue::Int32(i))).unwrap();
+        }
+    }
+    let storage = insertion_manager.finish_inserting().unwrap();
+    // The storage can only be written
+    assert!(storage.backend.reader().is_err());
+
+    let bytes = cursor.0.lock().unwrap().get_ref().clone();
+    let reader = StorageReader::from_bytes(bytes).unwrap();
+    assert_eq!(reader.stripe_count(), 3);
+    let ids: Vec<ColumnValue> = reader.rows().map(|r| r.unwrap()[0].clone()).collect();
+    assert_eq!(ids, (0..25).map(ColumnValue::Int32).collect::<Vec<ColumnValue>>());
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn not_null_columns_reject_nulls() {
+    let test_path = TestPath::new();
+    let file_name = test_path.file_name("test.storage");
+    let storage = StorageBuilder::new()
+        .column_not_null("id", ColumnDatatype::Int32)
+        .column("name", ColumnDatatype::VariableLength)
+        .at(&file_name).unwrap();
+
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        inserter.enqueue_row(&vec!(ColumnValue::Int32(1), ColumnValue::Null)).unwrap();
+        match inserter.enqueue_row(&vec!(ColumnValue::Null, ColumnValue::VariableLength(b"x".to_vec()))) {
+            Err(StorageError::NullNotAllowed(ref column)) if column == "id" => {},
+            r => panic!("Expected NullNotAllowed, got {:?}", r)
+        }
+    }
+    let storage = insertion_manager.finish_inserting().unwrap();
+    assert_eq!(storage.num_rows(), 1);
+
+    // The constraint is part of the schema
+    let storage = Storage::open(&file_name).unwrap();
+    assert!(!storage.column(0).is_nullable());
+    assert!(storage.column(1).is_nullable());
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn short_fixed_length_values_can_be_padded() {
+    let storage = StorageBuilder::new()
+        .column_padded("code", 4, b' ')
+        .column("strict", ColumnDatatype::FixedLength(4))
+        .in_memory().unwrap();
+    assert_eq!(storage.column(0).padding(), Some(b' '));
+    assert_eq!(storage.column(1).padding(), None);
+
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        let exact = ColumnValue::FixedLength(b"ABCD".to_vec());
+        let short = ColumnValue::FixedLength(b"AB".to_vec());
+        let long = ColumnValue::FixedLength(b"ABCDE".to_vec());
+
+        inserter.enqueue_row(&vec!(exact.clone(), exact.clone())).unwrap();
+        inserter.enqueue_row(&vec!(short.clone(), ColumnValue::Null)).unwrap();
+        inserter.enqueue_row(&vec!(ColumnValue::FixedLength(Vec::new()), ColumnValue::Null)).unwrap();
+
+        match inserter.enqueue_row(&vec!(long, ColumnValue::Null)) {
+            Err(StorageError::InvalidLength(5, 4)) => {},
+            r => panic!("Expected InvalidLength, got {:?}", r)
+        }
+        match inserter.enqueue_row(&vec!(exact, short)) {
+            Err(StorageError::InvalidLength(2, 4)) => {},
+            r => panic!("Expected InvalidLength, got {:?}", r)
+        }
+    }
+
+    let reader = StorageReader::from_bytes(insertion_manager.finish_to_bytes().unwrap()).unwrap();
+    let codes: Vec<ColumnValue> = reader.rows().map(|r| r.unwrap()[0].clone()).collect();
+    assert_eq!(codes, vec!(
+        ColumnValue::FixedLength(b"ABCD".to_vec()),
+        ColumnValue::FixedLength(b"AB  ".to_vec()),
+        ColumnValue::FixedLength(b"    ".to_vec())
+    ));
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn size_on_disk_can_be_estimated() {
+    let test_path = TestPath::new();
+    let file_name = test_path.file_name("test.storage");
+    let storage = TestStorage::insert_rows(TestStorage::new(&file_name), 1000, 600);
+    assert_eq!(storage.stripes().len(), 2);
+
+    let file_size = fs::metadata(&file_name).unwrap().len() as usize;
+    assert_eq!(storage.estimated_size_on_disk(), file_size);
+
+    // The stripe sizes are part of the index
+    let storage = Storage::open(&file_name).unwrap();
+    assert_eq!(storage.estimated_size_on_disk(), file_size);
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn clustered_nulls_are_stored_as_runs() {
+    let num_rows = 10000;
+    let present = [17, 5000, 5001];
+    let storage = StorageBuilder::new()
+        .column("value", ColumnDatatype::Int32)
+        .rows_per_stripe(num_rows)
+        .in_memory().unwrap();
+
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        for i in 0..num_rows {
+            let value = if present.contains(&i) { ColumnValue::Int32(i as i32) } else { ColumnValue::Null };
+            inserter.enqueue_row(&vec!(value)).unwrap();
+        }
+    }
+    let reader = StorageReader::from_bytes(insertion_manager.finish_to_bytes().unwrap()).unwrap();
+
+    // The chunk is the NULLs followed by the values
+    let header = StorageStripeIterator::new(reader.storage()).next().unwrap().get_header().unwrap();
+    let nulls_size = header.column_chunks[0].compressed_size - num_rows*4;
+    assert!(nulls_size < 10, "{} bytes of NULLs", nulls_size);
+
+    let values: Vec<ColumnValue> = reader.rows().map(|r| r.unwrap()[0].clone()).collect();
+    for (i, value) in values.iter().enumerate() {
+        if present.contains(&i) {
+            assert_eq!(*value, ColumnValue::Int32(i as i32));
+        } else {
+            assert_eq!(*value, ColumnValue::Null);
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn schemas_can_be_verified() {
+    let storage = StorageBuilder::new()
+        .column("id", ColumnDatatype::Int32)
+        .column("name", ColumnDatatype::VariableLength)
+        .in_memory().unwrap();
+    let reader = StorageReader::from_bytes(storage.begin_inserting().finish_to_bytes().unwrap()).unwrap();
+
+    let error_message = |expected: &[(&str, ColumnDatatype)]| match reader.verify_schema(expected) {
+        Err(StorageError::InvalidFormat(message)) => message,
+        r => panic!("Expected an InvalidFormat error, got {:?}", r)
+    };
+
+    reader.verify_schema(&[("id", ColumnDatatype::Int32), ("name", ColumnDatatype::VariableLength)]).unwrap();
+
+    // Reordered
+    assert_eq!(error_message(&[("name", ColumnDatatype::VariableLength), ("id", ColumnDatatype::Int32)]),
+        "Column 0 is 'id', expected 'name'");
+    // Type changed
+    assert_eq!(error_message(&[("id", ColumnDatatype::Int64), ("name", ColumnDatatype::VariableLength)]),
+        "Column 'id' is Int32, expected Int64");
+    // Missing and extra columns
+    assert_eq!(error_message(&[("id", ColumnDatatype::Int32)]), "Unexpected column 1 'name'");
+    assert_eq!(error_message(&[("id", ColumnDatatype::Int32), ("name", ColumnDatatype::VariableLength), ("x", ColumnDatatype::Byte)]),
+        "Missing column 2 'x'");
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn stripes_can_be_decoded_as_columns() {
+    let test_path = TestPath::new();
+    let storage = TestStorage::insert_rows(TestStorage::new(&test_path.file_name("test.storage")), 250, 100);
+    let reader = StorageReader::new(storage);
+
+    let stripe = reader.stripe(2).unwrap();
+    assert_eq!(stripe.num_rows, 50);
+    assert_eq!(stripe.columns.len(), 7);
+    for column in stripe.columns.iter() {
+        assert_eq!(column.values.len(), 50);
+        assert_eq!(column.nulls.len(), 50);
+    }
+
+    // The first column is always NULL
+    assert_eq!(stripe.columns[0].nulls.count_non_nulls(), 0);
+    assert_eq!(stripe.columns[2].values[0], TestStorage::row(200)[2]);
+
+    match reader.stripe(3) {
+        Err(StorageError::InvalidStripeIndex(3)) => {},
+        _ => panic!("Expected an InvalidStripeIndex error")
+    }
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn raw_numeric_values_can_be_read_without_conversions() {
+    let test_path = TestPath::new();
+    let storage = TestStorage::insert_rows(TestStorage::new(&test_path.file_name("test.storage")), 250, 100);
+
+    let mut sum: i64 = 0;
+    for stripe in StorageStripeIterator::new(&storage) {
+        let raw = stripe.read_raw_values::<i32>(2).unwrap();
+        assert_eq!(raw.values.len(), stripe.num_rows());
+        sum += raw.values.iter().zip(raw.nulls.iter())
+            .filter(|&(_, has_value)| has_value)
+            .map(|(&v, _)| v as i64)
+            .fold(0, |a, b| a + b);
+    }
+
+    let expected = (0..250).filter(|i| i % 11 != 3).map(|i| i as i64).fold(0, |a, b| a + b);
+    assert_eq!(sum, expected);
+
+    let stripe = StorageStripeIterator::new(&storage).next().unwrap();
+    match stripe.read_raw_values::<i64>(2) {
+        Err(StorageError::TypeError) => {},
+        _ => panic!("Expected a TypeError")
+    }
+    assert!(stripe.read_raw_values::<i32>(7).is_err());
+
+    // Timestamps are encoded with delta-of-delta, so their chunks are not raw
+    let storage = StorageBuilder::new().column("ts", ColumnDatatype::Timestamp).in_memory().unwrap();
+    let mut insertion_manager = storage.begin_inserting();
+    insertion_manager.create_inserter().enqueue_row(&vec!(ColumnValue::Timestamp(1))).unwrap();
+    let storage = insertion_manager.finish_inserting().unwrap();
+    let stripe = StorageStripeIterator::new(&storage).next().unwrap();
+    assert!(stripe.read_raw_values::<::storage::Timestamp>(0).is_err());
+}
+
+// ----------------------------------------------------------------------------
+#[cfg(feature = "derive")]
+#[derive(Row, Debug, PartialEq)]
+struct Event {
+    id: i32,
+    name: String,
+    score: Option<f32>
+}
+
+#[cfg(feature = "derive")]
+#[test]
+fn typed_rows_round_trip_through_a_storage() {
+    use ::row::Row;
+
+    let storage = StorageBuilder::new()
+        .column("extra", ColumnDatatype::Int64)
+        .row_columns::<Event>()
+        .in_memory().unwrap();
+    assert!(!storage.column_by_name("id").unwrap().is_nullable());
+    assert!(storage.column_by_name("score").unwrap().is_nullable());
+
+    let events: Vec<Event> = (0..100)
+        .map(|i| Event { id: i, name: format!("Event {}", i), score: if i % 3 == 0 { None } else { Some(i as f32 / 2.0) } })
+        .collect();
+
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        for event in events.iter() {
+            let mut row = vec!(ColumnValue::Int64(7));
+            row.extend(event.to_row());
+            inserter.enqueue_row(&row).unwrap();
+        }
+    }
+    let reader = StorageReader::new(insertion_manager.finish_inserting().unwrap());
+
+    let read: Vec<Event> = reader.typed_rows::<Event>().unwrap().map(|r| r.unwrap()).collect();
+    assert_eq!(read, events);
+
+    match Event::from_row(&[ColumnValue::Int32(1), ColumnValue::Int32(2), ColumnValue::Null]) {
+        Err(StorageError::TypeMismatch { column: 1, expected: ColumnDatatype::VariableLength }) => {},
+        r => panic!("Expected a TypeMismatch error, got {:?}", r)
+    }
+    match Event::from_row(&[ColumnValue::Int32(1)]) {
+        Err(StorageError::InvalidNumberOfColumns(1, 3)) => {},
+        r => panic!("Expected an InvalidNumberOfColumns error, got {:?}", r)
+    }
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn auto_encoding_picks_the_smallest_encoding() {
+    use ::{Encoder, Encoding};
+
+    let storage = StorageBuilder::new()
+        .column("monotonic", ColumnDatatype::Int32)
+        .column("runs", ColumnDatatype::Int64)
+        .column("noise", ColumnDatatype::Int16)
+        .column("float", ColumnDatatype::Float)
+        .rows_per_stripe(1000)
+        .encoder(Encoder::Auto)
+        .in_memory().unwrap();
+
+    let rows: Vec<Vec<ColumnValue>> = (0..1000i64)
+        .map(|i| vec!(
+            if i % 100 == 7 { ColumnValue::Null } else { ColumnValue::Int32(i as i32 * 3) },
+            ColumnValue::Int64(1000000 * (i / 100)),
+            ColumnValue::Int16((i * 7919 % 1000) as i16 + 5000),
+            ColumnValue::Float(i as f32 * 0.37)
+        ))
+        .collect();
+
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        for row in rows.iter() {
+            inserter.enqueue_row(row).unwrap();
+        }
+    }
+    let storage = insertion_manager.finish_inserting().unwrap();
+
+    let header = storage.stripes_iter().next().unwrap().get_header().unwrap();
+    let encodings: Vec<Encoding> = header.column_chunks.iter().map(|c| c.encoding).collect();
+    assert_eq!(encodings[0], Encoding::Delta);
+    assert_eq!(encodings[1], Encoding::RLE);
+    assert_eq!(encodings[2], Encoding::FrameOfReference(5000));
+    assert_eq!(encodings[3], Encoding::Raw);
+
+    let reader = StorageReader::new(storage);
+    let read: Vec<Vec<ColumnValue>> = reader.rows().map(|r| r.unwrap()).collect();
+    assert_eq!(read, rows);
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn stripes_of_any_size_can_be_read() {
+    let test_path = TestPath::new();
+    let file_name = test_path.file_name("test.storage");
+    let storage = StorageBuilder::new()
+        .column("id", ColumnDatatype::Int32)
+        .column("name", ColumnDatatype::VariableLength)
+        .rows_per_stripe(37)
+        .at(&file_name).unwrap();
+
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        // 27 stripes of 37 rows and one of 1, then one more of 5
+        let mut inserter = insertion_manager.create_inserter();
+        for i in 0..1005 {
+            inserter.enqueue_row(&vec!(ColumnValue::Int32(i), ColumnValue::VariableLength(format!("Row {}", i).into()))).unwrap();
+            if i == 999 {
+                inserter.end_stripe().unwrap();
+            }
+        }
+    }
+    insertion_manager.finish_inserting().unwrap();
+
+    // The reader knows nothing about the stripe size it was written with
+    let reader = StorageReader::open(&file_name).unwrap();
+    assert_eq!(reader.stripe_count(), 29);
+    assert_eq!(reader.row_count(), 1005);
+    let sizes: Vec<usize> = reader.stripes_iter().map(|s| s.num_rows()).collect();
+    assert_eq!(&sizes[26..], &[37, 1, 5]);
+
+    let ids: Vec<ColumnValue> = reader.rows().map(|r| r.unwrap()[0].clone()).collect();
+    assert_eq!(ids, (0..1005).map(ColumnValue::Int32).collect::<Vec<_>>());
+    for &i in [0, 36, 37, 998, 999, 1000, 1004].iter() {
+        assert_eq!(reader.get_row(i).unwrap()[1], ColumnValue::VariableLength(format!("Row {}", i).into()));
+    }
+    assert_eq!(reader.column_cursor::<i32>("id").unwrap().map(|v| v.unwrap().unwrap()).fold(0, |a, b| a + b), (0..1005).sum::<i32>());
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn nan_is_left_out_of_the_chunk_stats() {
+    use std::cmp::Ordering;
+
+    assert_eq!(ColumnValue::Float(1.0).numeric_cmp(&ColumnValue::Float(2.0)), Some(Ordering::Less));
+    assert_eq!(ColumnValue::Int32(3).numeric_cmp(&ColumnValue::Int32(3)), Some(Ordering::Equal));
+    assert_eq!(ColumnValue::Float(f32::NAN).numeric_cmp(&ColumnValue::Float(1.0)), None);
+    assert_eq!(ColumnValue::Null.numeric_cmp(&ColumnValue::Null), None);
+    assert_eq!(ColumnValue::Int32(1).numeric_cmp(&ColumnValue::Int64(1)), None);
+    assert!(ColumnValue::Float(f32::NAN) != ColumnValue::Float(f32::NAN));
+    assert_eq!(format!("{}", ColumnValue::Float(f32::NAN)), "Float(NaN)");
+
+    let storage = StorageBuilder::new()
+        .column("float", ColumnDatatype::Float)
+        .column("int", ColumnDatatype::Int32)
+        .rows_per_stripe(4)
+        .in_memory().unwrap();
+
+    let rows = [
+        // NaN first, then last, between regular values
+        vec!(ColumnValue::Float(f32::NAN), ColumnValue::Int32(7)),
+        vec!(ColumnValue::Float(10.0), ColumnValue::Null),
+        vec!(ColumnValue::Float(-2.5), ColumnValue::Int32(-7)),
+        vec!(ColumnValue::Float(f32::NAN), ColumnValue::Int32(0)),
+        // Only NaNs and NULLs
+        vec!(ColumnValue::Float(f32::NAN), ColumnValue::Int32(1)),
+        vec!(ColumnValue::Null, ColumnValue::Int32(1)),
+    ];
+
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        for row in rows.iter() {
+            inserter.enqueue_row(row).unwrap();
+        }
+    }
+    let storage = insertion_manager.finish_inserting().unwrap();
+
+    let headers: Vec<_> = storage.stripes_iter().map(|s| s.get_header().unwrap()).collect();
+    assert_eq!(headers[0].column_chunks[0].float_min_max, Some((-2.5, 10.0)));
+    assert_eq!(headers[0].column_chunks[0].min_max, None);
+    assert_eq!(headers[0].column_chunks[1].min_max, Some((-7, 7)));
+    assert_eq!(headers[0].column_chunks[1].float_min_max, None);
+    assert_eq!(headers[1].column_chunks[0].float_min_max, None);
+
+    // NaNs are still stored
+    let reader = StorageReader::new(storage);
+    match reader.get_row(3).unwrap()[0] {
+        ColumnValue::Float(v) => assert!(v.is_nan()),
+        ref v => panic!("Expected a NaN float, got {}", v)
+    }
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn columns_can_be_appended_to_an_existing_storage() {
+    let test_path = TestPath::new();
+    let file_name = test_path.file_name("test.storage");
+    TestStorage::insert_rows(TestStorage::new(&file_name), 30, 10);
+
+    let mut storage = Storage::open(&file_name).unwrap();
+    storage.append_column("extra", ColumnDatatype::Int32, ColumnValue::Int32(42)).unwrap();
+    storage.append_column("note", ColumnDatatype::VariableLength, ColumnValue::VariableLength(b"none".to_vec())).unwrap();
+    storage.append_column("missing", ColumnDatatype::Int64, ColumnValue::Null).unwrap();
+    match storage.append_column("extra", ColumnDatatype::Int32, ColumnValue::Null) {
+        Err(StorageError::InvalidFormat(_)) => {},
+        r => panic!("Expected an InvalidFormat error, got {:?}", r)
+    }
+    match storage.append_column("other", ColumnDatatype::Int32, ColumnValue::Int64(1)) {
+        Err(StorageError::TypeMismatch { column: 10, expected: ColumnDatatype::Int32 }) => {},
+        r => panic!("Expected a TypeMismatch error, got {:?}", r)
+    }
+
+    // Rows can be appended with the new schema
+    let storage = Storage::open_for_append(&file_name).unwrap();
+    assert_eq!(storage.num_columns(), 10);
+    assert_eq!(storage.column(7).default_value(), Some(&ColumnValue::Int32(42)));
+    assert_eq!(storage.column(0).default_value(), None);
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        let mut row = TestStorage::row(30);
+        row.extend(vec!(ColumnValue::Int32(7), ColumnValue::Null, ColumnValue::Int64(-1)));
+        inserter.enqueue_row(&row).unwrap();
+    }
+    insertion_manager.finish_inserting().unwrap();
+
+    let storage = Storage::open(&file_name).unwrap();
+    let mut sum = 0;
+    storage.visit_column_i32(7, |v| sum += v.unwrap()).unwrap();
+    assert_eq!(sum, 42 * 30 + 7);
+    assert_eq!(storage.column_stats("extra").unwrap(),
+               Some(ColumnStats { min: Some(7), max: Some(42), null_count: 0, num_rows: 31 }));
+    assert_eq!(storage.column_stats("missing").unwrap(),
+               Some(ColumnStats { min: Some(-1), max: Some(-1), null_count: 30, num_rows: 31 }));
+
+    // The old stripes have no chunks for the new columns
+    let summaries: Vec<Vec<_>> = storage.stripes_iter().map(|s| s.compression_summary().unwrap()).collect();
+    assert_eq!(summaries[0].len(), 7);
+    assert_eq!(summaries[3].len(), 10);
+    assert_eq!(summaries[3][7].column, "extra");
+
+    let reader = StorageReader::new(storage);
+    let rows: Vec<_> = reader.rows().map(|r| r.unwrap()).collect();
+    assert_eq!(rows.len(), 31);
+    for (i, row) in rows.iter().enumerate().take(30) {
+        assert_eq!(&row[..7], &TestStorage::row(i)[..]);
+        assert_eq!(&row[7..], &[ColumnValue::Int32(42), ColumnValue::VariableLength(b"none".to_vec()), ColumnValue::Null]);
+    }
+    assert_eq!(&rows[30][7..], &[ColumnValue::Int32(7), ColumnValue::Null, ColumnValue::Int64(-1)]);
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn columns_can_carry_metadata() {
+    use std::collections::HashMap;
+
+    let test_path = TestPath::new();
+    let file_name = test_path.file_name("test.storage");
+
+    let mut metadata = HashMap::new();
+    metadata.insert("unit".to_string(), "ms".to_string());
+    let mut commented = HashMap::new();
+    commented.insert("comment".to_string(), "Time to first byte".to_string());
+    commented.insert("source".to_string(), "probe".to_string());
+    let storage = StorageBuilder::new()
+        .column("id", ColumnDatatype::Int32)
+        .column_with_metadata("latency", ColumnDatatype::Int64, metadata.clone())
+        .column_with_metadata("ttfb", ColumnDatatype::Int64, commented.clone())
+        .at(&file_name).unwrap();
+    let mut insertion_manager = storage.begin_inserting();
+    insertion_manager.create_inserter().enqueue_row(&vec!(ColumnValue::Int32(1), ColumnValue::Int64(20), ColumnValue::Null)).unwrap();
+    insertion_manager.finish_inserting().unwrap();
+
+    let storage = Storage::open(&file_name).unwrap();
+    assert!(storage.column(0).metadata().is_empty());
+    assert_eq!(storage.column(1).metadata(), &metadata);
+    assert_eq!(storage.column(1).comment(), None);
+    assert_eq!(storage.column(2).metadata(), &commented);
+    assert_eq!(storage.column(2).comment(), Some("Time to first byte"));
+
+    // Copies of the storage keep it
+    let vacuumed = storage.vacuum(test_path.file_name("vacuumed.storage")).unwrap();
+    assert_eq!(vacuumed.column(1).metadata(), &metadata);
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn rows_can_be_streamed_through_a_closure() {
+    use std::ops::ControlFlow;
+
+    let test_path = TestPath::new();
+    let storage = TestStorage::insert_rows(TestStorage::new(&test_path.file_name("test.storage")), 3000, 1000);
+    let reader = StorageReader::new(storage);
+
+    let mut sum = 0i64;
+    let num_rows = reader.for_each_row(|row| {
+        if let ColumnValue::Int32(v) = row[2] {
+            sum += v as i64;
+        }
+        ControlFlow::Continue(())
+    }).unwrap();
+    assert_eq!(num_rows, 3000);
+    assert_eq!(sum, (0..3000i64).filter(|i| i % 11 != 3).fold(0, |a, b| a + b));
+}
+
+#[test]
+fn streaming_rows_can_stop_early() {
+    use std::ops::ControlFlow;
+
+    let test_path = TestPath::new();
+    let storage = TestStorage::insert_rows(TestStorage::new(&test_path.file_name("test.storage")), 3000, 1000);
+    let reader = StorageReader::new(storage);
+
+    let mut rows = Vec::new();
+    let num_rows = reader.for_each_row(|row| {
+        rows.push(row.to_vec());
+        if rows.len() == 10 { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+    }).unwrap();
+    assert_eq!(num_rows, 10);
+    assert_eq!(rows, (0..10).map(TestStorage::row).collect::<Vec<_>>());
+}
+
+#[test]
+fn rows_can_be_sampled() {
+    let test_path = TestPath::new();
+    let storage = TestStorage::insert_rows(TestStorage::new(&test_path.file_name("test.storage")), 1000, 300);
+    let reader = StorageReader::new(storage);
+
+    let sample = reader.sample_with_seed(10, 42).unwrap();
+    assert_eq!(sample.len(), 10);
+    let all_rows: Vec<Vec<ColumnValue>> = (0..1000).map(TestStorage::row).collect();
+    for row in sample.iter() {
+        assert!(all_rows.contains(row));
+    }
+
+    // The same seed picks the same rows, another one (almost certainly) doesn't
+    assert_eq!(reader.sample_with_seed(10, 42).unwrap(), sample);
+    assert!(reader.sample_with_seed(10, 43).unwrap() != sample);
+    assert_eq!(reader.sample(10).unwrap().len(), 10);
+
+    assert_eq!(reader.sample_with_seed(2000, 42).unwrap(), all_rows);
+    assert!(reader.sample_with_seed(0, 42).unwrap().is_empty());
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn low_cardinality_strings_share_a_dictionary() {
+    use ::{DictionaryMode, Encoding};
+
+    let test_path = TestPath::new();
+    let file_name = test_path.file_name("test.storage");
+    let countries = ["Switzerland", "Argentina", "Madagascar"];
+
+    let storage = StorageBuilder::new()
+        .column("id", ColumnDatatype::Int32)
+        .column_with_dictionary("country", DictionaryMode::Auto)
+        .column_with_dictionary("name", DictionaryMode::Auto)
+        .rows_per_stripe(1000)
+        .at(&file_name).unwrap();
+    let row = |i: usize| vec!(
+        ColumnValue::Int32(i as i32),
+        if i % 7 == 0 { ColumnValue::Null } else { ColumnValue::VariableLength(countries[i % 3].as_bytes().to_vec()) },
+        ColumnValue::VariableLength(format!("Name {}", i).into_bytes())
+    );
+    let mut insertion_manager = storage.begin_inserting();
+    {
+        let mut inserter = insertion_manager.create_inserter();
+        for i in 0..3000 {
+            inserter.enqueue_row(&row(i)).unwrap();
+        }
+    }
+    insertion_manager.finish_inserting().unwrap();
+
+    // The countries are stored once, in the footer, and the chunks only hold their codes
+    let bytes = fs::read(&file_name).unwrap();
+    let occurrences = bytes.windows(countries[0].len()).filter(|w| *w == countries[0].as_bytes()).count();
+    assert_eq!(occurrences, 1);
+
+    let reader = StorageReader::open(&file_name).unwrap();
+    assert_eq!(reader.stripe_count(), 3);
+    assert_eq!(reader.storage().column(1).dictionary_mode(), DictionaryMode::Auto);
+    assert_eq!(reader.storage().column(1).dictionary().unwrap().len(), 3);
+    for stripe in reader.stripes_iter() {
+        let header = stripe.get_header().unwrap();
+        assert_eq!(header.column_chunks[1].encoding, Encoding::Dictionary);
+        assert_eq!(header.column_chunks[1].uncompressed_size, 4 * 1000);
+        // Unique names would only grow the dictionary
+        assert_eq!(header.column_chunks[2].encoding, Encoding::Raw);
+    }
+    assert!(reader.storage().column(2).dictionary().unwrap().is_empty());
+
+    let rows: Vec<_> = reader.rows().map(|r| r.unwrap()).collect();
+    assert_eq!(rows, (0..3000).map(row).collect::<Vec<_>>());
+    let projected: Vec<_> = reader.project(&["country"]).unwrap().map(|r| r.unwrap()).collect();
+    assert_eq!(projected[4], vec!(row(4)[1].clone()));
+
+    // Forced dictionaries are used whatever the values, and survive appending rows
+    let storage = StorageBuilder::new()
+        .column_with_dictionary("name", DictionaryMode::Always)
+        .in_memory().unwrap();
+    let mut insertion_manager = storage.begin_inserting();
+    insertion_manager.create_inserter().enqueue_row(&vec!(ColumnValue::VariableLength(b"unique".to_vec()))).unwrap();
+    let storage = insertion_manager.finish_inserting().unwrap();
+    let header = storage.stripes_iter().next().unwrap().get_header().unwrap();
+    assert_eq!(header.column_chunks[0].encoding, Encoding::Dictionary);
+    assert_eq!(storage.column(0).dictionary().unwrap().values(), &[b"unique".to_vec()]);
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn closing_a_storage_returns_its_backend() {
+    // An empty storage is the header followed by the footer
+    let storage = StorageBuilder::new().column("id", ColumnDatatype::Int32).in_memory().unwrap();
+    let bytes = storage.close().unwrap().into_bytes().unwrap();
+    assert_eq!(&bytes[..3], b"SCS");
+    assert_eq!(&bytes[bytes.len() - 3..], b"SCS");
+    let mut footer_offset = [0u8; 8];
+    footer_offset.copy_from_slice(&bytes[bytes.len() - 11..bytes.len() - 3]);
+    assert_eq!(u64::from_le_bytes(footer_offset), 5);
+    let reader = StorageReader::from_bytes(bytes).unwrap();
+    assert_eq!(reader.row_count(), 0);
+    assert_eq!(reader.storage().column(0).name(), "id");
+
+    // The footer written when inserting is not written again
+    let insert = || {
+        let storage = StorageBuilder::new().column("id", ColumnDatatype::Int32).in_memory().unwrap();
+        let mut insertion_manager = storage.begin_inserting();
+        insertion_manager.create_inserter().enqueue_row(&vec!(ColumnValue::Int32(7))).unwrap();
+        insertion_manager
+    };
+    let expected = insert().finish_to_bytes().unwrap();
+    let bytes = insert().finish_inserting().unwrap().close().unwrap().into_bytes().unwrap();
+    assert_eq!(bytes, expected);
+    let reader = StorageReader::from_bytes(bytes).unwrap();
+    assert_eq!(reader.get_row(0).unwrap(), vec!(ColumnValue::Int32(7)));
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn integrity_can_be_verified_for_every_stripe() {
+    let test_path = TestPath::new();
+    let file_name = test_path.file_name("test.storage");
+    let storage = TestStorage::insert_rows(TestStorage::new(&file_name), 200, 50);
+    let stripe_ends: Vec<usize> = storage.stripes().iter().map(|s| s.absolute_offset + s.size).collect();
+    StorageReader::open(&file_name).unwrap().verify_integrity().unwrap();
+
+    // Flip the last byte of the third and fourth stripes: only the first one is reported
+    {
+        let mut bytes = fs::read(&file_name).unwrap();
+        bytes[stripe_ends[2] - 1] ^= 0xFF;
+        bytes[stripe_ends[3] - 1] ^= 0xFF;
+        fs::write(&file_name, &bytes).unwrap();
+    }
+
+    let reader = StorageReader::open(&file_name).unwrap();
+    match reader.verify_integrity() {
+        Err(StorageError::ChecksumMismatch { stripe: 2 }) => {},
+        r => panic!("Expected a checksum mismatch in stripe 2, got {:?}", r)
+    }
+    // Reads that don't decode the corrupted stripes still work
+    assert_eq!(reader.get_row(0).unwrap(), TestStorage::row(0));
+}
+
+// ----------------------------------------------------------------------------
+#[test]
+fn prefetched_rows_match_the_simple_scan() {
+    let test_path = TestPath::new();
+    let file_name = test_path.file_name("test.storage");
+    TestStorage::insert_rows(TestStorage::new(&file_name), 5000, 700);
+
+    let reader = StorageReader::open(&file_name).unwrap();
+    assert_eq!(reader.stripe_count(), 8);
+    let rows: Vec<_> = reader.rows().map(|r| r.unwrap()).collect();
+    let prefetched: Vec<_> = reader.rows_prefetching().map(|r| r.unwrap()).collect();
+    assert_eq!(prefetched, rows);
+    assert_eq!(prefetched, (0..5000).map(TestStorage::row).collect::<Vec<_>>());
+
+    // Stopping early stops the background reads too
+    let first: Vec<_> = reader.rows_prefetching().take(10).map(|r| r.unwrap()).collect();
+    assert_eq!(&first[..], &rows[..10]);
+
+    // Corrupted stripes are still detected
+    let stripe_end = reader.storage().stripes()[3].absolute_offset + reader.storage().stripes()[3].size;
+    {
+        let mut bytes = fs::read(&file_name).unwrap();
+        bytes[stripe_end - 1] ^= 0xFF;
+        fs::write(&file_name, &bytes).unwrap();
     }
+    let reader = StorageReader::open(&file_name).unwrap();
+    let results: Vec<_> = reader.rows_prefetching().collect();
+    assert_eq!(results.iter().take_while(|r| r.is_ok()).count(), 3 * 700);
+    assert!(results.last().unwrap().is_err());
 }
@@ -0,0 +1,63 @@
+//! #[derive(Row)] for the table crate. Each field of the struct is a column, in the order
+//! they are declared, and its type must implement table::row::ColumnType.
+extern crate proc_macro;
+extern crate proc_macro2;
+extern crate syn;
+#[macro_use]
+extern crate quote;
+
+use proc_macro::TokenStream;
+use syn::{Data, DeriveInput, Fields};
+
+#[proc_macro_derive(Row)]
+pub fn derive_row(input: TokenStream) -> TokenStream {
+    let input: DeriveInput = syn::parse(input).expect("Could not parse the struct");
+    let name = &input.ident;
+
+    let fields = match input.data {
+        Data::Struct(ref data) => match data.fields {
+            Fields::Named(ref fields) => &fields.named,
+            _ => panic!("#[derive(Row)] only supports structs with named fields")
+        },
+        _ => panic!("#[derive(Row)] only supports structs")
+    };
+
+    let idents: Vec<_> = fields.iter().map(|f| f.ident.clone().unwrap()).collect();
+    let types: Vec<_> = fields.iter().map(|f| f.ty.clone()).collect();
+    let names: Vec<String> = idents.iter().map(|i| i.to_string()).collect();
+    let indexes: Vec<usize> = (0..idents.len()).collect();
+    let num_fields = idents.len();
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let expanded = quote! {
+        impl #impl_generics ::table::row::Row for #name #ty_generics #where_clause {
+            fn schema() -> Vec<(&'static str, ::table::storage::ColumnDatatype, bool)> {
+                vec![#(
+                    (#names,
+                     <#types as ::table::row::ColumnType>::datatype(),
+                     <#types as ::table::row::ColumnType>::nullable())
+                ),*]
+            }
+
+            fn to_row(&self) -> Vec<::table::storage::ColumnValue> {
+                vec![#(::table::row::ColumnType::to_value(&self.#idents)),*]
+            }
+
+            fn from_row(row: &[::table::storage::ColumnValue]) -> ::table::error::StorageResult<Self> {
+                if row.len() != #num_fields {
+                    return Err(::table::error::StorageError::InvalidNumberOfColumns(row.len(), #num_fields));
+                }
+
+                Ok(#name {
+                    #(#idents: match ::table::row::field_from_value::<#types>(row, #indexes) {
+                        Ok(v) => v,
+                        Err(e) => return Err(e)
+                    }),*
+                })
+            }
+        }
+    };
+
+    expanded.into()
+}